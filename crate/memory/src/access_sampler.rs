@@ -0,0 +1,103 @@
+//! Access recency sampler.
+//!
+//! Sweeps the accessed bits of tracked pages in one pass,
+//! so that swap managers and other consumers can share the result
+//! instead of each sweeping the page table on its own.
+
+use alloc::collections::BTreeMap;
+use super::*;
+use paging::*;
+
+/// Age of a page: how many samples in a row found it not accessed.
+///
+/// 0 means accessed in the last sampling period. Saturates at `u8::max_value()`.
+pub type Age = u8;
+
+/// Tracks the age of resident pages by periodically sampling their accessed bits.
+#[derive(Default)]
+pub struct AccessSampler {
+    ages: BTreeMap<VirtAddr, Age>,
+}
+
+impl AccessSampler {
+    /// Starts tracking page of `addr`, as just accessed.
+    pub fn insert(&mut self, addr: VirtAddr) {
+        self.ages.insert(Page::of_addr(addr).start_address(), 0);
+    }
+
+    /// Stops tracking page of `addr`.
+    pub fn remove(&mut self, addr: VirtAddr) {
+        self.ages.remove(&Page::of_addr(addr).start_address());
+    }
+
+    /// Snapshots and clears the accessed bits of all tracked pages in `page_table`.
+    ///
+    /// Accessed pages become age 0, others get one older.
+    /// Should be called periodically, e.g. from the timer interrupt.
+    pub fn sample(&mut self, page_table: &mut impl PageTable) {
+        for (&addr, age) in self.ages.iter_mut() {
+            let entry = page_table.get_entry(addr);
+            if entry.accessed() {
+                entry.clear_accessed();
+                entry.update();
+                *age = 0;
+            } else {
+                *age = age.saturating_add(1);
+            }
+        }
+    }
+
+    /// Returns the age of page of `addr`, or `None` if it is not tracked.
+    pub fn age_of(&self, addr: VirtAddr) -> Option<Age> {
+        self.ages.get(&Page::of_addr(addr).start_address()).cloned()
+    }
+
+    /// Returns the oldest tracked page, the lowest address one if there are several.
+    pub fn oldest(&self) -> Option<VirtAddr> {
+        self.ages.iter()
+            .fold(None, |oldest: Option<(VirtAddr, Age)>, (&addr, &age)| match oldest {
+                Some((_, oldest_age)) if oldest_age >= age => oldest,
+                _ => Some((addr, age)),
+            })
+            .map(|(addr, _)| addr)
+    }
+
+    /// Iterates over all tracked pages and their ages.
+    pub fn iter(&self) -> impl Iterator<Item=(VirtAddr, Age)> + '_ {
+        self.ages.iter().map(|(&addr, &age)| (addr, age))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn age() {
+        let mut pt = MockPageTable::new();
+        let mut sampler = AccessSampler::default();
+        for i in 1..4 {
+            pt.map(i * PAGE_SIZE, i * PAGE_SIZE);
+            sampler.insert(i * PAGE_SIZE);
+        }
+        assert_eq!(sampler.age_of(0x1000), Some(0));
+        assert_eq!(sampler.age_of(0x4000), None);
+
+        pt.read(0x1000);
+        pt.write(0x2800, 0);
+        sampler.sample(&mut pt);
+        assert_eq!(sampler.iter().collect::<Vec<_>>(), [(0x1000, 0), (0x2000, 0), (0x3000, 1)]);
+        assert!(!pt.get_entry(0x1000).accessed(), "accessed bit cleared");
+        assert_eq!(sampler.oldest(), Some(0x3000));
+
+        pt.read(0x3000);
+        sampler.sample(&mut pt);
+        sampler.sample(&mut pt);
+        assert_eq!(sampler.iter().collect::<Vec<_>>(), [(0x1000, 2), (0x2000, 2), (0x3000, 1)]);
+        assert_eq!(sampler.oldest(), Some(0x1000));
+
+        sampler.remove(0x1fff);
+        assert_eq!(sampler.oldest(), Some(0x2000));
+    }
+}
@@ -1,6 +1,6 @@
 //! Helper functions/classes for address conversion.
 
-use core::ops::{Add, AddAssign};
+use core::ops::{Add, AddAssign, Sub, SubAssign};
 
 pub type VirtAddr = usize;
 pub type PhysAddr = usize;
@@ -19,7 +19,14 @@ impl Page {
     pub fn of_addr(addr: VirtAddr) -> Self {
         Page { number: addr / PAGE_SIZE }
     }
+    /// Returns the pages covering `[begin, end)`.
+    ///
+    /// An empty address range (`begin == end`) yields an empty page range.
     pub fn range_of(begin: VirtAddr, end: VirtAddr) -> PageRange {
+        if begin == end {
+            let page = Page::of_addr(begin);
+            return PageRange { start: page, end: page };
+        }
         PageRange {
             start: Page::of_addr(begin),
             end: Page::of_addr(end - 1) + 1,
@@ -41,6 +48,20 @@ impl AddAssign<usize> for Page {
     }
 }
 
+/// Overload - for Page so that page number can be subtracted like an usize.
+impl Sub<usize> for Page {
+    type Output = Self;
+    fn sub(self, rhs: usize) -> Self::Output {
+        Page { number: self.number - rhs }
+    }
+}
+
+impl SubAssign<usize> for Page {
+    fn sub_assign(&mut self, rhs: usize) {
+        *self = self.clone() - rhs;
+    }
+}
+
 /// A range of pages with exclusive upper bound.
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
@@ -49,6 +70,18 @@ pub struct PageRange {
     end: Page,
 }
 
+impl PageRange {
+    /// Number of pages left in the range.
+    pub fn len(&self) -> usize {
+        self.end.number - self.start.number
+    }
+
+    /// If no page is left in the range.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
 impl Iterator for PageRange {
     type Item = Page;
 
@@ -61,4 +94,75 @@ impl Iterator for PageRange {
             None
         }
     }
-}
\ No newline at end of file
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len(), Some(self.len()))
+    }
+}
+
+impl DoubleEndedIterator for PageRange {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.start < self.end {
+            self.end -= 1;
+            Some(self.end.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl ExactSizeIterator for PageRange {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn range_of() {
+        let pages: Vec<Page> = Page::range_of(0x1000, 0x3001).collect();
+        assert_eq!(pages, vec![Page::of_addr(0x1000), Page::of_addr(0x2000), Page::of_addr(0x3000)]);
+    }
+
+    #[test]
+    fn range_of_empty() {
+        assert_eq!(Page::range_of(0x1234, 0x1234).count(), 0);
+        assert_eq!(Page::range_of(0x1000, 0x1000).count(), 0);
+    }
+
+    #[test]
+    fn range_of_empty_at_zero() {
+        assert_eq!(Page::range_of(0, 0).count(), 0);
+    }
+
+    #[test]
+    fn range_len() {
+        let mut pages = Page::range_of(0x1000, 0x3001);
+        assert_eq!(pages.len(), 3);
+        pages.next();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(ExactSizeIterator::len(&pages), 2);
+        pages.by_ref().count();
+        assert_eq!(pages.len(), 0);
+        assert!(pages.is_empty());
+        assert!(Page::range_of(0x1234, 0x1234).is_empty());
+    }
+
+    #[test]
+    fn range_rev() {
+        let forward: Vec<Page> = Page::range_of(0x1000, 0x4001).collect();
+        let mut backward: Vec<Page> = Page::range_of(0x1000, 0x4001).rev().collect();
+        assert_eq!(backward[0], Page::of_addr(0x4000));
+        backward.reverse();
+        assert_eq!(forward, backward);
+        assert_eq!(Page::range_of(0, 0).rev().count(), 0);
+
+        // From both ends
+        let mut pages = Page::range_of(0x1000, 0x4000);
+        assert_eq!(pages.next_back(), Some(Page::of_addr(0x3000)));
+        assert_eq!(pages.next(), Some(Page::of_addr(0x1000)));
+        assert_eq!(pages.next_back(), Some(Page::of_addr(0x2000)));
+        assert_eq!(pages.next(), None);
+        assert_eq!(pages.next_back(), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! ELF core file writer, used by `MemorySet::write_coredump`.
+//!
+//! Only memory segments (`PT_LOAD`) are written, there is no `PT_NOTE` with registers.
+//! The ELF class follows the pointer width of the target.
+
+use alloc::vec::Vec;
+use super::*;
+
+/// Byte sink of a core file.
+pub trait Write {
+    fn write(&mut self, data: &[u8]) -> Result<(), ()>;
+}
+
+impl Write for Vec<u8> {
+    fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A memory segment in the core file.
+pub struct Segment<'a> {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    /// Content of the segment, `None` if it is not accessible.
+    pub data: Option<&'a [u8]>,
+}
+
+/// Write an ELF core file containing `segments` to `out`.
+///
+/// Contents of segments are page aligned in the file.
+pub fn write_core(out: &mut impl Write, segments: &[Segment]) -> Result<(), ()> {
+    let mut header = Header(Vec::new());
+    let phnum = segments.len();
+    header.ehdr(phnum);
+
+    let mut offset = align_up(EHDR_SIZE + PHDR_SIZE * phnum);
+    let mut offsets = Vec::with_capacity(phnum);
+    for seg in segments {
+        let file_size = seg.data.map_or(0, |data| data.len());
+        let flags = (seg.readable as u32 * PF_R) | (seg.writable as u32 * PF_W) | (seg.executable as u32 * PF_X);
+        header.phdr(flags, offset, seg.start, file_size, seg.end - seg.start);
+        offsets.push(offset);
+        offset = align_up(offset + file_size);
+    }
+    out.write(&header.0)?;
+
+    let mut pos = header.0.len();
+    for (seg, &offset) in segments.iter().zip(offsets.iter()) {
+        if let Some(data) = seg.data {
+            write_zeros(out, offset - pos)?;
+            out.write(data)?;
+            pos = offset + data.len();
+        }
+    }
+    Ok(())
+}
+
+fn align_up(offset: usize) -> usize {
+    (offset + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+fn write_zeros(out: &mut impl Write, mut len: usize) -> Result<(), ()> {
+    const ZEROS: [u8; 64] = [0; 64];
+    while len != 0 {
+        let n = len.min(ZEROS.len());
+        out.write(&ZEROS[..n])?;
+        len -= n;
+    }
+    Ok(())
+}
+
+/// Little endian ELF header builder.
+struct Header(Vec<u8>);
+
+impl Header {
+    fn int(&mut self, x: usize, size: usize) {
+        for i in 0..size {
+            self.0.push((x >> (i * 8)) as u8);
+        }
+    }
+    fn u16(&mut self, x: u16) {
+        self.int(x as usize, 2);
+    }
+    fn u32(&mut self, x: u32) {
+        self.int(x as usize, 4);
+    }
+    fn word(&mut self, x: usize) {
+        self.int(x, WORD_SIZE);
+    }
+
+    fn ehdr(&mut self, phnum: usize) {
+        self.0.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELF_CLASS, 1, 1]);
+        self.0.extend_from_slice(&[0; 9]);
+        self.u16(ET_CORE);
+        self.u16(EM);
+        self.u32(1);            // e_version
+        self.word(0);           // e_entry
+        self.word(EHDR_SIZE);   // e_phoff
+        self.word(0);           // e_shoff
+        self.u32(0);            // e_flags
+        self.u16(EHDR_SIZE as u16);
+        self.u16(PHDR_SIZE as u16);
+        self.u16(phnum as u16);
+        self.u16(0);            // e_shentsize
+        self.u16(0);            // e_shnum
+        self.u16(0);            // e_shstrndx
+    }
+
+    fn phdr(&mut self, flags: u32, offset: usize, vaddr: VirtAddr, file_size: usize, mem_size: usize) {
+        self.u32(PT_LOAD);
+        if WORD_SIZE == 8 {
+            self.u32(flags);
+        }
+        self.word(offset);
+        self.word(vaddr);
+        self.word(0);           // p_paddr
+        self.word(file_size);
+        self.word(mem_size);
+        if WORD_SIZE == 4 {
+            self.u32(flags);
+        }
+        self.word(PAGE_SIZE);   // p_align
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+const WORD_SIZE: usize = 8;
+#[cfg(target_pointer_width = "32")]
+const WORD_SIZE: usize = 4;
+
+const ELF_CLASS: u8 = WORD_SIZE as u8 / 4;
+const EHDR_SIZE: usize = 16 + 2 * 2 + 4 + 3 * WORD_SIZE + 4 + 6 * 2;
+const PHDR_SIZE: usize = 2 * 4 + 6 * WORD_SIZE;
+
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM: u16 = 62;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const EM: u16 = 243;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "riscv32", target_arch = "riscv64")))]
+const EM: u16 = 0;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(data: &[u8], offset: usize, size: usize) -> usize {
+        (0..size).fold(0, |x, i| x | (data[offset + i] as usize) << (i * 8))
+    }
+
+    /// Read the `i`th word field (p_offset, p_vaddr, ...) of the program header at `ph`.
+    fn read_phdr(data: &[u8], ph: usize, i: usize) -> usize {
+        let first = if WORD_SIZE == 8 { 8 } else { 4 };
+        read(data, ph + first + i * WORD_SIZE, WORD_SIZE)
+    }
+
+    #[test]
+    fn header() {
+        let text = [0x90u8; 0x10];
+        let segments = [
+            Segment { start: 0x1000, end: 0x1010, readable: true, writable: false, executable: true, data: Some(&text) },
+            Segment { start: 0x3000, end: 0x5000, readable: false, writable: true, executable: false, data: None },
+        ];
+        let mut core = Vec::new();
+        write_core(&mut core, &segments).unwrap();
+
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(EHDR_SIZE, if WORD_SIZE == 8 { 64 } else { 52 });
+        assert_eq!(PHDR_SIZE, if WORD_SIZE == 8 { 56 } else { 32 });
+        assert_eq!(read(&core, 16, 2), ET_CORE as usize);
+        assert_eq!(read(&core, 16 + 4 + 4 + WORD_SIZE * 3 + 4 + 4, 2), 2, "e_phnum");
+
+        let ph = EHDR_SIZE;
+        let offset = read_phdr(&core, ph, 0);
+        assert_eq!(offset, PAGE_SIZE);
+        assert_eq!(read_phdr(&core, ph, 1), 0x1000, "p_vaddr");
+        assert_eq!(read_phdr(&core, ph, 3), 0x10, "p_filesz");
+        assert_eq!(&core[offset..], &text[..]);
+
+        let ph = EHDR_SIZE + PHDR_SIZE;
+        assert_eq!(read_phdr(&core, ph, 3), 0, "p_filesz");
+        assert_eq!(read_phdr(&core, ph, 4), 0x2000, "p_memsz");
+    }
+}
@@ -60,8 +60,8 @@ impl<T: PageTable> CowExt<T> {
         self.page_table.unmap(addr);
     }
     /// This function must be called whenever PageFault happens.
-    /// Return whether copy-on-write happens.
-    pub fn page_fault_handler(&mut self, addr: VirtAddr, alloc_frame: impl FnOnce() -> PhysAddr) -> bool {
+    /// Return whether copy-on-write happens, `false` if `alloc_frame` runs out of frames.
+    pub fn page_fault_handler(&mut self, addr: VirtAddr, alloc_frame: impl FnOnce() -> Option<PhysAddr>) -> bool {
         {
             let entry = self.page_table.get_entry(addr);
             if !entry.readonly_shared() && !entry.writable_shared() {
@@ -76,12 +76,16 @@ impl<T: PageTable> CowExt<T> {
                 return true;
             }
         }
+        let frame = match alloc_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
         use core::mem::uninitialized;
         let mut temp_data: [u8; PAGE_SIZE] = unsafe { uninitialized() };
         temp_data[..].copy_from_slice(self.get_page_slice_mut(addr));
 
         self.unmap_shared(addr);
-        self.map(addr, alloc_frame());
+        self.map(addr, frame);
 
         self.get_page_slice_mut(addr).copy_from_slice(&temp_data[..]);
         true
@@ -159,7 +163,7 @@ pub mod test {
         let mut alloc = FrameAlloc(4);
 
         pt.page_table.set_handler(Box::new(move |_, addr: VirtAddr| {
-            pt0.page_fault_handler(addr, || alloc.alloc());
+            pt0.page_fault_handler(addr, || Some(alloc.alloc()));
         }));
 
         test_with(&mut pt);
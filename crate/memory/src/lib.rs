@@ -12,6 +12,9 @@ pub mod paging;
 pub mod cow;
 pub mod swap;
 pub mod memory_set;
+pub mod coredump;
+pub mod access_sampler;
+pub mod refcount;
 mod addr;
 
 pub use addr::*;
\ No newline at end of file
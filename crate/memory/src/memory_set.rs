@@ -10,16 +10,25 @@
 //! 
 //! A detailed description may be found in [rust-os-docs](https://rucore.gitbook.io/rust-os-docs/nei-cun-guan-li-mo-kuai) (in Chinese).
 
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
-use core::fmt::{Debug, Error, Formatter};
+use core::fmt::{self, Debug, Error, Formatter};
+use core::marker::PhantomData;
+use core::mem;
 use super::*;
 use paging::*;
+use swap::{set_swap_slot, swap_slot, SwapError, Swapper};
 
 /// An inactive, temporarily uneditable page table
 pub trait InactivePageTable {
     /// Associated type: active, editable page table
     type Active: PageTable;
 
+    /// Number of levels of the page table, e.g. 4 for x86_64, 3 for RISC-V Sv39, 2 for Sv32.
+    ///
+    /// It decides the range of valid user addresses, see `paging::user_space_end`.
+    const LEVELS: usize;
+
     /// Creates a new page table, sets recursive mapping and maps kernel space.
     fn new() -> Self;
 
@@ -31,8 +40,8 @@ pub trait InactivePageTable {
 
     unsafe fn activate(&self);
 
-    /// Activates the page table temporarily and apply function f.
-    unsafe fn with(&self, f: impl FnOnce());
+    /// Activates the page table temporarily and apply function f, returns its result.
+    unsafe fn with<R>(&self, f: impl FnOnce() -> R) -> R;
 
     /// Returns CR3(x86_64)/satp(RISC-V) when the page table is valid.
     fn token(&self) -> usize;
@@ -43,12 +52,56 @@ pub trait InactivePageTable {
     /// Dealloc a physical frame. Used by MemoryArea.
     fn dealloc_frame(target: PhysAddr);
 
-    /// Alloc kernel stack. Used at MemorySet initialization.
-    fn alloc_stack() -> Stack;
+    /// Returns a frame filled with zeros, shared read-only by pages of lazy areas until written.
+    ///
+    /// It must stay the same and never be freed.
+    fn zero_frame() -> PhysAddr;
+
+    /// Adds a reference to a frame shared by copy-on-write pages, see `MemorySet::clone_cow`.
+    ///
+    /// A frame has 1 reference when allocated, from the page it is allocated for.
+    /// Implementations can keep the counts in a global `refcount::FrameRefCount`.
+    fn frame_ref_inc(target: PhysAddr);
+
+    /// Removes a reference to a frame, returns how many are left.
+    /// The frame is not freed here even if there is none.
+    fn frame_ref_dec(target: PhysAddr) -> usize;
+
+    /// Returns how many pages are referring to a frame.
+    fn frame_ref_count(target: PhysAddr) -> usize;
+
+    /// Alloc kernel stack. Used the first time a MemorySet needs one, see `MemorySet::ensure_kstack`.
+    ///
+    /// An unmapped guard page may be left below it, so that overflowing the stack faults.
+    /// Returns `None` if kernel stacks or frames ran out.
+    fn alloc_stack() -> Option<Stack>;
+
+    /// Frees a kernel stack from `alloc_stack`, when its MemorySet is dropped.
+    ///
+    /// The default implementation never frees it.
+    fn dealloc_stack(_stack: Stack) {}
+
+    /// Frees slot `token` of the swapper, when the page swapped out to it is unmapped,
+    /// or the copy kept in it for a resident page is dropped, see `MemorySet::swap_in_page`.
+    ///
+    /// The default implementation does nothing, which is fine if pages are never swapped out.
+    fn swap_free(_token: usize) {}
+
+    /// Zeroes `[start, end)`, which is mapped in the page table.
+    ///
+    /// The default implementation activates the page table by `with`,
+    /// and clears the range in one pass instead of page by page, so that wide stores can be used.
+    unsafe fn zero(&self, start: VirtAddr, end: VirtAddr) {
+        use core::ptr;
+        self.with(|| ptr::write_bytes(start as *mut u8, 0, end - start));
+    }
 }
 
 /// 一片连续内存空间，有相同的访问权限
 /// 对应ucore中 `vma_struct`
+///
+/// An empty area (`start_addr == end_addr`) spans no pages:
+/// it maps nothing and overlaps with nothing.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct MemoryArea {
     start_addr: VirtAddr,
@@ -56,12 +109,110 @@ pub struct MemoryArea {
     phys_start_addr: Option<PhysAddr>, // can either be mapped or not
     flags: MemoryAttr,
     name: &'static str,
+    kind: AreaKind,
+    /// Offset of the first page from the first page of the area as created,
+    /// which is passed to the fault handler. Not 0 if the front is split off.
+    handler_offset: usize,
+    /// Map with large pages where possible, see `large_pages`.
+    large_pages: bool,
+    /// Frame of each page, if created by `new_physical_frames`. `phys_start_addr` is the first one.
+    frames: Option<&'static [PhysAddr]>,
+}
+
+/// How the pages of a memory area are backed.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AreaKind {
+    /// Mapped when pushed into MemorySet, to `phys_start_addr` or allocated frames.
+    Mapped,
+    /// Only reserves the virtual range. Nothing is mapped, any access faults fatally.
+    Reserved,
+    /// Pages are mapped on the first access, filled by the handler. See `MemorySet::handle_fault`.
+    Handled(FaultHandler),
+    /// Pages are mapped on the first access, read from a byte source. See `MemoryArea::new_file`.
+    File(FileMapping),
+    /// Mapped to the frames of a shared memory segment. See `MemorySet::attach_shared`.
+    Shared,
+}
+
+/// Fills a faulting page of an area created by `MemoryArea::new_with_fault_handler`.
+///
+/// Receives offset of the page from the first page of the area, and the page to fill.
+/// Returns `Err` to make the fault fatal.
+#[derive(Copy, Clone)]
+pub struct FaultHandler(pub fn(offset: usize, page: &mut [u8]) -> Result<(), ()>);
+
+impl PartialEq for FaultHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 as usize == other.0 as usize
+    }
+}
+
+impl Eq for FaultHandler {}
+
+impl Debug for FaultHandler {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "FaultHandler({:#x})", self.0 as usize)
+    }
+}
+
+/// Bytes backing an area created by `MemoryArea::new_file`, e.g. a file, or an ELF image in memory.
+///
+/// It is not tied to any filesystem. Areas are copied freely, so it must live forever.
+pub trait ByteSource: Sync {
+    /// Reads bytes from `offset` into `buf`, returns how many bytes are read.
+    ///
+    /// Less than `buf.len()` only if the source ends. Returns `Err` if it can not be read.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, ()>;
+}
+
+/// `len` bytes of `source` from `offset`, mapped at `start_addr`. See `MemoryArea::new_file`.
+#[derive(Copy, Clone)]
+pub struct FileMapping {
+    source: &'static ByteSource,
+    offset: usize,
+    len: usize,
+    start_addr: VirtAddr,
+}
+
+impl FileMapping {
+    /// Reads the bytes mapped in the page at `page` into `data`, the page zeroed.
+    ///
+    /// Returns `Err` if the source can not be read, or ends before `len`.
+    fn read_page(&self, page: VirtAddr, data: &mut [u8]) -> Result<(), ()> {
+        let start = self.start_addr.max(page);
+        let end = (self.start_addr + self.len).min(page + PAGE_SIZE);
+        if start >= end {
+            return Ok(());
+        }
+        let buf = &mut data[start - page..end - page];
+        match self.source.read_at(self.offset + (start - self.start_addr), buf)? {
+            n if n == buf.len() => Ok(()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl PartialEq for FileMapping {
+    fn eq(&self, other: &Self) -> bool {
+        self.source as *const ByteSource as *const u8 == other.source as *const ByteSource as *const u8
+            && self.offset == other.offset && self.len == other.len && self.start_addr == other.start_addr
+    }
+}
+
+impl Eq for FileMapping {}
+
+impl Debug for FileMapping {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "FileMapping({:#x}, offset: {:#x}, len: {:#x})",
+               self.source as *const ByteSource as *const u8 as usize, self.offset, self.len)
+    }
 }
 
 impl MemoryArea {
     pub fn new(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
         assert!(start_addr <= end_addr, "invalid memory area");
-        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name }
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name, kind: AreaKind::Mapped,
+                     handler_offset: 0, large_pages: false, frames: None }
     }
 
     /// Create a new memory area which is identically mapped.
@@ -69,7 +220,8 @@ impl MemoryArea {
     /// *notice that mappings will be done only when pushed into MemorySet*
     pub fn new_identity(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
         assert!(start_addr <= end_addr, "invalid memory area");
-        MemoryArea { start_addr, end_addr, phys_start_addr: Some(start_addr), flags, name }
+        MemoryArea { start_addr, end_addr, phys_start_addr: Some(start_addr), flags, name, kind: AreaKind::Mapped,
+                     handler_offset: 0, large_pages: false, frames: None }
     }
 
     /// Create a new memory area mapped with a offset.
@@ -81,12 +233,110 @@ impl MemoryArea {
     ///     (phys_start_addr, phys_end_addr)
     /// 
     /// *notice that mappings will be done only when pushed into MemorySet*
+    ///
+    /// Device memory should be mapped with `MemoryAttr::uncacheable`.
     pub fn new_physical(phys_start_addr: PhysAddr, phys_end_addr: PhysAddr, offset: usize, flags: MemoryAttr, name: &'static str) -> Self {
         let start_addr = phys_start_addr + offset;
         let end_addr = phys_end_addr + offset;
         assert!(start_addr <= end_addr, "invalid memory area");
         let phys_start_addr = Some(phys_start_addr);
-        MemoryArea { start_addr, end_addr, phys_start_addr, flags, name }
+        MemoryArea { start_addr, end_addr, phys_start_addr, flags, name, kind: AreaKind::Mapped,
+                     handler_offset: 0, large_pages: false, frames: None }
+    }
+
+    /// Create a new memory area mapped to `frames`, one per page from `start_addr`,
+    /// e.g. for a scatter-gather DMA buffer.
+    ///
+    /// Like `new_physical`, the frames are owned by the caller and never freed by the area.
+    /// Areas are copied freely, so the list must live forever.
+    ///
+    /// *notice that mappings will be done only when pushed into MemorySet*
+    pub fn new_physical_frames(frames: &'static [PhysAddr], start_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
+        assert!(start_addr % PAGE_SIZE == 0, "invalid memory area");
+        assert!(frames.iter().all(|frame| frame % PAGE_SIZE == 0), "frame not page aligned");
+        let end_addr = start_addr + frames.len() * PAGE_SIZE;
+        MemoryArea { start_addr, end_addr, phys_start_addr: frames.first().cloned(), flags, name, kind: AreaKind::Mapped,
+                     handler_offset: 0, large_pages: false, frames: Some(frames) }
+    }
+
+    /// Create a new memory area whose pages are filled by `handler` on demand.
+    ///
+    /// Nothing is allocated when pushed into MemorySet.
+    /// The kernel never accesses these pages itself: syscalls, fork and core dumps skip the area.
+    pub fn new_with_fault_handler(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str,
+                                  handler: fn(usize, &mut [u8]) -> Result<(), ()>) -> Self {
+        assert!(start_addr <= end_addr, "invalid memory area");
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name, kind: AreaKind::Handled(FaultHandler(handler)),
+                     handler_offset: 0, large_pages: false, frames: None }
+    }
+
+    /// Create a new memory area whose pages are read from `source` on the first access, like `mmap` of a file.
+    ///
+    /// `len` bytes of `source` from `offset` are mapped at `start_addr`, the rest of the area is zero,
+    /// e.g. an ELF segment whose memory size is larger than its file size.
+    /// Like `new_with_fault_handler`, nothing is allocated when pushed into MemorySet,
+    /// and the kernel never accesses these pages itself.
+    pub fn new_file(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str,
+                    source: &'static ByteSource, offset: usize, len: usize) -> Self {
+        assert!(start_addr <= end_addr, "invalid memory area");
+        assert!(len <= end_addr - start_addr, "file mapping out of the area");
+        let file = FileMapping { source, offset, len, start_addr };
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name, kind: AreaKind::File(file),
+                     handler_offset: 0, large_pages: false, frames: None }
+    }
+
+    /// Create a new memory area whose pages are zero on the first access.
+    ///
+    /// A page read first is mapped to the shared zero frame, see `InactivePageTable::zero_frame`,
+    /// and a frame is allocated for it on the first write.
+    /// It saves frames for large areas which are mostly untouched, or only read.
+    /// Like `new_with_fault_handler`, the kernel never accesses these pages itself.
+    pub fn new_lazy(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
+        Self::new_with_fault_handler(start_addr, end_addr, flags, name, zero_fill)
+    }
+
+    /// Create a guard area, e.g. below a stack, with nothing mapped.
+    ///
+    /// Like a reserved area any access faults fatally,
+    /// but `MemorySet::handle_fault` reports it as `FaultResult::StackOverflow`.
+    pub fn new_guard(start_addr: VirtAddr, end_addr: VirtAddr, name: &'static str) -> Self {
+        assert!(start_addr <= end_addr, "invalid memory area");
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags: MemoryAttr::default().hide(), name,
+                     kind: AreaKind::Reserved, handler_offset: 0, large_pages: false, frames: None }
+    }
+
+    /// If the area is created by `new_guard`.
+    pub fn is_guard(&self) -> bool {
+        self.kind == AreaKind::Reserved && self.flags.hide
+    }
+
+    /// If the area can not be accessed at all, e.g. a guard, or made `PROT_NONE` by `MemorySet::mprotect`.
+    ///
+    /// A fault in it is never resolved, see `MemoryAttr::hide`.
+    pub fn is_hidden(&self) -> bool {
+        self.flags.hide
+    }
+
+    /// If the area is created by `new_lazy`.
+    fn is_lazy(&self) -> bool {
+        self.kind == AreaKind::Handled(FaultHandler(zero_fill))
+    }
+
+    /// If the area is anonymous memory, mapped or lazy, backed by nothing but frames of its own.
+    pub fn is_anonymous(&self) -> bool {
+        self.phys_start_addr.is_none() && (self.kind == AreaKind::Mapped || self.is_lazy())
+    }
+
+    /// Requests mapping the physical area with large pages, see `PageTable::map_large`.
+    ///
+    /// Only parts aligned to `PageTable::LARGE_PAGE_SIZE` both in virtual and physical address
+    /// and spanning a whole large page are mapped so, the rest with normal pages.
+    /// A large page can only be unmapped as a whole.
+    pub fn large_pages(mut self) -> Self {
+        assert!(self.phys_start_addr.is_some(), "large pages for an area not physical");
+        assert!(self.frames.is_none(), "large pages for an area of frames");
+        self.large_pages = true;
+        self
     }
 
     /// Get raw content in the area as a slice.
@@ -101,65 +351,285 @@ impl MemoryArea {
         slice::from_raw_parts_mut(self.start_addr as *mut u8, self.end_addr - self.start_addr)
     }
 
+    pub fn start_addr(&self) -> VirtAddr {
+        self.start_addr
+    }
+    pub fn end_addr(&self) -> VirtAddr {
+        self.end_addr
+    }
+    pub fn flags(&self) -> MemoryAttr {
+        self.flags
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    pub fn set_name(&mut self, name: &'static str) {
+        self.name = name;
+    }
+    pub fn kind(&self) -> AreaKind {
+        self.kind
+    }
+
+    /// A copy of the area narrowed to `[start, end)`, e.g. for a part split off.
+    ///
+    /// The part keeps everything but the range, maps to the same frames if physical,
+    /// and is filled the same way if there is a fault handler or a file.
+    pub fn with_range(&self, start: VirtAddr, end: VirtAddr) -> MemoryArea {
+        assert!(self.start_addr <= start && start <= end && end <= self.end_addr, "range out of the area");
+        self.slice(start, end)
+    }
+
+    /// The part `[start, end)` of the area, mapped to the same frames if physical,
+    /// and filled the same way if there is a fault handler.
+    /// Unlike `with_range` it may be out of the area, e.g. for a growing heap.
+    fn slice(&self, start: VirtAddr, end: VirtAddr) -> MemoryArea {
+        let offset = Page::of_addr(start).start_address() - Page::of_addr(self.start_addr).start_address();
+        let frames = self.frames.map(|frames| {
+            let first = offset / PAGE_SIZE;
+            &frames[first..first + Page::range_of(start, end).len()]
+        });
+        let phys_start_addr = match frames {
+            Some(frames) => frames.first().cloned(),
+            None => self.phys_start_addr.map(|phys| phys + start - self.start_addr),
+        };
+        let handler_offset = self.handler_offset + offset;
+        MemoryArea { start_addr: start, end_addr: end, phys_start_addr, handler_offset, frames, ..*self }
+    }
+
+    /// Physical address `addr` in the area is mapped to, if the area is physical.
+    fn phys_addr_of(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        match self.frames {
+            Some(frames) => {
+                let page = Page::of_addr(addr).start_address() - Page::of_addr(self.start_addr).start_address();
+                Some(frames[page / PAGE_SIZE] + addr % PAGE_SIZE)
+            }
+            None => self.phys_start_addr.map(|phys| addr - self.start_addr + phys),
+        }
+    }
+
+    /// The area with its start moved down to `start`, e.g. for a growing stack.
+    ///
+    /// Only for anonymous areas, mapped or lazy, as others have nothing to map below.
+    fn extend_down(&self, start: VirtAddr) -> MemoryArea {
+        assert!(start <= self.start_addr, "not extending down");
+        assert!(self.is_anonymous(), "only anonymous areas can be extended down");
+        MemoryArea { start_addr: start, ..*self }
+    }
+
+    /// The area joined with `next` starting right at its end, or `None` if they can not be one area.
+    ///
+    /// They must be the same in everything but the range, like two slices of one area:
+    /// the same kind, attributes and name, physical ranges or offsets of pages filled on faults continuing.
+    /// Shared areas are never joined, as they are attached as a whole, nor areas of frame lists.
+    fn merge(&self, next: &MemoryArea) -> Option<MemoryArea> {
+        if self.start_addr == self.end_addr || next.start_addr == next.end_addr
+            || self.end_addr != next.start_addr || self.kind == AreaKind::Shared
+            || self.frames.is_some() || next.frames.is_some() {
+            return None;
+        }
+        let joined = MemoryArea { end_addr: next.end_addr, ..*self };
+        let mut expected = joined.slice(next.start_addr, next.end_addr);
+        match self.kind {
+            AreaKind::Handled(_) | AreaKind::File(_) => {}
+            // The offset only matters to areas filled on faults
+            _ => expected.handler_offset = next.handler_offset,
+        }
+        match expected == *next {
+            true => Some(joined),
+            false => None,
+        }
+    }
+
     /// If a virtual address is contained in the area.
     pub fn contains(&self, addr: VirtAddr) -> bool {
         addr >= self.start_addr && addr < self.end_addr
     }
 
+    /// Page aligned `[start, end)` of a non-empty area.
+    fn page_range(&self) -> (VirtAddr, VirtAddr) {
+        (Page::of_addr(self.start_addr).start_address(),
+         (Page::of_addr(self.end_addr - 1) + 1).start_address())
+    }
+
     /// If two areas overlap with each other.
-    fn is_overlap_with(&self, other: &MemoryArea) -> bool {
+    pub fn is_overlap_with(&self, other: &MemoryArea) -> bool {
+        self.overlaps_range(other.start_addr, other.end_addr)
+    }
+
+    /// If the area overlaps with `[start_addr, end_addr)`, in pages.
+    ///
+    /// Empty areas and ranges overlap with nothing.
+    pub fn overlaps_range(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        if self.start_addr == self.end_addr || start_addr == end_addr {
+            return false;
+        }
         let p0 = Page::of_addr(self.start_addr);
         let p1 = Page::of_addr(self.end_addr - 1) + 1;
-        let p2 = Page::of_addr(other.start_addr);
-        let p3 = Page::of_addr(other.end_addr - 1) + 1;
+        let p2 = Page::of_addr(start_addr);
+        let p3 = Page::of_addr(end_addr - 1) + 1;
         !(p1 <= p2 || p0 >= p3)
     }
 
     /// Maps memory area to corresponding physical area.
     /// 
     /// If physical address is not specified, then maps to an allocated frame.
-    fn map<T: InactivePageTable>(&self, pt: &mut T::Active) {
-        match self.phys_start_addr {
-            Some(phys_start) => {
+    /// An area of frames maps each page to its own frame in the list.
+    /// Returns how many frames are allocated, or `Err` with nothing mapped if frames run out.
+    ///
+    /// Entries are not updated one by one, the whole area is flushed once at the end by
+    /// `PageTable::flush_range`. For a 1024-page area, 1024 `Entry::update` flushes become 1,
+    /// which is a single reload of CR3 on x86_64 instead of 1024 `invlpg`.
+    fn map<T: InactivePageTable>(&self, pt: &mut T::Active) -> Result<usize, MapError> {
+        match self.kind {
+            AreaKind::Reserved => return Ok(0),
+            AreaKind::Handled(_) | AreaKind::File(_) => {
+                // Not present until the first access, see `MemorySet::handle_fault`
                 for page in Page::range_of(self.start_addr, self.end_addr) {
-                    let addr = page.start_address();
-                    let target = page.start_address() - self.start_addr + phys_start;
-                    self.flags.apply(pt.map(addr, target));
+                    pt.map(page.start_address(), 0).set_present(false);
                 }
+                pt.flush_range(self.start_addr, self.end_addr);
+                return Ok(0);
+            }
+            AreaKind::Shared => panic!("shared memory is only mapped by attach_shared"),
+            AreaKind::Mapped => {}
+        }
+        match self.phys_start_addr {
+            Some(_) => {
+                self.map_physical::<T>(pt);
+                Ok(0)
             }
             None => {
-                for page in Page::range_of(self.start_addr, self.end_addr) {
+                let pages = Page::range_of(self.start_addr, self.end_addr);
+                let count = pages.len();
+                for page in pages {
                     let addr = page.start_address();
-                    let target = T::alloc_frame().expect("failed to allocate frame");
-                    self.flags.apply(pt.map(addr, target));
+                    let target = match T::alloc_frame() {
+                        Some(target) => target,
+                        None => {
+                            // Free the pages mapped so far
+                            MemoryArea { end_addr: addr, ..*self }.unmap::<T>(pt);
+                            return Err(MapError::OutOfMemory);
+                        }
+                    };
+                    self.flags.apply_no_flush(pt.map(addr, target));
+                }
+                pt.flush_range(self.start_addr, self.end_addr);
+                Ok(count)
+            }
+        }
+    }
+
+    /// Maps a physical area, which allocates no frame so it never fails.
+    fn map_physical<T: InactivePageTable>(&self, pt: &mut T::Active) {
+        assert!(self.phys_start_addr.is_some() && self.kind == AreaKind::Mapped, "not a physical area");
+        if let Some(frames) = self.frames {
+            assert_eq!(frames.len(), Page::range_of(self.start_addr, self.end_addr).len(),
+                       "frame count not matching page count");
+        }
+        let large = <T::Active as PageTable>::LARGE_PAGE_SIZE;
+        let (start, end) = self.page_range();
+        let mut addr = start;
+        while addr < end {
+            let target = self.phys_addr_of(addr).unwrap();
+            if self.large_pages && addr % large == 0 && target % large == 0 && end - addr >= large {
+                if let Some(entry) = pt.map_large(addr, target) {
+                    self.flags.apply_no_flush(entry);
+                    addr += large;
+                    continue;
                 }
             }
+            self.flags.apply_no_flush(pt.map(addr, target));
+            addr += PAGE_SIZE;
         }
+        pt.flush_range(start, end);
     }
 
     /// Unmaps the memory area.
-    fn unmap<T: InactivePageTable>(&self, pt: &mut T::Active) {
+    ///
+    /// Returns how many pages with a frame are unmapped, see `MemorySet::rss`.
+    /// Like `map`, the TLB is flushed once for the whole area.
+    fn unmap<T: InactivePageTable>(&self, pt: &mut T::Active) -> usize {
+        if self.kind == AreaKind::Reserved {
+            return 0;
+        }
+        let mut resident = 0;
+        let large = <T::Active as PageTable>::LARGE_PAGE_SIZE;
+        // End of the last large page unmapped
+        let mut large_end = 0;
         for page in Page::range_of(self.start_addr, self.end_addr) {
             let addr = page.start_address();
+            if addr < large_end {
+                continue;
+            }
+            if pt.is_large(addr) {
+                assert!(addr % large == 0 && addr + large <= self.page_range().1, "unmapping part of a large page");
+                pt.unmap_no_flush(addr);
+                large_end = addr + large;
+                continue;
+            }
+            let entry = pt.get_entry(addr);
+            // Keeps its frame, mark it present to unmap
+            if entry.hidden() {
+                entry.set_hidden(false);
+                entry.set_present(true);
+            }
             if self.phys_start_addr.is_none() {
-                let target = pt.get_entry(addr).target();
-                T::dealloc_frame(target);
+                match self.kind {
+                    // Shared memory, freed by the last attachment or handle. Not resident.
+                    AreaKind::Shared => {
+                        if T::frame_ref_dec(entry.target()) == 0 {
+                            T::dealloc_frame(entry.target());
+                        }
+                    }
+                    // Swapped out, there is no frame but the slot
+                    _ if entry.swapped() => {
+                        T::swap_free(swap_slot(entry).unwrap());
+                        entry.set_swapped(false);
+                        entry.set_present(true);
+                    }
+                    // Never accessed, there is no frame. Mark it present to unmap.
+                    AreaKind::Handled(_) | AreaKind::File(_) if !entry.present() => entry.set_present(true),
+                    // The zero frame is never freed
+                    _ if entry.target() == T::zero_frame() => resident += 1,
+                    // Shared by copy-on-write, freed by the last one
+                    _ if entry.readonly_shared() || entry.writable_shared() => {
+                        if T::frame_ref_dec(entry.target()) == 0 {
+                            T::dealloc_frame(entry.target());
+                        }
+                        resident += 1;
+                    }
+                    _ => {
+                        T::dealloc_frame(entry.target());
+                        resident += 1;
+                    }
+                }
             }
-            pt.unmap(addr);
+            pt.unmap_no_flush(addr);
         }
+        pt.flush_range(self.start_addr, self.end_addr);
+        resident
     }
 }
 
+/// Fault handler of lazy areas.
+fn zero_fill(_offset: usize, _page: &mut [u8]) -> Result<(), ()> {
+    // The page is zeroed before the handler
+    Ok(())
+}
+
 /// Attributes of a memory area.
 /// 
 /// Only simpliest functions are provided,
-/// because attribute of a memory area rarely changes.
+/// because attribute of a memory area rarely changes, see `MemorySet::mprotect`.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub struct MemoryAttr {
     user: bool,
     readonly: bool,
     execute: bool,
     hide: bool,
+    uncacheable: bool,
+    write_through: bool,
+    global: bool,
 }
 
 impl MemoryAttr {
@@ -179,23 +649,174 @@ impl MemoryAttr {
         self.hide = true;
         self
     }
+    /// Disable caching, e.g. for MMIO regions of devices mapped by `MemoryArea::new_physical`.
+    pub fn uncacheable(mut self) -> Self {
+        self.uncacheable = true;
+        self
+    }
+    /// Write through the cache instead of write back.
+    pub fn write_through(mut self) -> Self {
+        self.write_through = true;
+        self
+    }
+    /// Keep in the TLB across page table switches.
+    ///
+    /// Only for kernel mappings, which are the same in all page tables.
+    pub fn global(mut self) -> Self {
+        self.global = true;
+        self
+    }
+    /// If writable and executable at the same time, which no legitimate segment needs.
+    pub fn is_wx(&self) -> bool {
+        !self.readonly && self.execute
+    }
 
     /// Apply attributes to a page entry.
+    ///
+    /// Every permission bit is written, so bits of previous attributes do not stay.
     fn apply(&self, entry: &mut impl Entry) {
-        if self.user { entry.set_user(true); }
-        if self.readonly { entry.set_writable(false); }
-        if self.execute { entry.set_execute(true); }
-        if self.hide { entry.set_present(false); }
-        if self.user || self.readonly || self.execute || self.hide { entry.update(); }
+        self.apply_no_flush(entry);
+        entry.update();
+    }
+
+    /// Like `apply`, but the entry is not updated.
+    /// The caller must flush it after, see `PageTable::flush_range`.
+    fn apply_no_flush(&self, entry: &mut impl Entry) {
+        entry.set_user(self.user);
+        entry.set_writable(!self.readonly);
+        entry.set_execute(self.execute);
+        entry.set_present(!self.hide);
+        entry.set_hidden(self.hide);
+        entry.set_cache_disable(self.uncacheable);
+        entry.set_write_through(self.write_through);
+        entry.set_global(self.global);
+    }
+}
+
+/// How `MemorySet::handle_fault` resolved a page fault.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FaultResult {
+    /// The page is swapped in.
+    SwappedIn,
+    /// A frame is allocated for the page of an area with a fault handler,
+    /// or the zero frame is mapped for a read of a lazy one.
+    Allocated,
+    /// The copy-on-write page is copied, or made writable in place.
+    Copied,
+    /// The fault handler of the area refused to fill the page, or the file can not be read. The fault is fatal.
+    Refused,
+    /// The stack above the faulting guard area is grown down by a page, see `MemorySet::set_max_stack`.
+    StackGrown,
+    /// Access to a guard area, see `MemoryArea::new_guard`,
+    /// and the stack above it can not grow any more. The fault is fatal.
+    StackOverflow,
+    /// Frames ran out, nothing is changed. The fault is fatal.
+    OutOfMemory,
+    /// Not a fault of the memory set. The caller may resolve it in other ways, or kill the process.
+    Unhandled,
+}
+
+impl FaultResult {
+    /// If the faulting access can be retried.
+    pub fn is_resolved(&self) -> bool {
+        match self {
+            FaultResult::SwappedIn | FaultResult::Allocated | FaultResult::Copied | FaultResult::StackGrown => true,
+            FaultResult::Refused | FaultResult::StackOverflow | FaultResult::OutOfMemory | FaultResult::Unhandled => false,
+        }
+    }
+}
+
+/// Why a memory area can not be mapped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MapError {
+    /// No frame can be allocated for a page.
+    OutOfMemory,
+}
+
+/// Why `MemorySet::try_push` or `MemorySet::try_push_all` failed. Nothing is added then.
+#[derive(Debug, Eq, PartialEq)]
+pub enum PushError {
+    /// The area overlaps with an area in the set, or one pushed before it.
+    Overlap(MemoryArea),
+    /// Frames ran out when mapping the area.
+    OutOfMemory(MemoryArea),
+    /// The area is writable and executable, which is refused by `MemorySet::set_enforce_wx`.
+    WriteExecute(MemoryArea),
+}
+
+/// Areas and page contents of a memory set, taken by `MemorySet::snapshot`.
+pub struct MemorySnapshot {
+    areas: Vec<MemoryArea>,
+    heap: Option<(VirtAddr, VirtAddr)>,
+    /// Contents of present pages of anonymous areas and areas with a fault handler, by page address.
+    pages: Vec<(VirtAddr, Vec<u8>)>,
+}
+
+impl MemorySnapshot {
+    /// Bytes of page contents captured.
+    pub fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
     }
 }
 
+/// Numbers of page faults resolved by `MemorySet::handle_fault`, by kind.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct FaultCounters {
+    /// Resolved without I/O: allocating or zero mapping a page, or growing the stack.
+    pub minor_faults: usize,
+    /// Resolved by I/O: swapping a page in, or reading it from a file.
+    pub major_faults: usize,
+    /// Resolved by copying a copy-on-write page, or making it writable.
+    pub cow_faults: usize,
+}
+
+/// A page whose entry does not match its area, found by `MemorySet::verify`.
+#[cfg(any(test, debug_assertions))]
+#[derive(Debug, Eq, PartialEq)]
+pub enum VerifyError {
+    /// The page should be present.
+    NotPresent(VirtAddr),
+    /// The page should not be present, e.g. in a hidden area.
+    Present(VirtAddr),
+    /// User, writable, execute or global bit differs from the area attributes.
+    Permission(VirtAddr),
+    /// A page of a physical area is mapped to a wrong frame.
+    Target(VirtAddr),
+    /// Pages with a frame counted in the page table differ from `MemorySet::rss`.
+    Resident(usize),
+}
+
+
 /// 内存空间集合，包含若干段连续空间
 /// 对应ucore中 `mm_struct`
+///
+/// SMP is not supported: changes only flush the TLB of the current CPU, there is no TLB shootdown.
 pub struct MemorySet<T: InactivePageTable> {
+    /// Sorted by start address, so that looking up an address is a binary search.
+    /// Areas starting at the same address, an empty one and a non-empty one, are in the order pushed.
     areas: Vec<MemoryArea>,
     page_table: T,
-    kstack: Stack,
+    /// Allocated on first use, as the init context and sets never scheduled do not need one.
+    kstack: Option<Stack>,
+    /// Alternate signal stack `[start, end)` registered by `sigaltstack`.
+    alt_stack: Option<(VirtAddr, VirtAddr)>,
+    /// Range `[start, end)` where `find_free_range` places new areas.
+    user_space: (VirtAddr, VirtAddr),
+    /// Start and end of the heap area, which is moved by `set_brk`.
+    heap: Option<(VirtAddr, VirtAddr)>,
+    /// Number of pages with a frame in anonymous areas, see `rss`.
+    resident: usize,
+    /// Size a stack can grow down to on a fault in its guard, see `set_max_stack`.
+    max_stack: usize,
+    /// Address the next `select_victim_page` scan starts at.
+    clock_hand: VirtAddr,
+    /// Swap slots still holding the content of resident pages swapped in, by page address.
+    /// A page not written since then is swapped out again without writing, see `swap_out_page`.
+    swap_slots: BTreeMap<VirtAddr, usize>,
+    /// Faults resolved by `handle_fault` so far.
+    fault_counters: FaultCounters,
+    /// Refuse writable and executable areas, see `set_enforce_wx`.
+    enforce_wx: bool,
 }
 
 impl<T: InactivePageTable> MemorySet<T> {
@@ -203,103 +824,3925 @@ impl<T: InactivePageTable> MemorySet<T> {
         MemorySet {
             areas: Vec::<MemoryArea>::new(),
             page_table: T::new(),
-            kstack: T::alloc_stack(),
+            kstack: None,
+            alt_stack: None,
+            user_space: (PAGE_SIZE, user_space_end(T::LEVELS)),
+            heap: None,
+            resident: 0,
+            max_stack: 0,
+            clock_hand: 0,
+            swap_slots: BTreeMap::new(),
+            fault_counters: FaultCounters::default(),
+            enforce_wx: false,
         }
     }
     /// Used for remap_kernel() where heap alloc is unavailable
+    ///
+    /// Areas are kept in `slice`, which must have room for all of them.
+    /// `kstack` is used as its kernel stack, and freed by `InactivePageTable::dealloc_stack` on drop.
     pub unsafe fn new_from_raw_space(slice: &mut [u8], kstack: Stack) -> Self {
         use core::mem::size_of;
         let cap = slice.len() / size_of::<MemoryArea>();
         MemorySet {
             areas: Vec::<MemoryArea>::from_raw_parts(slice.as_ptr() as *mut MemoryArea, 0, cap),
             page_table: T::new_bare(),
-            kstack,
+            kstack: Some(kstack),
+            alt_stack: None,
+            user_space: (PAGE_SIZE, user_space_end(T::LEVELS)),
+            heap: None,
+            resident: 0,
+            max_stack: 0,
+            clock_hand: 0,
+            swap_slots: BTreeMap::new(),
+            fault_counters: FaultCounters::default(),
+            enforce_wx: false,
         }
     }
     
     /// Returns the MemoryArea containing a certain virtual address.
     pub fn find_area(&self, addr: VirtAddr) -> Option<&MemoryArea> {
-        self.areas.iter().find(|area| area.contains(addr))
+        // Only the last non-empty area starting at or below `addr` can contain it
+        self.areas[..self.count_below(addr.saturating_add(1))].iter().rev()
+            .find(|area| area.start_addr != area.end_addr)
+            .filter(|area| area.contains(addr))
+    }
+
+    /// Returns the MemoryArea containing a certain virtual address, for modification.
+    ///
+    /// The page table is not updated with the area, the caller must remap changed pages.
+    /// The range of the area must not be changed, which areas are looked up by.
+    pub fn find_area_mut(&mut self, addr: VirtAddr) -> Option<&mut MemoryArea> {
+        let i = (0..self.count_below(addr.saturating_add(1))).rev()
+            .find(|&i| self.areas[i].start_addr != self.areas[i].end_addr)
+            .filter(|&i| self.areas[i].contains(addr));
+        i.map(move |i| &mut self.areas[i])
+    }
+
+    /// If `[start_addr, end_addr)` overlaps with no area in the set, in pages.
+    ///
+    /// E.g. to check a fixed address before mapping it. An empty range is always free.
+    pub fn is_range_free(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        assert!(start_addr <= end_addr, "invalid range");
+        if start_addr == end_addr {
+            return true;
+        }
+        let start = Page::of_addr(start_addr).start_address();
+        let end = (Page::of_addr(end_addr - 1) + 1).start_address();
+        // Non-empty areas are disjoint in pages, so their ends are sorted as well.
+        !self.areas[..self.count_below(end)].iter().rev()
+            .filter(|other| other.start_addr != other.end_addr)
+            .take_while(|other| other.page_range().1 > start)
+            .any(|other| other.overlaps_range(start_addr, end_addr))
+    }
+
+    /// If `area` overlaps with any area in the set.
+    fn is_overlap_with(&self, area: &MemoryArea) -> bool {
+        !self.is_range_free(area.start_addr, area.end_addr)
+    }
+
+    /// Number of areas starting below `addr`, found by binary search.
+    fn count_below(&self, addr: VirtAddr) -> usize {
+        use core::cmp::Ordering::{Greater, Less};
+        match self.areas.binary_search_by(|area| if area.start_addr < addr { Less } else { Greater }) {
+            Ok(i) | Err(i) => i,
+        }
+    }
+
+    /// Inserts `area` into `areas`, after the areas starting at or below it.
+    fn push_area(&mut self, area: MemoryArea) {
+        let i = self.count_below(area.start_addr.saturating_add(1));
+        self.areas.insert(i, area);
+    }
+
+    /// Removes the `i`th area from `areas`, keeping the others in order.
+    ///
+    /// Swap slots kept for its pages stay, as it may be pushed again in pieces.
+    /// They are freed by `free_kept_slots` when the pages are unmapped.
+    fn remove_at(&mut self, i: usize) -> MemoryArea {
+        self.areas.remove(i)
+    }
+
+    /// Frees the swap slots kept for resident pages in `[start, end)`, see `swap_in_page`.
+    fn free_kept_slots(&mut self, start: VirtAddr, end: VirtAddr) {
+        let kept: Vec<(VirtAddr, usize)> = self.swap_slots.range(start..end).map(|(&addr, &token)| (addr, token)).collect();
+        for (addr, token) in kept {
+            self.swap_slots.remove(&addr);
+            T::swap_free(token);
+        }
+    }
+
+    /// Returns position of the non-empty area starting at `start_addr` in `areas`.
+    fn position(&self, start_addr: VirtAddr) -> Option<usize> {
+        (0..self.count_below(start_addr.saturating_add(1))).rev()
+            .take_while(|&i| self.areas[i].start_addr == start_addr)
+            .find(|&i| self.areas[i].start_addr != self.areas[i].end_addr)
+    }
+
+    /// If cutting `area` at `addr` splits a large page of it.
+    fn splits_large_page(&mut self, area: &MemoryArea, addr: VirtAddr) -> bool {
+        let (start, end) = area.page_range();
+        if !area.large_pages || addr <= start || addr >= end {
+            return false;
+        }
+        let mut large = false;
+        self.page_table.edit(|pt| large = pt.is_large(addr));
+        large && addr % <T::Active as PageTable>::LARGE_PAGE_SIZE != 0
     }
 
     /// Adds a memory area to MemorySet and maps it.
+    ///
+    /// Panics if it can not be added, see `try_push`.
     pub fn push(&mut self, area: MemoryArea) {
-        assert!(self.areas.iter()
-                    .find(|other| area.is_overlap_with(other))
-                    .is_none(), "memory area overlap");
-        self.page_table.edit(|pt| area.map::<T>(pt));
-        self.areas.push(area);
+        if let Err(err) = self.try_push(area) {
+            push_failed(err);
+        }
     }
 
-    /// Iterator implementation for for-loop.
-    pub fn iter(&self) -> impl Iterator<Item=&MemoryArea> {
-        self.areas.iter()
+    /// Adds a memory area to MemorySet and maps it,
+    /// or returns it without mapping anything if it overlaps with an area in the set,
+    /// it is writable and executable while `set_enforce_wx` is on, or frames run out.
+    pub fn try_push(&mut self, area: MemoryArea) -> Result<(), PushError> {
+        self.check_push(&area)?;
+        let mut allocated = Ok(0);
+        self.page_table.edit(|pt| allocated = area.map::<T>(pt));
+        self.resident += allocated.map_err(|_| PushError::OutOfMemory(area))?;
+        self.push_area(area);
+        Ok(())
     }
 
-    /// See `InactivePageTable.with`
-    pub unsafe fn with(&self, f: impl FnOnce()) {
-        self.page_table.with(f);
+    /// Reserves `[start_addr, end_addr)` so that nothing else can be placed there,
+    /// without mapping or allocating anything.
+    ///
+    /// It's useful for guard regions between areas, and for holding a range for later use.
+    /// Unmapping it, even partially, frees nothing. Unlike `MemoryArea::new_guard`,
+    /// a fault in it is never taken as a stack overflow.
+    pub fn reserve(&mut self, start_addr: VirtAddr, end_addr: VirtAddr, name: &'static str) {
+        let mut area = MemoryArea::new(start_addr, end_addr, MemoryAttr::default(), name);
+        area.kind = AreaKind::Reserved;
+        self.push(area);
     }
 
-    /// See `InactivePageTable.activate`
-    pub unsafe fn activate(&self) {
-        self.page_table.activate();
+    /// Adds several memory areas to MemorySet and maps them in a single `edit`.
+    ///
+    /// Every `push` enters `InactivePageTable::edit` on its own, which on real hardware
+    /// costs a temporary mapping and two full TLB flushes. Pushing the N segments of an ELF
+    /// through `push_all` pays that cost once instead of N times.
+    pub fn push_all(&mut self, areas: impl IntoIterator<Item=MemoryArea>) {
+        if let Err(err) = self.try_push_all(areas) {
+            push_failed(err);
+        }
     }
 
-    /// See `InactivePageTable.token`
-    pub fn token(&self) -> usize {
-        self.page_table.token()
+    /// Like `push_all`, but returns the first area which can not be added like `try_push`.
+    /// Then none of the areas is added or mapped.
+    pub fn try_push_all(&mut self, areas: impl IntoIterator<Item=MemoryArea>) -> Result<(), PushError> {
+        let mut pushed = Vec::new();
+        for area in areas {
+            if let Err(err) = self.check_push(&area) {
+                // Nothing is mapped yet, forget the new areas.
+                self.forget_areas(&pushed);
+                return Err(err);
+            }
+            self.push_area(area);
+            pushed.push(area);
+        }
+        let mut failed = None;
+        {
+            let Self { ref mut page_table, ref mut resident, .. } = self;
+            page_table.edit(|pt| {
+                for (i, area) in pushed.iter().enumerate() {
+                    match area.map::<T>(pt) {
+                        Ok(allocated) => *resident += allocated,
+                        Err(_) => {
+                            // Unmap the areas mapped before
+                            for area in pushed[..i].iter() {
+                                *resident -= area.unmap::<T>(pt);
+                            }
+                            failed = Some(*area);
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        match failed {
+            Some(area) => {
+                self.forget_areas(&pushed);
+                Err(PushError::OutOfMemory(area))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Checks `area` can be pushed, except for running out of frames.
+    fn check_push(&self, area: &MemoryArea) -> Result<(), PushError> {
+        if self.is_overlap_with(area) {
+            return Err(PushError::Overlap(*area));
+        }
+        if self.enforce_wx && area.flags.is_wx() {
+            return Err(PushError::WriteExecute(*area));
+        }
+        Ok(())
     }
 
-    /// Returns address of kernel stack top.
-    pub fn kstack_top(&self) -> usize {
-        self.kstack.top
+    /// Refuses writable and executable areas if `enforce`: pushing them fails with `PushError::WriteExecute`,
+    /// and `mprotect` or `attach_shared` to such flags fails.
+    ///
+    /// It is off by default. It only checks areas added or changed later.
+    pub fn set_enforce_wx(&mut self, enforce: bool) {
+        self.enforce_wx = enforce;
     }
 
-    /// Unmaps all area, release all memories occupied.
-    pub fn clear(&mut self) {
-        let Self { ref mut page_table, ref mut areas, .. } = self;
-        page_table.edit(|pt| {
-            for area in areas.iter() {
+    /// Removes `areas` from the list without unmapping them.
+    fn forget_areas(&mut self, areas: &[MemoryArea]) {
+        for area in areas.iter() {
+            let i = self.areas.iter().position(|other| other == area).unwrap();
+            self.areas.remove(i);
+        }
+    }
+
+    /// Removes the area containing `addr` and unmaps it, like `munmap` of a whole mapping.
+    ///
+    /// Frames of anonymous pages are freed, while those of physical areas are left alone.
+    /// The returned area owns no frame, so it can be pushed again, e.g. to replace the mapping.
+    /// Returns `None` if no area contains `addr`.
+    pub fn remove_area(&mut self, addr: VirtAddr) -> Option<MemoryArea> {
+        let start_addr = self.find_area(addr)?.start_addr;
+        let i = self.position(start_addr).unwrap();
+        let area = self.remove_at(i);
+        // The heap can not be moved once cut
+        if self.is_heap_piece(&area) {
+            self.heap = None;
+        }
+        let mut freed = 0;
+        self.page_table.edit(|pt| freed = area.unmap::<T>(pt));
+        self.resident -= freed;
+        self.free_kept_slots(area.start_addr, area.end_addr);
+        Some(area)
+    }
+
+    /// Removes the area starting at `start_addr` without freeing its frames.
+    ///
+    /// The frames are owned by the returned `DetachedArea`, and can be mapped
+    /// into another memory set by `attach_area` without copying.
+    /// Pages keep their state: copy-on-write ones stay shared, and swapped out ones keep their slots.
+    /// Returns `None` if there is no such non-empty area.
+    pub fn take_area(&mut self, start_addr: VirtAddr) -> Option<DetachedArea<T>> {
+        let i = self.position(start_addr)?;
+        let area = self.remove_at(i);
+        // Not known to the memory set it is attached to
+        self.free_kept_slots(area.start_addr, area.end_addr);
+        let mut pages = Vec::new();
+        let mut zero_pages = 0;
+        self.page_table.edit(|pt| {
+            if area.kind == AreaKind::Reserved {
+                return;
+            }
+            // Pages are mapped by address, maybe with large pages
+            if area.phys_start_addr.is_some() {
                 area.unmap::<T>(pt);
+                return;
+            }
+            let range = Page::range_of(area.start_addr, area.end_addr);
+            pages.reserve_exact(range.len());
+            for page in range {
+                let addr = page.start_address();
+                let entry = pt.get_entry(addr);
+                if entry.hidden() {
+                    entry.set_hidden(false);
+                    entry.set_present(true);
+                }
+                let detached = match area.kind {
+                    // Not faulted yet. Mark it present to unmap.
+                    AreaKind::Handled(_) | AreaKind::File(_) if !entry.present() && !entry.swapped() => {
+                        entry.set_present(true);
+                        DetachedPage::None
+                    }
+                    // No frame, the slot is taken along
+                    _ if entry.swapped() => {
+                        let slot = swap_slot(entry).unwrap();
+                        entry.set_swapped(false);
+                        entry.set_present(true);
+                        DetachedPage::Swapped(slot)
+                    }
+                    // Faulted again when attached
+                    _ if entry.target() == T::zero_frame() => {
+                        zero_pages += 1;
+                        DetachedPage::None
+                    }
+                    _ if entry.readonly_shared() => DetachedPage::Frame(entry.target(), Some(false)),
+                    _ if entry.writable_shared() => DetachedPage::Frame(entry.target(), Some(true)),
+                    _ => DetachedPage::Frame(entry.target(), None),
+                };
+                pages.push(detached);
+                pt.unmap(addr);
             }
         });
-        areas.clear();
+        if area.kind != AreaKind::Shared {
+            self.resident -= pages.iter().filter(|page| page.frame().is_some()).count() + zero_pages;
+        }
+        Some(DetachedArea { area, pages, _page_table: PhantomData })
     }
-}
 
-impl<T: InactivePageTable> Clone for MemorySet<T> {
-    fn clone(&self) -> Self {
-        let mut page_table = T::new();
-        page_table.edit(|pt| {
-            for area in self.areas.iter() {
-                area.map::<T>(pt);
+    /// Adds an area removed from another memory set by `take_area`,
+    /// mapping its pages to the same frames, in the same state.
+    ///
+    /// Returns `detached` back without mapping anything if it overlaps with an area in the set.
+    pub fn attach_area(&mut self, mut detached: DetachedArea<T>) -> Result<(), DetachedArea<T>> {
+        let area = detached.area;
+        if self.is_overlap_with(&area) {
+            return Err(detached);
+        }
+        if area.phys_start_addr.is_some() {
+            // Nothing is allocated for physical areas, but page table pages
+            let mut mapped = Ok(0);
+            self.page_table.edit(|pt| mapped = area.map::<T>(pt));
+            if mapped.is_err() {
+                return Err(detached);
+            }
+            self.push_area(area);
+            return Ok(());
+        }
+        let pages = mem::replace(&mut detached.pages, Vec::new());
+        if area.kind != AreaKind::Shared {
+            self.resident += pages.iter().filter(|page| page.frame().is_some()).count();
+        }
+        self.page_table.edit(|pt| {
+            let range = Page::range_of(area.start_addr, area.end_addr);
+            for (page, detached) in range.zip(pages.into_iter()) {
+                let addr = page.start_address();
+                match detached {
+                    DetachedPage::Frame(frame, shared) => {
+                        let entry = pt.map(addr, frame);
+                        area.flags.apply(entry);
+                        // Still shared with other memory sets by copy-on-write
+                        if let Some(writable) = shared {
+                            entry.set_shared(writable);
+                            entry.set_writable(false);
+                            entry.update();
+                        }
+                    }
+                    DetachedPage::Swapped(slot) => set_swap_slot(pt.map(addr, 0), slot),
+                    DetachedPage::None => {
+                        let entry = pt.map(addr, 0);
+                        entry.set_present(false);
+                        entry.update();
+                    }
+                }
             }
         });
-        MemorySet {
-            areas: self.areas.clone(),
-            page_table,
-            kstack: T::alloc_stack(),
+        self.push_area(area);
+        Ok(())
+    }
+
+    /// Maps the frames of `shm` at `addr` with `flags`, like `shmat`.
+    ///
+    /// The area holds a reference to the frames, which is dropped when it is unmapped,
+    /// e.g. by `remove_area`. The frames are kept shared by `clone_cow`, not copied.
+    /// Returns `Err` if `addr` is not page aligned, the area would overlap with another one,
+    /// or `flags` are writable and executable while `set_enforce_wx` is on.
+    pub fn attach_shared(&mut self, shm: &SharedMemory<T>, addr: VirtAddr, flags: MemoryAttr) -> Result<(), ()> {
+        let end = addr.checked_add(shm.size()).ok_or(())?;
+        if addr % PAGE_SIZE != 0 || !self.is_range_free(addr, end) || (self.enforce_wx && flags.is_wx()) {
+            return Err(());
         }
+        let area = MemoryArea { start_addr: addr, end_addr: end, phys_start_addr: None, flags, name: "shared",
+                                kind: AreaKind::Shared, handler_offset: 0, large_pages: false, frames: None };
+        self.page_table.edit(|pt| {
+            for (page, &frame) in Page::range_of(addr, end).zip(shm.frames.iter()) {
+                T::frame_ref_inc(frame);
+                flags.apply(pt.map(page.start_address(), frame));
+            }
+        });
+        self.push_area(area);
+        Ok(())
     }
-}
 
-impl<T: InactivePageTable> Drop for MemorySet<T> {
-    fn drop(&mut self) {
-        self.clear();
+    /// Changes attributes of `[addr, addr + len)` to `flags`, like `mprotect`.
+    ///
+    /// `addr` must be page aligned, and `len` is rounded up to pages.
+    /// The range may span several adjacent areas. An area partially covered is split,
+    /// into up to three areas if the range is in the middle of it.
+    ///
+    /// Returns `Err` without changing anything if the range hits a gap or a reserved area,
+    /// splits a large page, or `flags` are writable and executable while `set_enforce_wx` is on.
+    pub fn mprotect(&mut self, addr: VirtAddr, len: usize, flags: MemoryAttr) -> Result<(), ()> {
+        if addr % PAGE_SIZE != 0 || (self.enforce_wx && flags.is_wx()) {
+            return Err(());
+        }
+        let end = match len.checked_add(PAGE_SIZE - 1).and_then(|len| addr.checked_add(len)) {
+            Some(end) => Page::of_addr(end).start_address(),
+            None => return Err(()),
+        };
+
+        // Start addresses of the covered areas
+        let mut starts = Vec::new();
+        let mut next = addr;
+        while next < end {
+            let area = match self.find_area(next) {
+                Some(area) if area.kind != AreaKind::Reserved => *area,
+                _ => return Err(()),
+            };
+            if self.splits_large_page(&area, addr) || self.splits_large_page(&area, end) {
+                return Err(());
+            }
+            starts.push(area.start_addr);
+            next = area.page_range().1;
+        }
+
+        let mut changed = Vec::with_capacity(starts.len());
+        for start in starts {
+            let i = self.position(start).unwrap();
+            let area = self.remove_at(i);
+            if area.start_addr < addr {
+                self.push_area(area.slice(area.start_addr, addr));
+            }
+            if area.end_addr > end {
+                self.push_area(area.slice(end, area.end_addr));
+            }
+            let mut area = area.slice(area.start_addr.max(addr), area.end_addr.min(end));
+            area.flags = flags;
+            self.push_area(area);
+            changed.push(area);
+        }
+
+        self.page_table.edit(|pt| {
+            for area in changed.iter() {
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let entry = pt.get_entry(page.start_address());
+                    entry.set_user(flags.user);
+                    entry.set_execute(flags.execute);
+                    // Copy-on-write pages stay read-only until copied
+                    if entry.readonly_shared() || entry.writable_shared() {
+                        entry.set_shared(!flags.readonly);
+                    } else {
+                        entry.set_writable(!flags.readonly);
+                    }
+                    // Swapped pages and pages not faulted yet have no frame to present
+                    if flags.hide && entry.present() {
+                        entry.set_present(false);
+                        entry.set_hidden(true);
+                    } else if !flags.hide && entry.hidden() {
+                        entry.set_hidden(false);
+                        entry.set_present(true);
+                    }
+                }
+            }
+            // The changed areas cover the range without gaps
+            pt.flush_range(addr, end);
+        });
+        Ok(())
     }
-}
 
-impl<T: InactivePageTable> Debug for MemorySet<T> {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        f.debug_list()
-            .entries(self.areas.iter())
-            .finish()
+    /// Unmaps pages in `[start, end)`, like `munmap`. Both must be page aligned.
+    ///
+    /// Frames of anonymous pages are freed, while those of physical areas are left alone.
+    /// An area partially covered is shrunk, or split in two if the range is strictly inside it.
+    /// Gaps in the range are skipped.
+    pub fn unmap_range(&mut self, start: VirtAddr, end: VirtAddr) {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0, "range is not page aligned");
+        assert!(start <= end, "invalid range");
+        // Start addresses of the overlapping areas
+        let starts: Vec<VirtAddr> = self.areas[..self.count_below(end)].iter().rev()
+            .filter(|area| area.start_addr != area.end_addr)
+            .take_while(|area| area.page_range().1 > start)
+            .map(|area| area.start_addr)
+            .collect();
+
+        for &area_start in starts.iter() {
+            let area = self.areas[self.position(area_start).unwrap()];
+            assert!(!self.splits_large_page(&area, start) && !self.splits_large_page(&area, end),
+                    "unmapping part of a large page");
+        }
+
+        let mut removed = Vec::with_capacity(starts.len());
+        for area_start in starts {
+            let i = self.position(area_start).unwrap();
+            let area = self.remove_at(i);
+            let (page_start, page_end) = area.page_range();
+            if page_start < start {
+                self.push_area(area.slice(area.start_addr, start));
+            }
+            if page_end > end {
+                self.push_area(area.slice(end, area.end_addr));
+            }
+            removed.push(area.slice(area.start_addr.max(start), area.end_addr.min(end)));
+            // The heap can not be moved once cut
+            if self.is_heap_piece(&area) {
+                self.heap = None;
+            }
+        }
+        let mut freed = 0;
+        self.page_table.edit(|pt| {
+            for area in removed.iter() {
+                freed += area.unmap::<T>(pt);
+            }
+        });
+        self.resident -= freed;
+        self.free_kept_slots(start, end);
     }
-}
 
-#[derive(Debug)]
-pub struct Stack {
-    pub top: usize,
-    pub bottom: usize,
-}
\ No newline at end of file
+    /// Joins contiguous areas which are the same but the range, e.g. pieces left by `mprotect`,
+    /// so that there are fewer areas to look up. See `MemoryArea::merge` for what can be joined.
+    ///
+    /// Only the bookkeeping changes, nothing is remapped. Pieces of the heap are only joined
+    /// with each other, so that `set_brk` never moves the end of another area.
+    pub fn coalesce(&mut self) {
+        // In place, as the areas are sorted
+        let mut len = 0;
+        for i in 0..self.areas.len() {
+            let area = self.areas[i];
+            let merged = match len {
+                0 => None,
+                _ if self.is_heap_piece(&self.areas[len - 1]) != self.is_heap_piece(&area) => None,
+                _ => self.areas[len - 1].merge(&area),
+            };
+            match merged {
+                Some(merged) => self.areas[len - 1] = merged,
+                None => {
+                    self.areas[len] = area;
+                    len += 1;
+                }
+            }
+        }
+        self.areas.truncate(len);
+    }
+
+    /// Adds `area` as the heap, whose end can be moved by `set_brk`.
+    ///
+    /// It is usually an empty lazy area just above the program.
+    pub fn push_heap(&mut self, area: MemoryArea) {
+        assert_eq!(area.start_addr % PAGE_SIZE, 0, "heap is not page aligned");
+        self.push(area);
+        self.heap = Some((area.start_addr, area.end_addr));
+    }
+
+    /// Maps `[start, end)` identically, e.g. all physical memory for the kernel.
+    ///
+    /// Large pages are used where aligned, and normal pages at the edges, see `MemoryArea::large_pages`.
+    /// A multi-GiB region then takes far fewer entries, and page tables, to map.
+    /// Like other physical areas, its frames are never freed, and it can only be unmapped as a whole,
+    /// or at the edges mapped with normal pages.
+    pub fn push_identity_huge(&mut self, start: VirtAddr, end: VirtAddr, flags: MemoryAttr, name: &'static str) {
+        self.push(MemoryArea::new_identity(start, end, flags, name).large_pages());
+    }
+
+    /// Returns the end of the heap, or `None` if there is no heap.
+    pub fn brk(&self) -> Option<VirtAddr> {
+        self.heap.map(|(_, end)| end)
+    }
+
+    /// If `area` is a piece of the heap, which may be split by `mprotect`.
+    fn is_heap_piece(&self, area: &MemoryArea) -> bool {
+        match self.heap {
+            // Another area may start right there
+            Some((start, end)) if start == end => area.start_addr == start && area.end_addr == start,
+            Some((start, end)) => area.start_addr >= start && area.start_addr < end,
+            None => false,
+        }
+    }
+
+    /// Moves the end of the heap to `new_end`, like `brk`.
+    ///
+    /// The heap may be split in pieces by `mprotect`. Pages are mapped like the topmost piece
+    /// when growing, e.g. lazily for a lazy heap, and unmapped when shrinking,
+    /// which frees frames of anonymous pages only. Pieces above `new_end` are removed.
+    /// Returns `Err` if there is no heap, `new_end` is below its start,
+    /// or the heap would overlap with another area, e.g. the user stack, or frames run out.
+    pub fn set_brk(&mut self, new_end: VirtAddr) -> Result<(), ()> {
+        let (start, end) = self.heap.ok_or(())?;
+        if new_end < start || new_end > user_space_end(T::LEVELS) {
+            return Err(());
+        }
+        let page_end = |addr: VirtAddr| Page::of_addr(addr + PAGE_SIZE - 1).start_address();
+        // The topmost piece, as the areas are sorted
+        let mut i = self.areas.iter().rposition(|area| self.is_heap_piece(area))
+            .expect("heap area not found");
+        if new_end > page_end(end) {
+            let grown = self.areas[i].slice(page_end(end), new_end);
+            if self.is_overlap_with(&grown) {
+                return Err(());
+            }
+            let mut allocated = Ok(0);
+            self.page_table.edit(|pt| allocated = grown.map::<T>(pt));
+            self.resident += allocated.map_err(|_| ())?;
+        } else if page_end(new_end) < page_end(end) {
+            let cut = page_end(new_end);
+            let shrunk: Vec<MemoryArea> = self.areas[..=i].iter().rev()
+                .take_while(|area| self.is_heap_piece(area) && area.end_addr > cut)
+                .map(|area| area.slice(area.start_addr.max(cut), area.end_addr))
+                .collect();
+            let mut freed = 0;
+            self.page_table.edit(|pt| {
+                for area in shrunk.iter() {
+                    freed += area.unmap::<T>(pt);
+                }
+            });
+            self.resident -= freed;
+            self.free_kept_slots(cut, end);
+        }
+        let mut top = self.remove_at(i);
+        // The first piece is kept even if empty, so that the heap can grow again
+        while top.start_addr > start && top.start_addr >= new_end {
+            i -= 1;
+            top = self.remove_at(i);
+        }
+        self.push_area(top.slice(top.start_addr, new_end));
+        self.heap = Some((start, new_end));
+        Ok(())
+    }
+
+    /// Sets the range `[start, end)` where `find_free_range` can place new areas.
+    ///
+    /// It should exclude the stack, and anything else the user program may grow into.
+    /// By default it is all the user half of address space except the first page,
+    /// and it can not go beyond that, see `paging::user_space_end`.
+    pub fn set_user_space(&mut self, start: VirtAddr, end: VirtAddr) {
+        assert!(start <= end && end <= user_space_end(T::LEVELS), "invalid user space");
+        self.user_space = (start, end);
+    }
+
+    /// Finds a free page aligned range of `len` bytes in the user space, for areas without a fixed address.
+    ///
+    /// The free address nearest to `hint` is chosen, the lower one if there is a tie.
+    /// Returns `None` if no gap fits.
+    ///
+    /// Only the areas between the nearest fitting gaps and `hint` are visited.
+    pub fn find_free_range(&self, len: usize, hint: VirtAddr) -> Option<VirtAddr> {
+        let (len, lo, hi) = self.free_space(len)?;
+        let hint = Page::of_addr(hint).start_address();
+
+        // Lowest free address >= hint
+        let above = self.free_above(len, hint.max(lo), hi);
+
+        // Highest free address <= hint
+        let below = {
+            let mut end = hint.saturating_add(len).min(hi);
+            let below_end = self.areas[..self.count_below(end)].iter().rev()
+                .filter(|area| area.start_addr != area.end_addr)
+                .map(|area| area.page_range());
+            for (start, area_end) in below_end {
+                if end < lo + len || area_end <= end - len {
+                    break;
+                }
+                end = start;
+            }
+            match end >= lo + len {
+                true => Some(end - len),
+                false => None,
+            }
+        };
+
+        match (below, above) {
+            (Some(below), Some(above)) if hint - below <= above - hint => Some(below),
+            (below, None) => below,
+            (_, above) => above,
+        }
+    }
+
+    /// Finds the lowest free page aligned range of `len` bytes at or above `hint` in the user space,
+    /// e.g. for `mmap` without a fixed address.
+    ///
+    /// Returns `None` if no gap fits above `hint`.
+    pub fn find_free_area(&self, hint: VirtAddr, len: usize) -> Option<VirtAddr> {
+        let (len, lo, hi) = self.free_space(len)?;
+        let hint = Page::of_addr(hint.checked_add(PAGE_SIZE - 1)?).start_address();
+        self.free_above(len, hint.max(lo), hi)
+    }
+
+    /// Returns `len` rounded up to pages, and the page aligned user space `[lo, hi)`
+    /// for finding free ranges, or `None` if nothing can be found.
+    fn free_space(&self, len: usize) -> Option<(usize, VirtAddr, VirtAddr)> {
+        let len = Page::of_addr(len.checked_add(PAGE_SIZE - 1)?).start_address();
+        let lo = Page::of_addr(self.user_space.0 + PAGE_SIZE - 1).start_address();
+        let hi = Page::of_addr(self.user_space.1).start_address();
+        if len == 0 || lo >= hi {
+            return None;
+        }
+        Some((len, lo, hi))
+    }
+
+    /// Lowest free address >= `addr` for `len` bytes below `hi`, all page aligned.
+    ///
+    /// Only the areas between `addr` and the gap found are visited.
+    fn free_above(&self, len: usize, addr: VirtAddr, hi: VirtAddr) -> Option<VirtAddr> {
+        let mut addr = addr;
+        if let Some(area) = self.find_area(addr) {
+            addr = area.page_range().1;
+        }
+        let above = self.areas[self.count_below(addr)..].iter()
+            .filter(|area| area.start_addr != area.end_addr)
+            .map(|area| area.page_range());
+        for (start, end) in above {
+            if start >= hi || start - addr >= len {
+                break;
+            }
+            addr = end;
+        }
+        match addr < hi && hi - addr >= len {
+            true => Some(addr),
+            false => None,
+        }
+    }
+
+    /// Resident set size in bytes: physical memory mapped by anonymous pages, like `VmRSS`.
+    ///
+    /// Pages not faulted in yet or swapped out are not counted, nor are physical areas and shared memory.
+    /// Frames shared by copy-on-write, including the zero frame, are counted in full
+    /// by every memory set sharing them, so the sum over processes may exceed the memory used.
+    /// It is tracked as pages are mapped and unmapped, so it is cheap to call.
+    pub fn rss(&self) -> usize {
+        self.resident * PAGE_SIZE
+    }
+
+    /// Sum of the page aligned spans of all areas in bytes, like `VmSize`. Reserved areas are not counted.
+    pub fn mapped_size(&self) -> usize {
+        self.areas.iter()
+            .filter(|area| area.kind != AreaKind::Reserved && area.start_addr != area.end_addr)
+            .map(|area| {
+                let (start, end) = area.page_range();
+                end - start
+            })
+            .sum()
+    }
+
+    /// Iterator implementation for for-loop.
+    pub fn iter(&self) -> impl Iterator<Item=&MemoryArea> {
+        self.areas.iter()
+    }
+
+    /// Mutable iterator over the areas.
+    ///
+    /// Like `find_area_mut`, the caller must remap changed pages, and must not change the ranges.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut MemoryArea> {
+        self.areas.iter_mut()
+    }
+
+    /// See `InactivePageTable.with`
+    pub unsafe fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.page_table.with(f)
+    }
+
+    /// Check whether `[addr, addr + len)` lies entirely in user areas,
+    /// which must also be writable if `write` is set, and make its pages accessible.
+    ///
+    /// Pages the user would fault on are faulted in by `handle_fault` with `swapper`,
+    /// e.g. not yet faulted pages of lazy or file areas, swapped pages, or copy-on-write pages for a write,
+    /// so that the kernel can access the range right after without faulting.
+    /// Must be called on the active memory set.
+    pub fn check_access(&mut self, addr: VirtAddr, len: usize, write: bool, mut swapper: Option<&mut Swapper>) -> bool {
+        if !self.check_areas(addr, len, write) {
+            return false;
+        }
+        for page in Page::range_of(addr, addr + len) {
+            let page = page.start_address();
+            if self.is_page_accessible(page, write) {
+                continue;
+            }
+            let result = self.handle_fault(page, write, swapper.as_mut().map(|swapper| &mut **swapper as &mut Swapper));
+            if !result.is_resolved() || !self.is_page_accessible(page, write) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check whether `[addr, addr + len)` lies entirely in user areas, writable if `write` is set.
+    ///
+    /// The page entries are not checked, see `check_access`.
+    fn check_areas(&self, addr: VirtAddr, len: usize, write: bool) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= user_space_end(T::LEVELS) => end,
+            _ => return false,
+        };
+        let mut addr = addr;
+        while addr < end {
+            match self.find_area(addr) {
+                Some(area) if area.kind != AreaKind::Reserved && area.flags.user && !area.flags.hide
+                    && !(write && area.flags.readonly) =>
+                    addr = area.end_addr,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// If the entry of `page` is present, and writable if `write` is set.
+    fn is_page_accessible(&mut self, page: VirtAddr, write: bool) -> bool {
+        let mut accessible = false;
+        self.page_table.edit(|pt| {
+            let entry = pt.get_entry(page);
+            accessible = entry.present() && (!write || entry.writable());
+        });
+        accessible
+    }
+
+    /// Copies `buf.len()` bytes from user address `addr` into `buf`.
+    ///
+    /// The range is validated by `check_access` before it is dereferenced,
+    /// so a kernel or unmapped pointer returns `Err` instead of faulting in the kernel.
+    /// Must be called on the active memory set, e.g. in a syscall of its process.
+    pub fn copy_from_user(&mut self, addr: VirtAddr, buf: &mut [u8], swapper: Option<&mut Swapper>) -> Result<(), ()> {
+        if !self.check_access(addr, buf.len(), false, swapper) {
+            return Err(());
+        }
+        use core::slice;
+        buf.copy_from_slice(unsafe { slice::from_raw_parts(addr as *const u8, buf.len()) });
+        Ok(())
+    }
+
+    /// Copies `data` to user address `addr`, which must be writable.
+    ///
+    /// See `copy_from_user`.
+    pub fn copy_to_user(&mut self, addr: VirtAddr, data: &[u8], swapper: Option<&mut Swapper>) -> Result<(), ()> {
+        if !self.check_access(addr, data.len(), true, swapper) {
+            return Err(());
+        }
+        use core::slice;
+        unsafe { slice::from_raw_parts_mut(addr as *mut u8, data.len()) }.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Returns the length of the NUL terminated string at user address `ptr`, excluding the NUL.
+    ///
+    /// The string is scanned page by page, each checked by `check_access` before it is read,
+    /// so it may start anywhere in a page and end in a later one.
+    /// Returns `None` if it runs into memory not readable by the user,
+    /// or the NUL is not within the first `max_len` bytes.
+    /// Must be called on the active memory set, see `copy_from_user`.
+    pub fn validate_str(&mut self, ptr: VirtAddr, max_len: usize, mut swapper: Option<&mut Swapper>) -> Option<usize> {
+        use core::slice;
+        let mut len = 0;
+        while len < max_len {
+            let addr = ptr.checked_add(len)?;
+            let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(max_len - len);
+            if !self.check_access(addr, chunk, false, swapper.as_mut().map(|swapper| &mut **swapper as &mut Swapper)) {
+                return None;
+            }
+            let bytes = unsafe { slice::from_raw_parts(addr as *const u8, chunk) };
+            if let Some(i) = bytes.iter().position(|&b| b == 0) {
+                return Some(len + i);
+            }
+            len += chunk;
+        }
+        None
+    }
+
+    /// Apply function f with a `UserAccessor`, faulting in pages with `swapper`.
+    ///
+    /// Unlike `with`, every access made through the accessor is checked by `check_access`,
+    /// so an invalid user pointer returns an error instead of faulting in the kernel.
+    /// Must be called on the active memory set, see `copy_from_user`.
+    pub fn access_user<'a, R>(&'a mut self, swapper: Option<&'a mut Swapper>, f: impl FnOnce(UserAccessor<'a, T>) -> R) -> R {
+        f(UserAccessor { memory_set: self, swapper })
+    }
+
+    /// Zeroes `[start, end)`, e.g. the bss of a program in freshly allocated frames.
+    ///
+    /// The range must lie entirely in mapped, writable areas.
+    pub fn zero_range(&self, start: VirtAddr, end: VirtAddr) {
+        assert!(start <= end, "invalid range");
+        let mut addr = start;
+        while addr < end {
+            match self.find_area(addr) {
+                Some(area) if area.kind == AreaKind::Mapped && !area.flags.hide && !area.flags.readonly =>
+                    addr = area.end_addr,
+                _ => panic!("zeroing unmapped memory: {:#x}", addr),
+            }
+        }
+        unsafe { self.page_table.zero(start, end); }
+    }
+
+    /// Resolves a page fault at `addr`, `write` if it is caused by writing.
+    ///
+    /// The page entry decides what the fault means, checked in order:
+    ///
+    /// 1. Not present and swapped out: swap it in from `swapper`.
+    /// 2. Not present in an area with a fault handler or a file: allocate and fill it.
+    ///    Reading a lazy one maps the zero frame instead.
+    /// 3. Writing a present, read-only page shared by copy-on-write, or the zero frame: copy it.
+    ///
+    /// A fault in a guard area grows the stack above it, or is `FaultResult::StackOverflow`.
+    /// A fault in any other hidden area is a protection violation, nothing is mapped for it.
+    /// Anything else is `FaultResult::Unhandled`, e.g. out of any area, or the swapper is `None`.
+    /// Resolved faults are counted, see `fault_counters`.
+    /// Must be called on the active memory set.
+    pub fn handle_fault(&mut self, addr: VirtAddr, write: bool, swapper: Option<&mut Swapper>) -> FaultResult {
+        let file = self.find_area(addr).map_or(false, |area| match area.kind {
+            AreaKind::File(_) => true,
+            _ => false,
+        });
+        let result = self.resolve_fault(addr, write, swapper);
+        let counters = &mut self.fault_counters;
+        match result {
+            FaultResult::SwappedIn => counters.major_faults += 1,
+            FaultResult::Allocated if file => counters.major_faults += 1,
+            FaultResult::Allocated | FaultResult::StackGrown => counters.minor_faults += 1,
+            FaultResult::Copied => counters.cow_faults += 1,
+            _ => {}
+        }
+        result
+    }
+
+    /// Returns the numbers of faults resolved by `handle_fault` since created or cleared.
+    pub fn fault_counters(&self) -> FaultCounters {
+        self.fault_counters
+    }
+
+    fn resolve_fault(&mut self, addr: VirtAddr, write: bool, swapper: Option<&mut Swapper>) -> FaultResult {
+        let area = match self.find_area(addr) {
+            Some(area) if area.is_guard() => {
+                let guard = *area;
+                return self.grow_stack(addr, &guard);
+            }
+            Some(area) if area.kind != AreaKind::Reserved && !area.is_hidden() => *area,
+            Some(_) => return FaultResult::Unhandled,
+            // Maybe a large stack frame skipping over the guard
+            None => match self.next_area(addr) {
+                Some(area) if area.is_guard() => {
+                    let guard = *area;
+                    return self.grow_stack(addr, &guard);
+                }
+                _ => return FaultResult::Unhandled,
+            },
+        };
+        let page = Page::of_addr(addr).start_address();
+        let mut state = (false, false, false, false);
+        self.page_table.edit(|pt| {
+            let entry = pt.get_entry(page);
+            state = (entry.present(), entry.swapped(), entry.writable(), entry.writable_shared());
+        });
+        match (state, area.kind) {
+            ((false, true, _, _), _) => match swapper {
+                Some(swapper) => match self.swap_in_page(page, swapper) {
+                    Ok(()) => FaultResult::SwappedIn,
+                    Err(SwapError::OutOfMemory) => FaultResult::OutOfMemory,
+                    Err(_) => FaultResult::Unhandled,
+                },
+                None => FaultResult::Unhandled,
+            },
+            ((false, _, _, _), AreaKind::Handled(_)) if !write && area.is_lazy() => self.map_zero(&area, page),
+            ((false, _, _, _), AreaKind::Handled(handler)) => {
+                let offset = page - Page::of_addr(area.start_addr).start_address() + area.handler_offset;
+                self.fault_in(&area, page, |data| (handler.0)(offset, data))
+            }
+            ((false, _, _, _), AreaKind::File(file)) => self.fault_in(&area, page, |data| file.read_page(page, data)),
+            ((true, _, false, true), _) if write => {
+                let mut result = FaultResult::Unhandled;
+                self.page_table.edit(|pt| {
+                    if copy_on_write::<T>(pt, page) == Some(true) {
+                        result = FaultResult::Copied;
+                    }
+                });
+                result
+            }
+            _ => FaultResult::Unhandled,
+        }
+    }
+
+    /// Resolves a fault at `addr` in `guard` or in the gap below it,
+    /// by growing the stack above the guard down to the page of `addr`, and moving the guard below it.
+    ///
+    /// The stack must be an anonymous area, mapped or lazy, starting right at the end of the guard.
+    /// Returns `FaultResult::StackOverflow` if the stack would be larger than `max_stack`,
+    /// or the range below the guard is taken, and `FaultResult::Unhandled` if there is no stack.
+    fn grow_stack(&mut self, addr: VirtAddr, guard: &MemoryArea) -> FaultResult {
+        let stack = match self.position(guard.end_addr).map(|i| self.areas[i]) {
+            Some(stack) if stack.is_anonymous() => stack,
+            _ => return FaultResult::Unhandled,
+        };
+        let new_start = Page::of_addr(addr).start_address();
+        let guard_size = guard.end_addr - guard.start_addr;
+        let guard_start = match new_start.checked_sub(guard_size) {
+            Some(start) if stack.end_addr - new_start <= self.max_stack => start,
+            _ if guard.contains(addr) => return FaultResult::StackOverflow,
+            // Too far below the stack to be an access of it
+            _ => return FaultResult::Unhandled,
+        };
+        if guard_start < guard.start_addr && !self.is_range_free(guard_start, guard.start_addr) {
+            return FaultResult::StackOverflow;
+        }
+        // Mapped first, so that nothing is changed if frames run out
+        let grown = stack.extend_down(new_start);
+        let mut mapped = Ok(0);
+        self.page_table.edit(|pt| mapped = grown.slice(new_start, stack.start_addr).map::<T>(pt));
+        match mapped {
+            Ok(allocated) => self.resident += allocated,
+            Err(MapError::OutOfMemory) => return FaultResult::OutOfMemory,
+        }
+        let i = self.position(guard.start_addr).unwrap();
+        self.remove_at(i);
+        let i = self.position(stack.start_addr).unwrap();
+        self.remove_at(i);
+        // Reserved guard maps nothing, so it is moved without touching the page table
+        self.push_area(MemoryArea { start_addr: guard_start, end_addr: new_start, ..*guard });
+        self.push_area(grown);
+        FaultResult::StackGrown
+    }
+
+    /// Returns the lowest non-empty area starting above `addr`.
+    fn next_area(&self, addr: VirtAddr) -> Option<&MemoryArea> {
+        self.areas[self.count_below(addr)..].iter().find(|area| area.start_addr != area.end_addr)
+    }
+
+    /// Sets the size stacks can grow down to, on faults in or below the guard area below them.
+    ///
+    /// It is 0 by default, so stacks never grow and a fault in a guard is a stack overflow.
+    pub fn set_max_stack(&mut self, size: usize) {
+        self.max_stack = size;
+    }
+
+    /// Maps a not present `page` of an area with a fault handler or a file, filled by `fill`.
+    fn fault_in(&mut self, area: &MemoryArea, page: VirtAddr, fill: impl FnOnce(&mut [u8]) -> Result<(), ()>) -> FaultResult {
+        let mut data = [0u8; PAGE_SIZE];
+        if fill(&mut data).is_err() {
+            return FaultResult::Refused;
+        }
+        let frame = match T::alloc_frame() {
+            Some(frame) => frame,
+            None => return FaultResult::OutOfMemory,
+        };
+        self.page_table.edit(|pt| {
+            let entry = pt.get_entry(page);
+            entry.set_target(frame);
+            entry.set_present(true);
+            entry.update();
+            pt.get_page_slice_mut(page).copy_from_slice(&data);
+            area.flags.apply(pt.get_entry(page));
+        });
+        self.resident += 1;
+        FaultResult::Allocated
+    }
+
+    /// Maps a not present `page` of a lazy area to the zero frame, shared as copy-on-write.
+    fn map_zero(&mut self, area: &MemoryArea, page: VirtAddr) -> FaultResult {
+        let zero = T::zero_frame();
+        self.page_table.edit(|pt| {
+            let entry = pt.get_entry(page);
+            entry.set_target(zero);
+            entry.set_present(true);
+            entry.update();
+            area.flags.apply(entry);
+            entry.set_shared(!area.flags.readonly);
+            entry.set_writable(false);
+            entry.update();
+        });
+        self.resident += 1;
+        FaultResult::Allocated
+    }
+
+    /// Handles a page fault at `addr` in a lazy area, returns whether a page is faulted in.
+    ///
+    /// Returns `false` if `addr` is out of any area, or the page can not be faulted in,
+    /// so that the caller can kill the process.
+    pub fn handle_page_fault(&mut self, addr: VirtAddr) -> bool {
+        self.handle_fault(addr, false, None) == FaultResult::Allocated
+    }
+
+    /// Writes the page of `addr` out to `swapper` and frees its frame.
+    ///
+    /// The page entry keeps the swap slot, see `swap::set_swap_slot`.
+    /// If the page is swapped in and not dirty since then, the slot still holds the same content,
+    /// so the write is skipped. If it is dirty, the slot is rewritten instead of allocating one.
+    /// Only private pages of anonymous areas can be swapped out, not physical or copy-on-write ones.
+    /// Must be called on the active memory set.
+    pub fn swap_out_page(&mut self, addr: VirtAddr, swapper: &mut Swapper) -> Result<(), SwapError> {
+        match self.find_area(addr) {
+            Some(area) if area.kind != AreaKind::Reserved && area.kind != AreaKind::Shared
+                && area.phys_start_addr.is_none() => {}
+            _ => return Err(SwapError::NotSwappable),
+        }
+        let page = Page::of_addr(addr).start_address();
+        let kept = self.swap_slots.get(&page).cloned();
+        let mut result = Ok(());
+        self.page_table.edit(|pt| {
+            result = (|| {
+                let dirty = {
+                    let entry = pt.get_entry(page);
+                    if entry.swapped() {
+                        return Err(SwapError::AlreadySwapped);
+                    }
+                    if !entry.present() || entry.readonly_shared() || entry.writable_shared() {
+                        return Err(SwapError::NotSwappable);
+                    }
+                    entry.dirty()
+                };
+                let token = match kept {
+                    Some(token) if !dirty => token,
+                    Some(token) => {
+                        swapper.swap_update(token, pt.get_page_slice_mut(page)).map_err(|_| SwapError::IOError)?;
+                        token
+                    }
+                    None => swapper.swap_out(pt.get_page_slice_mut(page)).map_err(|_| SwapError::IOError)?,
+                };
+                let entry = pt.get_entry(page);
+                let frame = entry.target();
+                set_swap_slot(entry, token);
+                T::dealloc_frame(frame);
+                Ok(())
+            })();
+        });
+        if result.is_ok() {
+            self.resident -= 1;
+            self.swap_slots.remove(&page);
+        }
+        result
+    }
+
+    /// Reads the swapped out page of `addr` back from `swapper` into a new frame.
+    ///
+    /// It should be called on page faults at swapped out pages.
+    /// The slot is kept with the content and the page is marked clean, see `swap_out_page`.
+    /// Must be called on the active memory set.
+    pub fn swap_in_page(&mut self, addr: VirtAddr, swapper: &mut Swapper) -> Result<(), SwapError> {
+        let hide = match self.find_area(addr) {
+            Some(area) => area.flags.hide,
+            None => return Err(SwapError::NotSwapped),
+        };
+        let page = Page::of_addr(addr).start_address();
+        let mut result = Err(SwapError::NotSwapped);
+        self.page_table.edit(|pt| {
+            result = (|| {
+                let token = {
+                    let entry = pt.get_entry(page);
+                    let token = swap_slot(entry).ok_or(SwapError::NotSwapped)?;
+                    entry.set_target(T::alloc_frame().ok_or(SwapError::OutOfMemory)?);
+                    entry.set_swapped(false);
+                    entry.set_present(true);
+                    entry.update();
+                    token
+                };
+                swapper.swap_read(token, pt.get_page_slice_mut(page)).map_err(|_| SwapError::IOError)?;
+                let entry = pt.get_entry(page);
+                entry.clear_dirty();
+                // Swapped out before the area is hidden by `mprotect`
+                if hide {
+                    entry.set_present(false);
+                    entry.set_hidden(true);
+                }
+                entry.update();
+                Ok(token)
+            })();
+        });
+        match result {
+            Err(SwapError::NotSwapped) | Err(SwapError::OutOfMemory) => {}
+            // A frame is allocated even if it fails to be read
+            _ => self.resident += 1,
+        }
+        let token = result?;
+        self.swap_slots.insert(page, token);
+        Ok(())
+    }
+
+    /// Selects a page to swap out by the clock (second chance) algorithm, see `swap_out_page`.
+    ///
+    /// Scans resident private pages of anonymous areas in address order from where the last scan
+    /// stopped, wrapping around at the end. A page with its accessed bit set gets a second chance:
+    /// the bit is cleared and the page skipped. The first page not accessed is returned,
+    /// after at most two rounds. Returns `None` if there is no page to swap out.
+    ///
+    /// TLB entries are not flushed when clearing the bits, so a page may look idle a bit too early.
+    pub fn select_victim_page(&mut self) -> Option<VirtAddr> {
+        let ranges: Vec<(VirtAddr, VirtAddr)> = self.areas.iter()
+            .filter(|area| area.kind != AreaKind::Reserved && area.kind != AreaKind::Shared
+                && area.phys_start_addr.is_none() && area.start_addr != area.end_addr)
+            .map(|area| area.page_range())
+            .collect();
+        let hand = self.clock_hand;
+        // Pages from the hand to the end, then from the start back to the hand
+        let pages = || ranges.iter()
+            .flat_map(|&(start, end)| (start..end).step_by(PAGE_SIZE))
+            .filter(move |&addr| addr >= hand)
+            .chain(ranges.iter()
+                .flat_map(|&(start, end)| (start..end).step_by(PAGE_SIZE))
+                .filter(move |&addr| addr < hand));
+        let mut victim = None;
+        self.page_table.edit(|pt| {
+            for addr in pages().chain(pages()) {
+                let entry = pt.get_entry(addr);
+                if !entry.present() || entry.readonly_shared() || entry.writable_shared() {
+                    continue;
+                }
+                if !entry.accessed() {
+                    victim = Some(addr);
+                    return;
+                }
+                entry.clear_accessed();
+                entry.update();
+            }
+        });
+        if let Some(addr) = victim {
+            self.clock_hand = addr + PAGE_SIZE;
+        }
+        victim
+    }
+
+    /// Clears the accessed bit of every present page, the aging step of working set estimation.
+    ///
+    /// Like `select_victim_page`, the TLB is only flushed on the current CPU.
+    pub fn clear_access_bits(&mut self) {
+        self.clear_bits(false);
+    }
+
+    /// Clears the dirty bit of every present page.
+    ///
+    /// Pages swapped in with their swap slot kept are left dirty if written,
+    /// as the bit tells `swap_out_page` whether the slot must be written again.
+    pub fn clear_dirty_bits(&mut self) {
+        self.clear_bits(true);
+    }
+
+    fn clear_bits(&mut self, dirty: bool) {
+        let ranges: Vec<(VirtAddr, VirtAddr)> = self.areas.iter()
+            .filter(|area| area.kind != AreaKind::Reserved && area.start_addr != area.end_addr)
+            .map(|area| area.page_range())
+            .collect();
+        let Self { ref mut page_table, ref swap_slots, .. } = self;
+        page_table.edit(|pt| {
+            for addr in ranges.iter().flat_map(|&(start, end)| (start..end).step_by(PAGE_SIZE)) {
+                let entry = pt.get_entry(addr);
+                // Not present, swapped out, or hidden
+                if !entry.present() {
+                    continue;
+                }
+                match dirty {
+                    true if swap_slots.contains_key(&addr) => continue,
+                    true => entry.clear_dirty(),
+                    false => entry.clear_accessed(),
+                }
+                entry.update();
+            }
+        });
+    }
+
+    /// Registers `[start, start + size)` as the alternate signal stack.
+    ///
+    /// The region must lie in user writable areas.
+    /// Its pages are faulted in when a signal is delivered on it, see `copy_to_user`.
+    pub fn set_alt_stack(&mut self, start: VirtAddr, size: usize) -> Result<(), ()> {
+        if size == 0 || !self.check_areas(start, size, true) {
+            return Err(());
+        }
+        self.alt_stack = Some((start, start + size));
+        Ok(())
+    }
+
+    /// Unregisters the alternate signal stack.
+    pub fn clear_alt_stack(&mut self) {
+        self.alt_stack = None;
+    }
+
+    /// Returns the alternate signal stack `[start, end)` if registered.
+    pub fn alt_stack(&self) -> Option<(VirtAddr, VirtAddr)> {
+        self.alt_stack
+    }
+
+    /// Returns the user stack pointer to deliver a signal on, given the interrupted one `sp`.
+    ///
+    /// That is the top of the alternate signal stack if registered and not already in use,
+    /// otherwise `sp` itself.
+    pub fn signal_stack_top(&self, sp: VirtAddr) -> VirtAddr {
+        match self.alt_stack {
+            Some((start, end)) if !(start < sp && sp <= end) => end,
+            _ => sp,
+        }
+    }
+
+    /// Writes an ELF core image of the memory set to `out`, with a segment for each area.
+    ///
+    /// Contents are read with the page table activated by `with`.
+    /// Hidden and reserved areas are not present, so only their ranges are recorded.
+    /// So are areas with a fault handler, whose pages may not be present either.
+    /// An area with pages swapped out is split into segments around them, recorded without contents,
+    /// so that nothing is read from a page not present.
+    pub fn write_coredump(&mut self, out: &mut impl coredump::Write) -> Result<(), ()> {
+        use core::slice;
+        // (area, start, end, with contents) of every segment
+        let mut runs = Vec::new();
+        {
+            let Self { ref mut page_table, ref areas, .. } = self;
+            page_table.edit(|pt| {
+                for area in areas.iter() {
+                    let present = !area.flags.hide && area.kind != AreaKind::Reserved;
+                    if !present || area.kind != AreaKind::Mapped {
+                        runs.push((*area, area.start_addr, area.end_addr, false));
+                        continue;
+                    }
+                    let mut run = (area.start_addr, true);
+                    for page in Page::range_of(area.start_addr, area.end_addr) {
+                        let addr = page.start_address().max(area.start_addr);
+                        let present = pt.get_entry(page.start_address()).present();
+                        if present != run.1 {
+                            if addr > run.0 {
+                                runs.push((*area, run.0, addr, run.1));
+                            }
+                            run = (addr, present);
+                        }
+                    }
+                    runs.push((*area, run.0, area.end_addr, run.1));
+                }
+            });
+        }
+        let segments: Vec<coredump::Segment> = runs.into_iter().map(|(area, start, end, data)| {
+            let present = !area.flags.hide && area.kind != AreaKind::Reserved;
+            coredump::Segment {
+                start,
+                end,
+                readable: present,
+                writable: present && !area.flags.readonly,
+                executable: present && area.flags.execute,
+                data: match data {
+                    true => Some(unsafe { slice::from_raw_parts(start as *const u8, end - start) }),
+                    false => None,
+                },
+            }
+        }).collect();
+        unsafe { self.page_table.with(|| coredump::write_core(out, &segments)) }
+    }
+
+    /// Captures the areas and the contents of present pages, to be put back by `restore`.
+    ///
+    /// Physical areas are recorded without contents, and shared memory is not recorded at all.
+    /// Pages not present are not captured: hidden and swapped ones lose their contents on restore,
+    /// while lazy pages read as zero again. Contents are read through the page table,
+    /// like swapping, so the memory set must be the active one.
+    pub fn snapshot(&mut self) -> MemorySnapshot {
+        let areas: Vec<MemoryArea> = self.areas.iter()
+            .filter(|area| area.kind != AreaKind::Shared)
+            .cloned().collect();
+        let mut pages = Vec::new();
+        self.page_table.edit(|pt| {
+            let backed = |area: &&MemoryArea| area.phys_start_addr.is_none() && area.kind != AreaKind::Reserved;
+            for area in areas.iter().filter(backed) {
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    let entry = pt.get_entry(addr);
+                    if !entry.present() || entry.target() == T::zero_frame() {
+                        continue;
+                    }
+                    pages.push((addr, pt.get_page_slice_mut(addr).to_vec()));
+                }
+            }
+        });
+        MemorySnapshot { areas, heap: self.heap, pages }
+    }
+
+    /// Replaces all areas with the ones in `snapshot`, and copies the captured pages back.
+    ///
+    /// Captured pages get frames of their own, even if they were shared by copy-on-write.
+    /// Returns `Err` if an area can not be pushed, then the set is left partly restored.
+    pub fn restore(&mut self, snapshot: &MemorySnapshot) -> Result<(), PushError> {
+        self.clear();
+        for area in snapshot.areas.iter() {
+            self.try_push(*area)?;
+        }
+        self.heap = snapshot.heap;
+        for &(addr, ref data) in snapshot.pages.iter() {
+            let area = *self.find_area(addr).unwrap();
+            let mut present = false;
+            self.page_table.edit(|pt| present = pt.get_entry(addr).present());
+            if !present {
+                self.fault_in(&area, addr, |page| Ok(page.copy_from_slice(data)));
+                continue;
+            }
+            self.page_table.edit(|pt| {
+                // Write even if read-only, then restore the attributes
+                let entry = pt.get_entry(addr);
+                entry.set_writable(true);
+                entry.update();
+                pt.get_page_slice_mut(addr).copy_from_slice(data);
+                area.flags.apply(pt.get_entry(addr));
+            });
+        }
+        Ok(())
+    }
+
+    /// See `InactivePageTable.activate`
+    pub unsafe fn activate(&self) {
+        self.page_table.activate();
+    }
+
+    /// See `InactivePageTable.token`
+    pub fn token(&self) -> usize {
+        self.page_table.token()
+    }
+
+    /// Returns address of kernel stack top, allocating the stack if there is none yet.
+    ///
+    /// Returns `None` if it can not be allocated, see `ensure_kstack`.
+    pub fn kstack_top(&mut self) -> Option<usize> {
+        self.ensure_kstack().map(|stack| stack.top)
+    }
+
+    /// Returns the kernel stack, allocated by `InactivePageTable::alloc_stack` on the first call.
+    ///
+    /// Returns `None` if it can not be allocated, and tries again on the next call.
+    pub fn ensure_kstack(&mut self) -> Option<&Stack> {
+        if self.kstack.is_none() {
+            self.kstack = T::alloc_stack();
+        }
+        self.kstack.as_ref()
+    }
+
+    /// Takes the kernel stack away, so that it is not freed when the memory set is dropped,
+    /// e.g. for a thread to keep running on it with another memory set.
+    pub fn take_kstack(&mut self) -> Option<Stack> {
+        self.kstack.take()
+    }
+
+    /// Clones the memory set, sharing frames with it by copy-on-write instead of copying data.
+    ///
+    /// Pages of both sets are mapped read-only to the same frames, marked as shared.
+    /// A frame is copied only when written from either side, see `copy_on_write`.
+    /// Physical areas are not copy-on-write, their frames are shared permanently.
+    /// Pages swapped out are swapped in from `swapper` first, so that there is a frame to share.
+    /// Returns `Err` if one can not be swapped in, e.g. the swapper is `None`,
+    /// then the pages swapped in so far stay resident and nothing is shared.
+    /// Must be called on the active memory set, see `swap_in_page`.
+    ///
+    /// This is the only way to clone a memory set. It is not `Clone`,
+    /// as mapping new frames would lose the data, and sharing them changes `self` too.
+    pub fn clone_cow(&mut self, swapper: Option<&mut Swapper>) -> Result<Self, SwapError> {
+        let swapped = self.swapped_pages();
+        if !swapped.is_empty() {
+            let swapper = swapper.ok_or(SwapError::NoSwapper)?;
+            for addr in swapped {
+                self.swap_in_page(addr, swapper)?;
+            }
+        }
+        // (address, frame, writable) of every page to share
+        let mut shared = Vec::new();
+        // Frames of shared memory, in the order of pages
+        let mut attached = Vec::new();
+        let Self { ref mut page_table, ref areas, .. } = self;
+        page_table.edit(|pt| {
+            for area in areas.iter() {
+                match area.kind {
+                    AreaKind::Reserved => continue,
+                    AreaKind::Shared => {
+                        for page in Page::range_of(area.start_addr, area.end_addr) {
+                            let target = pt.get_entry(page.start_address()).target();
+                            T::frame_ref_inc(target);
+                            attached.push(target);
+                        }
+                        continue;
+                    }
+                    _ if area.phys_start_addr.is_some() => continue,
+                    _ => {}
+                }
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    let entry = pt.get_entry(addr);
+                    match area.kind {
+                        AreaKind::Handled(_) | AreaKind::File(_) if !entry.present() && !entry.hidden() => continue,
+                        _ => {}
+                    }
+                    let writable = !area.flags.readonly;
+                    if !entry.readonly_shared() && !entry.writable_shared() {
+                        entry.set_shared(writable);
+                        entry.set_writable(false);
+                        entry.update();
+                    }
+                    if entry.target() != T::zero_frame() {
+                        T::frame_ref_inc(entry.target());
+                    }
+                    shared.push((addr, entry.target(), writable));
+                }
+            }
+        });
+
+        let mut page_table = T::new();
+        page_table.edit(|pt| {
+            let mut shared = shared.iter().peekable();
+            let mut attached = attached.iter();
+            for area in self.areas.iter() {
+                if area.kind == AreaKind::Reserved {
+                    continue;
+                }
+                if area.phys_start_addr.is_some() {
+                    area.map_physical::<T>(pt);
+                    continue;
+                }
+                if area.kind == AreaKind::Shared {
+                    for page in Page::range_of(area.start_addr, area.end_addr) {
+                        let entry = pt.map(page.start_address(), *attached.next().unwrap());
+                        area.flags.apply(entry);
+                    }
+                    continue;
+                }
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    match shared.peek() {
+                        Some(&&(shared_addr, target, writable)) if shared_addr == addr => {
+                            shared.next();
+                            let entry = pt.map(addr, target);
+                            area.flags.apply(entry);
+                            entry.set_shared(writable);
+                            entry.set_writable(false);
+                            entry.update();
+                        }
+                        // Not faulted yet
+                        _ => {
+                            let entry = pt.map(addr, 0);
+                            entry.set_present(false);
+                            entry.update();
+                        }
+                    }
+                }
+            }
+        });
+        Ok(MemorySet {
+            areas: self.areas.clone(),
+            page_table,
+            kstack: None,
+            alt_stack: self.alt_stack,
+            user_space: self.user_space,
+            heap: self.heap,
+            resident: shared.len(),
+            max_stack: self.max_stack,
+            clock_hand: 0,
+            swap_slots: BTreeMap::new(),
+            fault_counters: FaultCounters::default(),
+            enforce_wx: self.enforce_wx,
+        })
+    }
+
+    /// Returns the swapped out pages of all areas, in ascending order.
+    fn swapped_pages(&mut self) -> Vec<VirtAddr> {
+        let areas = &self.areas;
+        let mut swapped = Vec::new();
+        self.page_table.edit(|pt| {
+            for area in areas.iter().filter(|area| area.kind != AreaKind::Reserved && area.kind != AreaKind::Shared
+                && area.phys_start_addr.is_none()) {
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    if pt.get_entry(page.start_address()).swapped() {
+                        swapped.push(page.start_address());
+                    }
+                }
+            }
+        });
+        swapped
+    }
+
+    /// Checks that the page table matches the areas, returns the first mismatch.
+    ///
+    /// Known divergences are accepted: copy-on-write pages are read-only,
+    /// swapped pages and not yet faulted pages of areas with a fault handler are not present.
+    /// Reserved areas are not checked, as nothing is mapped.
+    #[cfg(any(test, debug_assertions))]
+    pub fn verify(&mut self) -> Result<(), VerifyError> {
+        let areas = &self.areas;
+        let mut result = Ok(());
+        let mut resident = 0;
+        self.page_table.edit(|pt| {
+            result = areas.iter().filter(|area| area.kind != AreaKind::Reserved).try_for_each(|area| {
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    // Start of the page the entry maps
+                    let entry_addr = match pt.is_large(addr) {
+                        true => addr & !(<T::Active as PageTable>::LARGE_PAGE_SIZE - 1),
+                        false => addr,
+                    };
+                    let entry = pt.get_entry(addr);
+                    // Hidden pages are not present, but keep their frames
+                    if area.phys_start_addr.is_none() && area.kind != AreaKind::Shared
+                        && (entry.present() || entry.hidden()) {
+                        resident += 1;
+                    }
+                    let present = !area.flags.hide;
+                    match (entry.present(), present) {
+                        (true, true) => {}
+                        (false, false) => continue,
+                        (true, false) => return Err(VerifyError::Present(addr)),
+                        (false, true) if entry.swapped() => continue,
+                        (false, true) => match area.kind {
+                            AreaKind::Handled(_) | AreaKind::File(_) => continue,
+                            _ => return Err(VerifyError::NotPresent(addr)),
+                        },
+                    }
+                    let shared = entry.readonly_shared() || entry.writable_shared();
+                    let writable = !area.flags.readonly && !shared;
+                    if entry.user() != area.flags.user || entry.writable() != writable
+                        || entry.execute() != area.flags.execute || entry.global() != area.flags.global {
+                        return Err(VerifyError::Permission(addr));
+                    }
+                    if let Some(target) = area.phys_addr_of(entry_addr) {
+                        if entry.target() != target {
+                            return Err(VerifyError::Target(addr));
+                        }
+                    }
+                }
+                Ok(())
+            });
+        });
+        if result.is_ok() && resident != self.resident {
+            result = Err(VerifyError::Resident(resident));
+        }
+        result
+    }
+
+    /// Returns every virtual page mapping the frame containing `phys`, in ascending order.
+    ///
+    /// For chasing frame ownership bugs, like double frees or unexpected aliasing.
+    /// Only this page table is searched, so a frame shared by copy-on-write is found in each set separately.
+    /// Pages not present are skipped, including swapped and hidden ones.
+    /// An entry is read for every page of every area, so the cost is linear in the mapped size.
+    pub fn find_by_phys(&mut self, phys: PhysAddr) -> Vec<VirtAddr> {
+        let frame = phys & !(PAGE_SIZE - 1);
+        let areas = &self.areas;
+        let mut found = Vec::new();
+        self.page_table.edit(|pt| {
+            for area in areas.iter().filter(|area| area.kind != AreaKind::Reserved) {
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    // Offset in the large page the entry maps
+                    let offset = match pt.is_large(addr) {
+                        true => addr & (<T::Active as PageTable>::LARGE_PAGE_SIZE - 1),
+                        false => 0,
+                    };
+                    let entry = pt.get_entry(addr);
+                    if entry.present() && !entry.swapped() && entry.target() + offset == frame {
+                        found.push(addr);
+                    }
+                }
+            }
+        });
+        found
+    }
+
+    /// Writes the areas and the state of their page entries to `out`, for debugging.
+    ///
+    /// Each area is a line of its range, permissions (`rwxu`, `-` if not set,
+    /// no `r` if hidden), kind and name, followed by a line for each page:
+    ///
+    /// ```text
+    /// 0x1000 -> 0x8000 present writable user dirty
+    /// 0x2000 -> 0x9000 present shared-writable
+    /// 0x3000 swapped
+    /// 0x4000 not present
+    /// ```
+    ///
+    /// `shared` and `shared-writable` are copy-on-write pages, read-only or writable before.
+    /// Pages of reserved areas are not listed, as nothing is mapped.
+    pub fn dump_layout(&mut self, out: &mut impl fmt::Write) -> fmt::Result {
+        let areas = &self.areas;
+        let mut result = Ok(());
+        self.page_table.edit(|pt| {
+            result = areas.iter().try_for_each(|area| {
+                let flags = &area.flags;
+                let kind = match area.kind {
+                    AreaKind::Mapped if area.phys_start_addr.is_some() => "physical",
+                    AreaKind::Mapped => "mapped",
+                    AreaKind::Reserved => "reserved",
+                    AreaKind::Handled(_) => "handled",
+                    AreaKind::File(_) => "file",
+                    AreaKind::Shared => "shared",
+                };
+                writeln!(out, "{:#x}..{:#x} {}{}{}{} {} {:?}", area.start_addr, area.end_addr,
+                         if flags.hide { '-' } else { 'r' }, if flags.readonly { '-' } else { 'w' },
+                         if flags.execute { 'x' } else { '-' }, if flags.user { 'u' } else { '-' },
+                         kind, area.name)?;
+                if area.kind == AreaKind::Reserved {
+                    return Ok(());
+                }
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    let entry_addr = match pt.is_large(addr) {
+                        true => addr & !(<T::Active as PageTable>::LARGE_PAGE_SIZE - 1),
+                        false => addr,
+                    };
+                    let entry = pt.get_entry(addr);
+                    if entry.swapped() {
+                        writeln!(out, "    {:#x} swapped", addr)?;
+                        continue;
+                    }
+                    // Hidden pages are not present, but keep their frames
+                    if !entry.present() && !entry.hidden() {
+                        writeln!(out, "    {:#x} not present", addr)?;
+                        continue;
+                    }
+                    write!(out, "    {:#x} -> {:#x}", addr, entry.target() + addr - entry_addr)?;
+                    let states = [
+                        (entry.present(), "present"),
+                        (entry.writable(), "writable"),
+                        (entry.user(), "user"),
+                        (entry.dirty(), "dirty"),
+                        (entry.readonly_shared(), "shared"),
+                        (entry.writable_shared(), "shared-writable"),
+                    ];
+                    for &(_, state) in states.iter().filter(|&&(set, _)| set) {
+                        write!(out, " {}", state)?;
+                    }
+                    writeln!(out)?;
+                }
+                Ok(())
+            });
+        });
+        result
+    }
+
+    /// Unmaps all area, release all memories occupied.
+    pub fn clear(&mut self) {
+        let Self { ref mut page_table, ref areas, .. } = self;
+        page_table.edit(|pt| {
+            for area in areas.iter() {
+                area.unmap::<T>(pt);
+            }
+        });
+        self.areas.clear();
+        self.alt_stack = None;
+        self.heap = None;
+        self.resident = 0;
+        self.clock_hand = 0;
+        for (_, &token) in self.swap_slots.iter() {
+            T::swap_free(token);
+        }
+        self.swap_slots.clear();
+        self.fault_counters = FaultCounters::default();
+    }
+}
+
+/// Panics for `MemorySet::push` and `MemorySet::push_all`.
+fn push_failed(err: PushError) -> ! {
+    match err {
+        PushError::Overlap(_) => panic!("memory area overlap"),
+        PushError::OutOfMemory(_) => panic!("failed to allocate frame"),
+        PushError::WriteExecute(_) => panic!("memory area is writable and executable"),
+    }
+}
+
+/// Resolves a write fault at `addr` on a copy-on-write page in active page table `pt`.
+///
+/// Copies the page to a new frame if the frame is still shared by others or is the zero frame,
+/// or makes it writable in place if this is the last reference.
+/// Returns `None` if the page is not copy-on-write,
+/// otherwise whether the fault is resolved, `false` if the page is read-only or frames run out.
+pub fn copy_on_write<T: InactivePageTable>(pt: &mut T::Active, addr: VirtAddr) -> Option<bool> {
+    let page = Page::of_addr(addr).start_address();
+    let target = {
+        let entry = pt.get_entry(page);
+        if !entry.present() {
+            return None;
+        }
+        if entry.readonly_shared() {
+            return Some(false);
+        }
+        if !entry.writable_shared() {
+            return None;
+        }
+        if T::frame_ref_count(entry.target()) == 1 && entry.target() != T::zero_frame() {
+            entry.clear_shared();
+            entry.set_writable(true);
+            entry.update();
+            return Some(true);
+        }
+        entry.target()
+    };
+    let frame = match T::alloc_frame() {
+        Some(frame) => frame,
+        None => return Some(false),
+    };
+    let mut data = [0u8; PAGE_SIZE];
+    data.copy_from_slice(pt.get_page_slice_mut(page));
+    if target != T::zero_frame() {
+        T::frame_ref_dec(target);
+    }
+    {
+        let entry = pt.get_entry(page);
+        entry.set_target(frame);
+        entry.clear_shared();
+        entry.set_writable(true);
+        entry.update();
+    }
+    pt.get_page_slice_mut(page).copy_from_slice(&data);
+    Some(true)
+}
+
+impl<T: InactivePageTable> Drop for MemorySet<T> {
+    fn drop(&mut self) {
+        self.clear();
+        if let Some(kstack) = self.kstack.take() {
+            T::dealloc_stack(kstack);
+        }
+    }
+}
+
+impl<T: InactivePageTable> Debug for MemorySet<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.debug_list()
+            .entries(self.areas.iter())
+            .finish()
+    }
+}
+
+/// Checked access to the user space of an active `MemorySet`.
+///
+/// Only available inside `MemorySet::access_user`.
+pub struct UserAccessor<'a, T: InactivePageTable + 'a> {
+    memory_set: &'a mut MemorySet<T>,
+    swapper: Option<&'a mut Swapper>,
+}
+
+impl<'a, T: InactivePageTable> UserAccessor<'a, T> {
+    /// Read `buf.len()` bytes from user address `addr`.
+    pub fn read(&mut self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), ()> {
+        let swapper = self.swapper.as_mut().map(|swapper| &mut **swapper as &mut Swapper);
+        self.memory_set.copy_from_user(addr, buf, swapper)
+    }
+
+    /// Write `data` to user address `addr`.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), ()> {
+        let swapper = self.swapper.as_mut().map(|swapper| &mut **swapper as &mut Swapper);
+        self.memory_set.copy_to_user(addr, data, swapper)
+    }
+}
+
+/// Alignment of the stack pointer required by the ABI of the target.
+///
+/// * x86_64 (System V): `rsp` is 16 byte aligned at function calls and process entry.
+/// * RISC-V: `sp` is always 16 byte aligned.
+pub const STACK_ALIGN: usize = 16;
+
+/// A stack, growing down from `top` to `bottom`.
+///
+/// `top` is aligned to `STACK_ALIGN`.
+#[derive(Debug)]
+pub struct Stack {
+    pub top: usize,
+    pub bottom: usize,
+}
+
+impl Stack {
+    pub fn new(bottom: usize, top: usize) -> Self {
+        assert_eq!(top % STACK_ALIGN, 0, "stack top is not aligned");
+        assert!(bottom <= top);
+        Stack { top, bottom }
+    }
+
+    /// Initial stack pointer leaving `extra` bytes above it, aligned to `STACK_ALIGN`.
+    ///
+    /// The `extra` bytes can be used to pass data, e.g. argc and argv, to the stack owner.
+    pub fn aligned_top(&self, extra: usize) -> usize {
+        let sp = (self.top - extra) & !(STACK_ALIGN - 1);
+        assert!(sp >= self.bottom, "stack overflow");
+        sp
+    }
+}
+
+/// An area removed from a memory set by `MemorySet::take_area`, with the frames of its pages.
+///
+/// Frames and swap slots not handed to `MemorySet::attach_area` are freed on drop.
+pub struct DetachedArea<T: InactivePageTable> {
+    area: MemoryArea,
+    /// State of every page. Empty for reserved and physical areas.
+    pages: Vec<DetachedPage>,
+    _page_table: PhantomData<T>,
+}
+
+/// A page of a `DetachedArea`.
+enum DetachedPage {
+    /// No frame, not faulted in yet or mapped to the zero frame.
+    None,
+    /// A frame, with `Some(writable)` if it is shared by copy-on-write, see `MemorySet::clone_cow`.
+    Frame(PhysAddr, Option<bool>),
+    /// Swapped out to the slot, see `swap::set_swap_slot`.
+    Swapped(usize),
+}
+
+impl DetachedPage {
+    fn frame(&self) -> Option<PhysAddr> {
+        match *self {
+            DetachedPage::Frame(frame, _) => Some(frame),
+            _ => None,
+        }
+    }
+}
+
+impl<T: InactivePageTable> DetachedArea<T> {
+    pub fn area(&self) -> &MemoryArea {
+        &self.area
+    }
+}
+
+impl<T: InactivePageTable> Drop for DetachedArea<T> {
+    fn drop(&mut self) {
+        if self.area.phys_start_addr.is_some() {
+            return;
+        }
+        for page in self.pages.iter() {
+            match *page {
+                // Maybe shared by copy-on-write or shared memory, freed by the last one
+                DetachedPage::Frame(frame, _) => if T::frame_ref_dec(frame) == 0 {
+                    T::dealloc_frame(frame);
+                },
+                DetachedPage::Swapped(slot) => T::swap_free(slot),
+                DetachedPage::None => {}
+            }
+        }
+    }
+}
+
+/// Frames which several memory sets can map by `MemorySet::attach_shared`,
+/// like a System V shared memory segment.
+///
+/// Every handle and every attachment holds a reference to the frames, see `InactivePageTable::frame_ref_inc`,
+/// so they are freed when the last handle is dropped and the last attachment is unmapped.
+pub struct SharedMemory<T: InactivePageTable> {
+    frames: Vec<PhysAddr>,
+    _page_table: PhantomData<T>,
+}
+
+impl<T: InactivePageTable> SharedMemory<T> {
+    /// Allocates frames for `size` bytes, rounded up to pages.
+    ///
+    /// Like those of anonymous areas, the frames are not zeroed.
+    /// Returns `None` if frames run out, then those allocated so far are freed.
+    pub fn new(size: usize) -> Option<Self> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut shm = SharedMemory { frames: Vec::with_capacity(pages), _page_table: PhantomData };
+        for _ in 0..pages {
+            shm.frames.push(T::alloc_frame()?);
+        }
+        Some(shm)
+    }
+
+    /// Size in bytes, a multiple of pages.
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+}
+
+impl<T: InactivePageTable> Clone for SharedMemory<T> {
+    /// Another handle to the same frames.
+    fn clone(&self) -> Self {
+        for &frame in self.frames.iter() {
+            T::frame_ref_inc(frame);
+        }
+        SharedMemory { frames: self.frames.clone(), _page_table: PhantomData }
+    }
+}
+
+impl<T: InactivePageTable> Drop for SharedMemory<T> {
+    fn drop(&mut self) {
+        for &frame in self.frames.iter() {
+            if T::frame_ref_dec(frame) == 0 {
+                T::dealloc_frame(frame);
+            }
+        }
+    }
+}
+
+/// Areas of kernel space, which every page table created by `InactivePageTable::new` maps.
+///
+/// Keeping them in one place makes sure that all address spaces see identical kernel mappings.
+/// All of them must be mapped to fixed physical addresses, so that every page table shares the same frames.
+///
+/// Areas added after user processes exist are `broadcast` instead:
+/// they live in a window whose page table pages are shared by all page tables,
+/// so mapping them once makes them visible in every address space.
+#[derive(Debug, Default)]
+pub struct KernelMappings {
+    areas: Vec<MemoryArea>,
+    broadcast: Vec<MemoryArea>,
+}
+
+impl KernelMappings {
+    /// Adds a kernel area.
+    ///
+    /// It only affects page tables created later,
+    /// so it must be called before any user process is created.
+    pub fn register(&mut self, area: MemoryArea) {
+        self.check(&area);
+        self.areas.push(area);
+    }
+
+    /// Adds a kernel area in the shared window, and maps it into the active page table `pt`.
+    ///
+    /// Since page table pages of the window are shared, it is visible in all address spaces,
+    /// existing or created later. The caller must make sure `area` lies in the window.
+    pub fn broadcast<T: InactivePageTable>(&mut self, area: MemoryArea, pt: &mut T::Active) {
+        self.check(&area);
+        area.map_physical::<T>(pt);
+        self.broadcast.push(area);
+    }
+
+    /// Removes the broadcast area starting at `start_addr`, and unmaps it from the active page table `pt`.
+    ///
+    /// Its frames are not freed, the caller owns them. Returns the area, or `None` if there is none.
+    /// Other CPUs may keep stale TLB entries, so the caller must make sure they are not using it.
+    pub fn unbroadcast<T: InactivePageTable>(&mut self, start_addr: VirtAddr, pt: &mut T::Active) -> Option<MemoryArea> {
+        let i = self.broadcast.iter().position(|area| area.start_addr == start_addr)?;
+        let area = self.broadcast.remove(i);
+        area.unmap::<T>(pt);
+        Some(area)
+    }
+
+    fn check(&self, area: &MemoryArea) {
+        assert!(area.phys_start_addr.is_some(), "kernel mapping must have a physical address");
+        assert!(area.kind == AreaKind::Mapped, "kernel mapping must be mapped");
+        assert!(self.iter()
+                    .find(|other| area.is_overlap_with(other))
+                    .is_none(), "kernel mapping overlap");
+    }
+
+    /// Maps all registered kernel areas into page table `pt`.
+    ///
+    /// Broadcast areas are not mapped, `pt` should share the window instead.
+    pub fn map_into<T: InactivePageTable>(&self, pt: &mut T::Active) {
+        for area in self.areas.iter() {
+            area.map_physical::<T>(pt);
+        }
+    }
+
+    /// Iterates over all kernel areas, including broadcast ones.
+    pub fn iter(&self) -> impl Iterator<Item=&MemoryArea> {
+        self.areas.iter().chain(self.broadcast.iter())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty_area_overlaps_nothing() {
+        let attr = MemoryAttr::default();
+        let empty = MemoryArea::new(0x1000, 0x1000, attr, "empty");
+        let zero = MemoryArea::new(0, 0, attr, "zero");
+        let area = MemoryArea::new(0x0, 0x3000, attr, "area");
+        assert!(!empty.is_overlap_with(&area));
+        assert!(!area.is_overlap_with(&empty));
+        assert!(!zero.is_overlap_with(&area));
+        assert!(area.is_overlap_with(&MemoryArea::new(0x2fff, 0x4000, attr, "")));
+    }
+
+    #[test]
+    fn push_all_edits_once() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push_all(vec![
+            MemoryArea::new(0x1000, 0x2000, attr, "text"),
+            MemoryArea::new(0x2000, 0x4000, attr, "data"),
+            MemoryArea::new(0x5000, 0x6000, attr, "bss"),
+        ]);
+        assert_eq!(ms.page_table.edit_count, 1);
+        assert_eq!(ms.iter().count(), 3);
+    }
+
+    #[test]
+    fn check_access() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user.readonly(), "text"));
+        ms.push(MemoryArea::new(0x2000, 0x3000, user, "data"));
+        ms.push(MemoryArea::new(0x4000, 0x5000, user, "stack"));
+        ms.push(MemoryArea::new(0x5000, 0x6000, MemoryAttr::default(), "kernel"));
+
+        assert!(ms.check_access(0x1800, 0x1000, false, None));
+        assert!(!ms.check_access(0x1800, 0x1000, true, None), "text is readonly");
+        assert!(ms.check_access(0x2000, 0x1000, true, None));
+        assert!(!ms.check_access(0x2800, 0x1000, false, None), "gap between data and stack");
+        assert!(!ms.check_access(0x4800, 0x1000, false, None), "kernel area");
+        assert!(!ms.check_access(0x0, 0x10, false, None), "unmapped");
+        assert!(!ms.check_access(usize::max_value(), 2, false, None), "overflow");
+        assert!(ms.check_access(0x4000, 0, true, None));
+        assert!(ms.check_access(0x2800, 0, false, None), "empty range in a gap is not accessed");
+    }
+
+    #[test]
+    fn copy_user_spanning_areas() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user, "data"));
+        ms.push(MemoryArea::new(0x2000, 0x3000, user, "heap"));
+        ms.push(MemoryArea::new(0x4000, 0x5000, user, "stack"));
+
+        assert!(ms.check_access(0x1ffc, 8, true, None), "adjacent areas");
+        assert!(!ms.check_access(0x2ffc, 8, true, None), "crosses into a gap");
+        let mut buf = [0u8; 8];
+        assert_eq!(ms.copy_from_user(0x2ffc, &mut buf, None), Err(()));
+        assert_eq!(ms.copy_to_user(0x3ffc, &buf, None), Err(()));
+        assert_eq!(ms.copy_to_user(usize::max_value() - 4, &buf, None), Err(()));
+        assert_eq!(ms.copy_from_user(0x2800, &mut [], None), Ok(()));
+    }
+
+    #[test]
+    fn check_access_faults_in() {
+        use swap::MockSwapper;
+        let user = MemoryAttr::default().user();
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user, "data"));
+        ms.push(MemoryArea::new_lazy(0x2000, 0x5000, user, "heap"));
+        ms.page_table.edit(|pt| pt.write(0x1234, 7));
+        assert_eq!(ms.swap_out_page(0x1000, &mut swapper), Ok(()));
+
+        // Like a syscall reading a buffer across the data and the untouched heap
+        assert!(!ms.check_access(0x1800, 0x1000, false, None), "swapped without a swapper");
+        assert!(ms.check_access(0x1800, 0x1000, false, Some(&mut swapper)));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.read(0x1234), 7);
+            assert!(pt.get_entry(0x2000).present());
+            assert!(!pt.get_entry(0x2000).writable(), "zero frame for a read");
+            assert!(!pt.get_entry(0x3000).present());
+        });
+        // Writing copies the zero frame, and faults in the next page
+        assert!(ms.check_access(0x2800, 0x1000, true, None));
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x2000).writable());
+            assert!(pt.get_entry(0x3000).writable());
+            assert_eq!(pt.read(0x3000), 0);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+        ms.clear();
+    }
+
+    #[test]
+    fn validate_str_rejects_invalid_pointer() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user, "data"));
+        ms.push(MemoryArea::new(0x2000, 0x3000, MemoryAttr::default(), "kernel"));
+        ms.push(MemoryArea::new_guard(0x3000, 0x4000, "guard"));
+
+        assert_eq!(ms.validate_str(0x1000, 0, None), None, "nothing scanned");
+        assert_eq!(ms.validate_str(0x2ffe, 16, None), None, "kernel area");
+        assert_eq!(ms.validate_str(0x3000, 16, None), None, "guard area");
+        assert_eq!(ms.validate_str(0x4ffe, 16, None), None, "unmapped");
+        assert_eq!(ms.validate_str(usize::max_value(), 16, None), None, "overflow");
+    }
+
+    #[test]
+    fn access_user_rejects_invalid_pointer() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default().user().readonly(), "text"));
+        let mut buf = [0u8; 4];
+        assert_eq!(ms.access_user(None, |mut user| user.read(0x3000, &mut buf)), Err(()));
+        assert_eq!(ms.access_user(None, |mut user| user.write(0x1000, &buf)), Err(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "memory area overlap")]
+    fn push_all_overlap() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push_all(vec![
+            MemoryArea::new(0x1000, 0x3000, attr, "text"),
+            MemoryArea::new(0x2000, 0x4000, attr, "data"),
+        ]);
+    }
+
+    #[test]
+    fn try_push() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, attr, "text"));
+        let data = MemoryArea::new(0x2000, 0x4000, attr, "data");
+        assert_eq!(ms.try_push(data), Err(PushError::Overlap(data)));
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x3000).present(), "nothing is mapped"));
+
+        let bss = MemoryArea::new(0x4000, 0x5000, attr, "bss");
+        assert_eq!(ms.try_push_all(vec![bss, data]), Err(PushError::Overlap(data)));
+        assert!(ms.find_area(0x4000).is_none());
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x4000).present()));
+        assert_eq!(ms.try_push_all(vec![bss]), Ok(()));
+        assert_eq!(ms.try_push(data.slice(0x3000, 0x4000)), Ok(()));
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn enforce_wx() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.set_enforce_wx(true);
+        ms.push(MemoryArea::new_identity(0x1000, 0x2000, attr.execute().readonly(), "text"));
+        ms.push(MemoryArea::new_identity(0x2000, 0x3000, attr, "data"));
+        let wx = MemoryArea::new(0x3000, 0x4000, attr.execute(), "wx");
+        assert_eq!(ms.try_push(wx), Err(PushError::WriteExecute(wx)));
+        assert_eq!(ms.try_push_all(vec![MemoryArea::new(0x4000, 0x5000, attr, "bss"), wx]),
+                   Err(PushError::WriteExecute(wx)));
+        assert!(ms.find_area(0x4000).is_none());
+        assert!(ms.clone_cow(None).unwrap().try_push(wx).is_err(), "policy is inherited");
+
+        assert_eq!(ms.mprotect(0x2000, 0x1000, attr.execute()), Err(()));
+        assert_eq!(ms.find_area(0x2000).unwrap().flags, attr, "not changed");
+        let shm = SharedMemory::<MockInactivePageTable>::new(0x1000).unwrap();
+        assert_eq!(ms.attach_shared(&shm, 0x5000, attr.execute()), Err(()));
+        assert!(ms.find_area(0x5000).is_none());
+
+        ms.set_enforce_wx(false);
+        assert_eq!(ms.try_push(wx), Ok(()));
+        assert_eq!(ms.mprotect(0x2000, 0x1000, attr.execute()), Ok(()));
+    }
+
+    #[test]
+    fn try_push_out_of_memory() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        // The mock has fewer free frames than pages
+        let text = MemoryArea::new(0x0, 0x8000, attr, "text");
+        let data = MemoryArea::new(0x8000, 0x10000, attr, "data");
+        assert_eq!(ms.try_push_all(vec![text, data]), Err(PushError::OutOfMemory(data)));
+        assert!(ms.find_area(0x0).is_none());
+        assert_eq!(ms.rss(), 0);
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x0).present(), "text is unmapped"));
+
+        let all = MemoryArea::new(0x0, 0x10000, attr, "all");
+        assert_eq!(ms.try_push(all), Err(PushError::OutOfMemory(all)));
+        assert_eq!(ms.rss(), 0);
+        // All frames are freed
+        assert_eq!(ms.try_push(MemoryArea::new(0x0, 0xf000, attr, "text")), Ok(()));
+        assert_eq!(ms.rss(), 0xf000);
+    }
+
+    #[test]
+    fn map_out_of_memory_rolls_back() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        // Leave 4 free frames, so that the 5th of 10 pages fails
+        let taken: Vec<_> = (0..11).map(|_| MockInactivePageTable::alloc_frame().unwrap()).collect();
+        let area = MemoryArea::new(0x0, 0xa000, MemoryAttr::default(), "data");
+        assert_eq!(ms.try_push(area), Err(PushError::OutOfMemory(area)));
+        assert!(ms.find_area(0x0).is_none());
+        assert_eq!(ms.rss(), 0);
+        ms.page_table.edit(|pt| {
+            for addr in (0x0..0xa000).step_by(PAGE_SIZE) {
+                assert!(!pt.get_entry(addr).present(), "{:#x} is unmapped", addr);
+            }
+        });
+        // Frames of the first 4 pages are freed
+        let freed: Vec<_> = (0..4).map(|_| MockInactivePageTable::alloc_frame()).collect();
+        assert!(freed.iter().all(|frame| frame.is_some()));
+        assert_eq!(MockInactivePageTable::alloc_frame(), None);
+        for frame in taken.into_iter().chain(freed.into_iter().map(Option::unwrap)) {
+            MockInactivePageTable::dealloc_frame(frame);
+        }
+        assert_eq!(ms.try_push(area), Ok(()));
+        assert_eq!(ms.rss(), 0xa000);
+    }
+
+    #[test]
+    fn fault_out_of_memory() {
+        use swap::MockSwapper;
+        let user = MemoryAttr::default().user();
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user, "data"));
+        ms.push(MemoryArea::new_lazy(0x2000, 0x3000, user, "heap"));
+        ms.push(MemoryArea::new_guard(0x4000, 0x5000, "guard"));
+        ms.push(MemoryArea::new(0x5000, 0x6000, user, "stack"));
+        ms.set_max_stack(0x2000);
+        let mut child = ms.clone_cow(None).unwrap();
+        ms.push(MemoryArea::new(0x7000, 0x8000, user, "bss"));
+        assert_eq!(ms.swap_out_page(0x7000, &mut swapper), Ok(()));
+        let taken: Vec<_> = (0..).map(|_| MockInactivePageTable::alloc_frame())
+            .take_while(Option::is_some).map(Option::unwrap).collect();
+
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::OutOfMemory);
+        assert_eq!(ms.handle_fault(0x4ff8, true, None), FaultResult::OutOfMemory);
+        assert!(ms.find_area(0x4000).unwrap().is_guard(), "the stack is not grown");
+        assert_eq!(ms.handle_fault(0x7000, false, Some(&mut swapper)), FaultResult::OutOfMemory);
+        assert!(!ms.handle_fault(0x1000, true, None).is_resolved(), "no frame to copy to");
+        assert!(SharedMemory::<MockInactivePageTable>::new(0x1000).is_none());
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x1000).writable_shared());
+            assert!(pt.get_entry(0x7000).swapped());
+        });
+        assert_eq!(ms.verify(), Ok(()));
+
+        for frame in taken {
+            MockInactivePageTable::dealloc_frame(frame);
+        }
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x4ff8, true, None), FaultResult::StackGrown);
+        assert_eq!(ms.handle_fault(0x7000, false, Some(&mut swapper)), FaultResult::SwappedIn);
+        assert_eq!(ms.handle_fault(0x1000, true, None), FaultResult::Copied);
+        assert_eq!(ms.verify(), Ok(()));
+        ms.clear();
+        child.clear();
+    }
+
+    #[test]
+    fn alt_stack() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user.readonly(), "text"));
+        ms.push(MemoryArea::new(0x4000, 0x6000, user, "stack"));
+        ms.push(MemoryArea::new(0x8000, 0x9000, user, "alt_stack"));
+
+        assert_eq!(ms.signal_stack_top(0x5ff0), 0x5ff0);
+        assert_eq!(ms.set_alt_stack(0x1000, 0x1000), Err(()), "readonly");
+        assert_eq!(ms.set_alt_stack(0x8000, 0x2000), Err(()), "not mapped");
+        assert_eq!(ms.set_alt_stack(0x8000, 0), Err(()));
+        assert_eq!(ms.alt_stack(), None);
+
+        ms.set_alt_stack(0x8000, 0x1000).unwrap();
+        assert_eq!(ms.alt_stack(), Some((0x8000, 0x9000)));
+        assert_eq!(ms.signal_stack_top(0x5ff0), 0x9000);
+        assert_eq!(ms.signal_stack_top(0x8ff0), 0x8ff0, "already on alt stack");
+        assert_eq!(ms.clone_cow(None).unwrap().alt_stack(), Some((0x8000, 0x9000)));
+
+        ms.clear_alt_stack();
+        assert_eq!(ms.signal_stack_top(0x5ff0), 0x5ff0);
+    }
+
+    #[test]
+    fn coredump() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x1000, MemoryAttr::default().user(), "empty"));
+        let mut core = Vec::new();
+        ms.write_coredump(&mut core).unwrap();
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(core[16], 4, "ET_CORE");
+        assert_eq!(core.len(), PAGE_SIZE, "headers only, padded to page");
+    }
+
+    #[test]
+    fn coredump_swapped() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default().user(), "data"));
+        assert_eq!(ms.swap_out_page(0x1000, &mut swapper), Ok(()));
+        // Reading the page would fault, as the mock page table is never active
+        let mut core = Vec::new();
+        ms.write_coredump(&mut core).unwrap();
+        assert_eq!(core.len(), 64 + 56, "a program header without contents");
+        assert_eq!(&core[64 + 32..64 + 40], &[0; 8], "p_filesz");
+    }
+
+    #[test]
+    fn reserve() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user, "data"));
+        ms.reserve(0x2000, 0x4000, "guard");
+        assert_eq!(ms.find_area(0x3000).unwrap().kind(), AreaKind::Reserved);
+        assert!(!ms.check_access(0x2000, 1, false, None));
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x1000).present());
+            assert!(!pt.get_entry(0x2000).present());
+            assert!(!pt.get_entry(0x3000).present());
+        });
+        ms.clear();
+        assert_eq!(ms.iter().count(), 0);
+    }
+
+    #[test]
+    fn areas_sorted() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x5000, 0x6000, MemoryAttr::default(), "c"));
+        ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "a"));
+        ms.push_heap(MemoryArea::new_lazy(0x3000, 0x3000, MemoryAttr::default(), "heap"));
+        ms.push(MemoryArea::new(0x3000, 0x4000, MemoryAttr::default(), "b"));
+        ms.push(MemoryArea::new(0x5000, 0x5000, MemoryAttr::default(), "empty"));
+        ms.push(MemoryArea::new(0x2000, 0x3000, MemoryAttr::default(), "a2"));
+        let names = |ms: &MemorySet<MockInactivePageTable>| ms.iter().map(|area| area.name()).collect::<Vec<_>>();
+        assert_eq!(names(&ms), ["a", "a2", "heap", "b", "c", "empty"]);
+        // Empty areas are skipped
+        assert_eq!(ms.find_area(0x3000).map(|area| area.name()), Some("b"));
+        assert_eq!(ms.find_area(0x5800).map(|area| area.name()), Some("c"));
+        assert_eq!(ms.find_area(0x4000), None);
+
+        assert!(ms.remove_area(0x1000).is_some());
+        assert_eq!(ms.mprotect(0x3000, 0x1000, MemoryAttr::default().readonly()), Ok(()));
+        ms.unmap_range(0x5000, 0x6000);
+        assert_eq!(names(&ms), ["a2", "heap", "b", "empty"]);
+        assert_eq!(ms.find_free_range(0x2000, 0x3000), Some(0x4000));
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn with_range() {
+        let user = MemoryAttr::default().user();
+        let phys = MemoryArea::new_physical(0x3000, 0x6000, 0x1000, user, "mmio");
+        let part = phys.with_range(0x5000, 0x6000);
+        assert_eq!((part.start_addr(), part.end_addr()), (0x5000, 0x6000));
+        assert_eq!(part.phys_start_addr, Some(0x4000));
+        assert_eq!((part.flags(), part.name(), part.kind()), (user, "mmio", AreaKind::Mapped));
+        assert!(!phys.is_anonymous());
+
+        let lazy = MemoryArea::new_lazy(0x1000, 0x4000, user, "heap");
+        let part = lazy.with_range(0x2000, 0x3000);
+        assert!(part.is_anonymous() && part.handler_offset == 0x1000);
+        assert_eq!(part.merge(&lazy.with_range(0x3000, 0x4000)), Some(lazy.with_range(0x2000, 0x4000)));
+        assert!(MemoryArea::new(0x1000, 0x2000, user, "data").is_anonymous());
+        assert!(!MemoryArea::new_guard(0x1000, 0x2000, "guard").is_anonymous());
+    }
+
+    #[test]
+    fn physical_frames() {
+        static FRAMES: [PhysAddr; 3] = [0x9000, 0x3000, 0x6000];
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        let area = MemoryArea::new_physical_frames(&FRAMES, 0x1000, user, "dma");
+        assert_eq!((area.start_addr(), area.end_addr()), (0x1000, 0x4000));
+        assert!(!area.is_anonymous());
+        ms.push(area);
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x1000).target(), 0x9000);
+            assert_eq!(pt.get_entry(0x2000).target(), 0x3000);
+            assert_eq!(pt.get_entry(0x3000).target(), 0x6000);
+            pt.write(0x2008, 7);
+        });
+        assert_eq!(ms.find_by_phys(0x3000), [0x2000]);
+        assert_eq!(ms.rss(), 0);
+        assert_eq!(ms.verify(), Ok(()));
+
+        // Split parts keep their frames. Freeing one would panic, the mock never allocated them.
+        assert_eq!(ms.mprotect(0x2000, 0x1000, user.readonly()), Ok(()));
+        assert_eq!(ms.verify(), Ok(()));
+        ms.unmap_range(0x1000, 0x2000);
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x2000).target(), 0x3000);
+            assert_eq!(pt.read(0x2008), 7);
+        });
+        ms.clear();
+    }
+
+    #[test]
+    #[should_panic(expected = "range out of the area")]
+    fn with_range_out_of_area() {
+        MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "data").with_range(0x1000, 0x3000);
+    }
+
+    #[test]
+    fn coalesce() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x4000, user, "data"));
+        assert_eq!(ms.mprotect(0x2000, 0x1000, user.readonly()), Ok(()));
+        assert_eq!(ms.mprotect(0x2000, 0x1000, user), Ok(()));
+        assert_eq!(ms.iter().count(), 3);
+        // Not contiguous, or with other flags
+        ms.push(MemoryArea::new(0x5000, 0x6000, user, "data"));
+        ms.push(MemoryArea::new(0x6000, 0x7000, user.readonly(), "data"));
+        // Physical areas continuing or not
+        ms.push(MemoryArea::new_identity(0x9000, 0xa000, MemoryAttr::default(), "mmio"));
+        ms.push(MemoryArea::new_identity(0x8000, 0x9000, MemoryAttr::default(), "mmio"));
+        ms.push(MemoryArea::new_physical(0x3000, 0x4000, 0x7000, MemoryAttr::default(), "mmio"));
+        let targets: Vec<PhysAddr> = (0x1000..0x4000).step_by(PAGE_SIZE)
+            .map(|addr| { let mut target = 0; ms.page_table.edit(|pt| target = pt.get_entry(addr).target()); target })
+            .collect();
+
+        ms.coalesce();
+        let areas: Vec<_> = ms.iter().map(|area| (area.start_addr, area.end_addr)).collect();
+        assert_eq!(areas, [(0x1000, 0x4000), (0x5000, 0x6000), (0x6000, 0x7000), (0x8000, 0xa000), (0xa000, 0xb000)]);
+        assert_eq!(ms.find_area(0x3000).map(|area| area.start_addr), Some(0x1000));
+        assert_eq!(ms.find_area(0x9000).map(|area| area.start_addr), Some(0x8000));
+        for (i, addr) in (0x1000..0x4000).step_by(PAGE_SIZE).enumerate() {
+            ms.page_table.edit(|pt| assert_eq!(pt.get_entry(addr).target(), targets[i], "not remapped"));
+        }
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn unmap_reserved() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        // Far beyond the mock memory, as nothing is mapped
+        ms.reserve(0x100000, 0x200000, "hole");
+        ms.unmap_range(0x180000, 0x190000);
+        let areas: Vec<_> = ms.iter().map(|area| (area.start_addr, area.end_addr, area.kind())).collect();
+        assert_eq!(areas, [(0x100000, 0x180000, AreaKind::Reserved), (0x190000, 0x200000, AreaKind::Reserved)]);
+        assert_eq!(ms.rss(), 0);
+        assert_eq!(ms.handle_fault(0x100000, false, None), FaultResult::Unhandled);
+        ms.unmap_range(0x100000, 0x200000);
+        assert_eq!(ms.iter().count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "memory area overlap")]
+    fn push_over_reserved() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.reserve(0x2000, 0x4000, "guard");
+        ms.push(MemoryArea::new(0x3000, 0x5000, MemoryAttr::default(), "data"));
+    }
+
+    #[test]
+    fn find_free_range() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.set_user_space(0x1000, 0x10000);
+        ms.push(MemoryArea::new(0x1000, 0x2000, attr, "text"));
+        ms.push(MemoryArea::new(0x8000, 0x9000, attr, "data"));
+        ms.push(MemoryArea::new(0x3000, 0x3800, attr, "rodata"));
+        ms.reserve(0x5000, 0x7000, "guard");
+        ms.push(MemoryArea::new(0xe000, 0x10000, attr, "stack"));
+        // Free: [0x2000, 0x3000), [0x4000, 0x5000), [0x7000, 0x8000), [0x9000, 0xe000)
+
+        assert_eq!(ms.find_free_range(0x1000, 0), Some(0x2000));
+        assert_eq!(ms.find_free_range(0x800, 0x4800), Some(0x4000), "rounded to page");
+        assert_eq!(ms.find_free_range(0x1000, 0x7800), Some(0x7000));
+        assert_eq!(ms.find_free_range(0x2000, 0), Some(0x9000), "first gap that fits");
+        assert_eq!(ms.find_free_range(0x2000, 0xb000), Some(0xb000), "at hint");
+        assert_eq!(ms.find_free_range(0x2000, 0xf000), Some(0xc000), "nearest to hint");
+        assert_eq!(ms.find_free_range(0x5000, 0), Some(0x9000));
+        assert_eq!(ms.find_free_range(0x6000, 0), None);
+        assert_eq!(ms.find_free_range(0, 0), None);
+        assert_eq!(ms.find_free_range(usize::max_value(), 0), None);
+    }
+
+    #[test]
+    fn is_range_free() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2800, attr, "text"));
+        ms.reserve(0x4000, 0x5000, "guard");
+        assert!(ms.is_range_free(0x0, 0x1000));
+        assert!(!ms.is_range_free(0x0, 0x1001));
+        assert!(!ms.is_range_free(0x2800, 0x3000), "same page as the end of text");
+        assert!(ms.is_range_free(0x3000, 0x4000));
+        assert!(!ms.is_range_free(0x3fff, 0x4000 + 1));
+        assert!(!ms.is_range_free(0x0, 0x10000));
+        assert!(ms.is_range_free(0x2000, 0x2000), "empty");
+        assert!(ms.find_area(0x1000).unwrap().overlaps_range(0x2fff, 0x3000));
+        assert!(!ms.find_area(0x1000).unwrap().overlaps_range(0x3000, 0x4000));
+    }
+
+    #[test]
+    fn find_free_area() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.set_user_space(0x1000, 0x8000);
+        ms.push(MemoryArea::new(0x1000, 0x3000, attr, "text"));
+        ms.push(MemoryArea::new(0x4000, 0x6000, attr, "data"));
+        ms.reserve(0x7000, 0x8000, "guard");
+        assert_eq!(ms.find_free_area(0, 0x1000), Some(0x3000));
+        assert_eq!(ms.find_free_area(0, 0x1001), None, "no gap of 2 pages");
+        assert_eq!(ms.find_free_area(0x3001, 0x1000), Some(0x6000), "hint is a lower bound");
+        assert_eq!(ms.find_free_area(0x6001, 0x1000), None);
+
+        ms.unmap_range(0x4000, 0x5000);
+        assert_eq!(ms.find_free_area(0x1000, 0x2000), Some(0x3000));
+        ms.push(MemoryArea::new(0x3000, 0x5000, attr, "mmap"));
+        ms.push(MemoryArea::new(0x6000, 0x7000, attr, "mmap"));
+        assert_eq!(ms.find_free_area(0, 0x1000), None, "exhausted");
+    }
+
+    #[test]
+    fn kernel_mappings() {
+        let mut km = KernelMappings::default();
+        km.register(MemoryArea::new_identity(0x1000, 0x3000, MemoryAttr::default().execute(), "text"));
+        km.register(MemoryArea::new_physical(0x0, 0x1000, 0x8000, MemoryAttr::default(), "physmap"));
+        assert_eq!(km.iter().count(), 2);
+
+        let mut pt = MockInactivePageTable::new();
+        pt.edit(|pt| {
+            km.map_into::<MockInactivePageTable>(pt);
+            assert_eq!(pt.get_entry(0x2000).target(), 0x2000);
+            assert_eq!(pt.get_entry(0x8000).target(), 0x0);
+            assert!(pt.get_entry(0x8000).present());
+            assert!(!pt.get_entry(0x3000).present());
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "kernel mapping must have a physical address")]
+    fn kernel_mapping_not_physical() {
+        KernelMappings::default().register(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "heap"));
+    }
+
+    #[test]
+    #[should_panic(expected = "kernel mapping overlap")]
+    fn kernel_mapping_overlap() {
+        let mut km = KernelMappings::default();
+        km.register(MemoryArea::new_identity(0x1000, 0x3000, MemoryAttr::default(), "text"));
+        km.register(MemoryArea::new_identity(0x2000, 0x4000, MemoryAttr::default(), "data"));
+    }
+
+    #[test]
+    fn kernel_mapping_broadcast() {
+        let mut km = KernelMappings::default();
+        km.register(MemoryArea::new_identity(0x1000, 0x3000, MemoryAttr::default(), "text"));
+        let mut active = MockInactivePageTable::new();
+        active.edit(|pt| {
+            km.broadcast::<MockInactivePageTable>(MemoryArea::new_physical(0x0, 0x1000, 0x8000, MemoryAttr::default(), "mmio"), pt);
+            assert_eq!(pt.get_entry(0x8000).target(), 0x0);
+        });
+        assert_eq!(km.iter().count(), 2);
+
+        let mut pt = MockInactivePageTable::new();
+        pt.edit(|pt| {
+            km.map_into::<MockInactivePageTable>(pt);
+            assert!(pt.get_entry(0x1000).present());
+            assert!(!pt.get_entry(0x8000).present(), "shared, not mapped again");
+        });
+
+        active.edit(|pt| {
+            assert!(km.unbroadcast::<MockInactivePageTable>(0x9000, pt).is_none());
+            let area = km.unbroadcast::<MockInactivePageTable>(0x8000, pt).unwrap();
+            assert_eq!(area.start_addr(), 0x8000);
+            assert!(!pt.get_entry(0x8000).present());
+        });
+        assert_eq!(km.iter().count(), 1);
+        // Not freed, mapping it again does not overlap
+        active.edit(|pt| {
+            km.broadcast::<MockInactivePageTable>(MemoryArea::new_physical(0x0, 0x1000, 0x8000, MemoryAttr::default(), "mmio"), pt);
+        });
+    }
+
+    #[test]
+    fn find_area_in_many_areas() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        // Reserved areas map nothing, so they can be out of the mock memory
+        for i in (0..10000).rev() {
+            ms.reserve(0x10000 + i * 0x3000, 0x10000 + i * 0x3000 + 0x1800, "");
+        }
+        assert_eq!(ms.find_area(0x10000 + 5000 * 0x3000 + 0x17ff).unwrap().start_addr(), 0x10000 + 5000 * 0x3000);
+        assert!(ms.find_area(0x10000 + 5000 * 0x3000 + 0x1800).is_none());
+        assert!(ms.find_area(0xffff).is_none());
+        assert_eq!(ms.find_free_range(0x1000, 0x10000 + 5000 * 0x3000), Some(0x10000 + 5000 * 0x3000 - 0x1000));
+        assert_eq!(ms.find_free_range(0x2000, 0x10000 + 4000 * 0x3000), Some(0xe000));
+    }
+
+    #[test]
+    fn find_area_mut() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "text"));
+        ms.push(MemoryArea::new(0x2000, 0x4000, MemoryAttr::default(), "data"));
+        ms.find_area_mut(0x2fff).unwrap().set_name("heap");
+        assert_eq!(ms.find_area(0x2000).unwrap().name(), "heap");
+        assert!(ms.find_area_mut(0x4000).is_none());
+        for area in ms.iter_mut() {
+            area.flags = area.flags.execute();
+        }
+        assert!(ms.iter().all(|area| area.flags() == MemoryAttr::default().execute()));
+    }
+
+    #[test]
+    fn stack_aligned_top() {
+        let stack = Stack::new(0x1000, 0x2000);
+        assert_eq!(stack.aligned_top(0), 0x2000);
+        assert_eq!(stack.aligned_top(8), 0x2000 - STACK_ALIGN);
+        assert_eq!(stack.aligned_top(STACK_ALIGN + 1), 0x2000 - 2 * STACK_ALIGN);
+        assert_eq!(stack.aligned_top(0x1000), 0x1000);
+    }
+
+    #[test]
+    fn fault_handler() {
+        fn fill(offset: usize, page: &mut [u8]) -> Result<(), ()> {
+            match offset {
+                0x2000 => Err(()),
+                _ => {
+                    page[0] = (offset / PAGE_SIZE) as u8 + 1;
+                    Ok(())
+                }
+            }
+        }
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_with_fault_handler(0x1000, 0x4000, MemoryAttr::default().user(), "", fill));
+        ms.push(MemoryArea::new(0x4000, 0x5000, MemoryAttr::default().user(), ""));
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x1000).present()));
+        assert!(!ms.check_access(0x3000, 1, false, None), "refused by the fault handler");
+
+        assert_eq!(ms.handle_fault(0x1800, false, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x2800, true, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x1000, false, None), FaultResult::Unhandled, "already present");
+        assert_eq!(ms.handle_fault(0x3000, false, None), FaultResult::Refused);
+        assert_eq!(ms.handle_fault(0x4000, true, None), FaultResult::Unhandled, "no fault handler");
+        assert_eq!(ms.verify(), Ok(()));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.read(0x1000), 1);
+            assert_eq!(pt.read(0x2000), 2);
+            assert_eq!(pt.get_entry(0x1000).target(), 0x1000, "frame 0x0 is taken by the normal area");
+            assert_eq!(pt.get_entry(0x2000).target(), 0x2000);
+            assert!(pt.get_entry(0x2000).user());
+            assert!(!pt.get_entry(0x3000).present());
+        });
+        // Frees only the frames of faulted pages, or the mock allocator panics
+        ms.clear();
+    }
+
+    #[test]
+    fn verify() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user.readonly().execute(), "text"));
+        ms.push(MemoryArea::new(0x2000, 0x4000, user, "data"));
+        ms.push(MemoryArea::new_identity(0x4000, 0x5000, MemoryAttr::default(), "kernel"));
+        ms.reserve(0x5000, 0x6000, "guard");
+        assert_eq!(ms.verify(), Ok(()));
+
+        ms.page_table.edit(|pt| {
+            let entry = pt.get_entry(0x3000);
+            entry.set_shared(true);
+            entry.set_writable(false);
+        });
+        assert_eq!(ms.verify(), Ok(()), "copy-on-write page is read-only");
+
+        ms.page_table.edit(|pt| pt.get_entry(0x1000).set_writable(true));
+        assert_eq!(ms.verify(), Err(VerifyError::Permission(0x1000)));
+        ms.page_table.edit(|pt| pt.get_entry(0x1000).set_writable(false));
+
+        ms.page_table.edit(|pt| pt.get_entry(0x4000).set_target(0x0));
+        assert_eq!(ms.verify(), Err(VerifyError::Target(0x4000)));
+        ms.page_table.edit(|pt| pt.get_entry(0x4000).set_target(0x4000));
+
+        ms.page_table.edit(|pt| pt.get_entry(0x2000).set_present(false));
+        assert_eq!(ms.verify(), Err(VerifyError::NotPresent(0x2000)));
+        ms.page_table.edit(|pt| pt.get_entry(0x2000).set_swapped(true));
+        assert_eq!(ms.verify(), Err(VerifyError::Resident(2)), "swapped page is not present, but still counted");
+        ms.page_table.edit(|pt| {
+            pt.get_entry(0x2000).set_swapped(false);
+            pt.get_entry(0x2000).set_present(true);
+        });
+    }
+
+    #[test]
+    fn transfer_area() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new(0x4000, 0x5000, MemoryAttr::default(), "stack"));
+        ms.page_table.edit(|pt| {
+            pt.write(0x1000, 1);
+            pt.write(0x2fff, 2);
+        });
+        assert!(ms.take_area(0x1800).is_none(), "not the start of an area");
+
+        let detached = ms.take_area(0x1000).unwrap();
+        assert!(ms.find_area(0x1000).is_none());
+        assert!(ms.find_area(0x4000).is_some());
+        assert_eq!(ms.verify(), Ok(()));
+
+        let mut other = MemorySet::<MockInactivePageTable>::new();
+        assert!(other.attach_area(detached).is_ok());
+        other.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x1000).target(), 0x0);
+            assert_eq!(pt.get_entry(0x2000).target(), 0x1000);
+            assert_eq!(pt.read(0x1000), 1);
+            assert_eq!(pt.read(0x2fff), 2);
+        });
+        assert_eq!(other.verify(), Ok(()));
+        // Each frame is freed only once, or the mock allocator panics
+        drop(other);
+        drop(ms);
+        // Frames of a detached area are freed on drop
+        let detached = {
+            let mut ms = MemorySet::<MockInactivePageTable>::new();
+            ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "data"));
+            ms.take_area(0x1000).unwrap()
+        };
+        drop(detached);
+    }
+
+    #[test]
+    fn transfer_shared_and_swapped_area() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.page_table.edit(|pt| {
+            pt.write(0x1000, 1);
+            pt.write(0x2000, 2);
+        });
+        let mut child = ms.clone_cow(None).unwrap();
+        child.page_table.edit(|pt| {
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x2000), Some(true));
+        });
+        assert_eq!(child.swap_out_page(0x2000, &mut swapper), Ok(()));
+
+        let detached = child.take_area(0x1000).unwrap();
+        let mut other = MemorySet::<MockInactivePageTable>::new();
+        other.push(MemoryArea::new(0x2000, 0x3000, MemoryAttr::default(), "data"));
+        let detached = match other.attach_area(detached) {
+            Err(detached) => detached,
+            Ok(()) => panic!("attached over an area"),
+        };
+        other.remove_area(0x2000);
+        assert!(other.attach_area(detached).is_ok());
+        other.page_table.edit(|pt| {
+            let entry = pt.get_entry(0x1000);
+            assert!(entry.writable_shared() && !entry.writable(), "still copy-on-write");
+            assert_eq!(pt.read(0x1000), 1);
+            assert!(pt.get_entry(0x2000).swapped());
+        });
+        assert_eq!(MockInactivePageTable::frame_ref_count(0x0), 2);
+        assert_eq!(other.verify(), Ok(()));
+        assert_eq!(other.swap_in_page(0x2000, &mut swapper), Ok(()));
+        other.page_table.edit(|pt| {
+            assert_eq!(pt.read(0x2000), 2);
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x1000), Some(true));
+            pt.write(0x1000, 5);
+        });
+        ms.page_table.edit(|pt| assert_eq!(pt.read(0x1000), 1));
+        // Shared frames are freed only by the last one, or the mock allocator panics
+        drop(ms);
+        drop(other);
+    }
+
+    #[test]
+    fn user_space_of_levels() {
+        let ms4 = MemorySet::<MockInactivePageTable>::new();
+        let mut ms3 = MemorySet::<MockInactivePageTable3>::new();
+        assert_eq!(ms4.find_free_range(0x1000, usize::max_value()), Some(0x7fff_ffff_f000));
+        assert_eq!(ms3.find_free_range(0x1000, usize::max_value()), Some(0x3f_ffff_f000));
+        assert!(!ms3.check_access(0x3f_ffff_f000, 0x2000, false, None), "beyond user space");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid user space")]
+    fn user_space_beyond_levels() {
+        let mut ms = MemorySet::<MockInactivePageTable3>::new();
+        ms.set_user_space(0x1000, 0x8000_0000_0000);
+    }
+
+    #[test]
+    fn zero_range() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new(0x3000, 0x4000, MemoryAttr::default(), "bss"));
+        ms.page_table.edit(|pt| {
+            for addr in (0x1000..0x4000).step_by(0x400) {
+                pt.write(addr, 1);
+                pt.write(addr + 0x3ff, 1);
+            }
+        });
+        ms.zero_range(0x1400, 0x3400);
+        ms.page_table.edit(|pt| {
+            for addr in (0x1000..0x4000).step_by(0x400) {
+                let expected = if addr >= 0x1400 && addr < 0x3400 { 0 } else { 1 };
+                assert_eq!(pt.read(addr), expected, "{:#x}", addr);
+                assert_eq!(pt.read(addr + 0x3ff), expected, "{:#x}", addr + 0x3ff);
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "zeroing unmapped memory")]
+    fn zero_range_unmapped() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "data"));
+        ms.zero_range(0x1000, 0x2001);
+    }
+
+    #[test]
+    fn dump_layout() {
+        use alloc::string::String;
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default().user(), "data"));
+        ms.push(MemoryArea::new_lazy(0x3000, 0x4000, MemoryAttr::default().readonly(), "lazy"));
+        ms.reserve(0x4000, 0x5000, "guard");
+        ms.push(MemoryArea::new_identity(0x5000, 0x6000, MemoryAttr::default().execute(), "mmio"));
+        ms.page_table.edit(|pt| pt.write(0x1000, 1));
+        let _child = ms.clone_cow(None).unwrap();
+
+        let mut out = String::new();
+        ms.dump_layout(&mut out).unwrap();
+        assert_eq!(out, "\
+0x1000..0x3000 rw-u mapped \"data\"
+    0x1000 -> 0x0 present user dirty shared-writable
+    0x2000 -> 0x1000 present user shared-writable
+0x3000..0x4000 r--- handled \"lazy\"
+    0x3000 not present
+0x4000..0x5000 rw-- reserved \"guard\"
+0x5000..0x6000 rwx- physical \"mmio\"
+    0x5000 -> 0x5000 present writable
+");
+    }
+
+    #[test]
+    fn clone_cow() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new(0x3000, 0x4000, MemoryAttr::default().readonly(), "text"));
+        ms.push(MemoryArea::new_identity(0x5000, 0x6000, MemoryAttr::default(), "mmio"));
+        ms.page_table.edit(|pt| {
+            pt.write(0x1000, 1);
+            pt.write(0x2000, 2);
+        });
+        let mut child = ms.clone_cow(None).unwrap();
+        for set in [&mut ms, &mut child].iter_mut() {
+            set.page_table.edit(|pt| {
+                assert_eq!(pt.get_entry(0x1000).target(), 0x0);
+                assert!(pt.get_entry(0x1000).writable_shared() && !pt.get_entry(0x1000).writable());
+                assert!(pt.get_entry(0x3000).readonly_shared());
+                assert!(!pt.get_entry(0x5000).writable_shared(), "physical area is not copy-on-write");
+                assert_eq!(pt.get_entry(0x5000).target(), 0x5000);
+                assert_eq!(pt.read(0x2000), 2);
+            });
+            assert_eq!(set.verify(), Ok(()));
+        }
+        assert_eq!(MockInactivePageTable::frame_ref_count(0x0), 2);
+
+        // The child copies, then the parent is the last one and writes in place
+        child.page_table.edit(|pt| {
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x1800), Some(true));
+            assert_ne!(pt.get_entry(0x1000).target(), 0x0);
+            assert_eq!(pt.read(0x1000), 1);
+            pt.write(0x1000, 3);
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x3000), Some(false));
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x5000), None);
+        });
+        ms.page_table.edit(|pt| {
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x1000), Some(true));
+            assert_eq!(pt.get_entry(0x1000).target(), 0x0, "last reference is not copied");
+            assert_eq!(pt.read(0x1000), 1);
+            pt.write(0x1000, 4);
+        });
+        child.page_table.edit(|pt| assert_eq!(pt.read(0x1000), 3));
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(child.verify(), Ok(()));
+        // Shared frames are freed only by the last one, or the mock allocator panics
+        drop(ms);
+        drop(child);
+    }
+
+    #[test]
+    fn borrow_then_exec_touches_no_parent_frame() {
+        // Like a child of `vfork`, which borrows the memory set instead of `clone_cow`,
+        // then loads a new program into a memory set of its own.
+        fn snapshot(ms: &mut MemorySet<MockInactivePageTable>) -> Vec<(PhysAddr, bool, bool, bool, bool, u8)> {
+            let mut pages = Vec::new();
+            ms.page_table.edit(|pt| for addr in (0x1000..0x4000).step_by(PAGE_SIZE) {
+                let data = pt.read(addr);
+                let entry = pt.get_entry(addr);
+                pages.push((entry.target(), entry.present(), entry.writable(),
+                            entry.writable_shared(), entry.readonly_shared(), data));
+            });
+            pages
+        }
+        let mut parent = MemorySet::<MockInactivePageTable>::new();
+        parent.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        parent.push(MemoryArea::new(0x3000, 0x4000, MemoryAttr::default().readonly(), "text"));
+        parent.page_table.edit(|pt| {
+            pt.write(0x1000, 1);
+            pt.write(0x2000, 2);
+        });
+        let before = snapshot(&mut parent);
+
+        {
+            let borrowed = &mut parent;
+            borrowed.page_table.edit(|pt| assert_eq!(pt.read(0x2000), 2));
+            let mut exec = MemorySet::<MockInactivePageTable>::new();
+            exec.push(MemoryArea::new(0x1000, 0x4000, MemoryAttr::default(), "data"));
+            exec.page_table.edit(|pt| pt.write(0x1000, 5));
+            let frames: Vec<_> = before.iter().map(|page| page.0).collect();
+            exec.page_table.edit(|pt| for addr in (0x1000..0x4000).step_by(PAGE_SIZE) {
+                assert!(!frames.contains(&pt.get_entry(addr).target()), "new frames only");
+            });
+            drop(exec);
+        }
+
+        assert_eq!(snapshot(&mut parent), before);
+        for page in before.iter() {
+            assert_eq!(MockInactivePageTable::frame_ref_count(page.0), 1, "never shared");
+        }
+        assert_eq!(parent.verify(), Ok(()));
+    }
+
+    #[test]
+    fn lazy_area() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_lazy(0x1000, 0x4000, MemoryAttr::default(), "stack"));
+        ms.push(MemoryArea::new(0x4000, 0x5000, MemoryAttr::default(), "data"));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x4000).target(), 0x0, "no frame allocated for the lazy area");
+            pt.write(0x4000, 1);
+        });
+
+        assert!(ms.handle_page_fault(0x2800));
+        assert!(!ms.handle_page_fault(0x2000), "already mapped");
+        assert!(!ms.handle_page_fault(0x6000), "out of any area");
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x2000).target(), MockInactivePageTable::zero_frame(), "mapped for a read");
+            assert_eq!(pt.read(0x2800), 0);
+            assert!(!pt.get_entry(0x1000).present());
+        });
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn stack_guard() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_guard(0x1000, 0x2000, "guard"));
+        ms.push(MemoryArea::new(0x2000, 0x4000, MemoryAttr::default().user(), "stack"));
+        ms.reserve(0x4000, 0x5000, "reserved");
+        assert!(ms.find_area(0x1000).unwrap().is_guard());
+        assert!(!ms.find_area(0x4000).unwrap().is_guard());
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x1000).present()));
+        assert_eq!(ms.handle_fault(0x1ff8, true, None), FaultResult::StackOverflow);
+        assert_eq!(ms.handle_fault(0x4000, true, None), FaultResult::Unhandled);
+        assert_eq!(ms.mprotect(0x1000, 0x1000, MemoryAttr::default()), Err(()));
+
+        // Kept by fork
+        let mut child = ms.clone_cow(None).unwrap();
+        assert!(child.find_area(0x1000).unwrap().is_guard());
+        assert_eq!(child.handle_fault(0x1000, false, None), FaultResult::StackOverflow);
+        assert_eq!(child.verify(), Ok(()));
+    }
+
+    #[test]
+    fn hidden_fault() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_guard(0x1000, 0x2000, "guard"));
+        ms.push(MemoryArea::new_lazy(0x2000, 0x4000, MemoryAttr::default().hide(), "none"));
+        ms.push(MemoryArea::new_with_fault_handler(0x4000, 0x5000, MemoryAttr::default().hide(), "filled", |_, _| Ok(())));
+        assert!(ms.find_area(0x1000).unwrap().is_hidden());
+        assert!(ms.find_area(0x2000).unwrap().is_hidden() && !ms.find_area(0x2000).unwrap().is_guard());
+
+        assert_eq!(ms.handle_fault(0x1000, false, None), FaultResult::StackOverflow);
+        assert_eq!(ms.handle_fault(0x2000, false, None), FaultResult::Unhandled);
+        assert_eq!(ms.handle_fault(0x3000, true, None), FaultResult::Unhandled);
+        assert_eq!(ms.handle_fault(0x4000, false, None), FaultResult::Unhandled);
+        assert_eq!(ms.rss(), 0);
+        ms.page_table.edit(|pt| for addr in (0x1000..0x5000).step_by(PAGE_SIZE) {
+            assert!(!pt.get_entry(addr).present());
+        });
+        assert_eq!(ms.fault_counters(), FaultCounters::default());
+
+        // Accessible again once unhidden
+        assert_eq!(ms.mprotect(0x2000, 0x1000, MemoryAttr::default()), Ok(()));
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::Allocated);
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn hide_faulted_pages() {
+        let attr = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_lazy(0x1000, 0x4000, attr, "heap"));
+        assert_eq!(ms.handle_fault(0x1000, true, None), FaultResult::Allocated);
+        ms.page_table.edit(|pt| pt.write(0x1000, 7));
+        assert_eq!(ms.handle_fault(0x2000, false, None), FaultResult::Allocated);
+
+        assert_eq!(ms.mprotect(0x1000, 0x3000, attr.hide()), Ok(()));
+        ms.page_table.edit(|pt| for addr in (0x1000..0x4000).step_by(PAGE_SIZE) {
+            assert!(!pt.get_entry(addr).present());
+        });
+        assert_eq!(ms.handle_fault(0x1000, false, None), FaultResult::Unhandled);
+        assert_eq!(ms.rss(), 0x2000, "frames are kept");
+        assert_eq!(ms.verify(), Ok(()));
+
+        // Kept hidden in a child, and shown again with the contents
+        let mut child = ms.clone_cow(None).unwrap();
+        child.page_table.edit(|pt| assert!(!pt.get_entry(0x1000).present()));
+        assert_eq!(ms.mprotect(0x1000, 0x3000, attr), Ok(()));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.read(0x1000), 7);
+            assert!(pt.get_entry(0x2000).present());
+            assert!(!pt.get_entry(0x3000).present(), "not faulted yet");
+        });
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(child.verify(), Ok(()));
+
+        // Hidden pages are unmapped too, or the mock panics on unmapping a page not present
+        child.clear();
+        ms.clear();
+    }
+
+    #[test]
+    fn grow_stack() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, user, "data"));
+        ms.push(MemoryArea::new_guard(0x4000, 0x5000, "guard"));
+        ms.push(MemoryArea::new(0x5000, 0x6000, user, "stack"));
+        ms.push(MemoryArea::new_guard(0x8000, 0x9000, "lone guard"));
+        ms.set_max_stack(0x2000);
+
+        assert_eq!(ms.handle_fault(0x4ff8, true, None), FaultResult::StackGrown);
+        assert_eq!(ms.find_area(0x4000).map(|area| (area.start_addr, area.end_addr)), Some((0x4000, 0x6000)));
+        assert!(ms.find_area(0x3000).unwrap().is_guard());
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x4000).present() && pt.get_entry(0x4000).user());
+            pt.write(0x4ff8, 1);
+        });
+        assert_eq!(ms.rss(), 3 * PAGE_SIZE);
+        assert_eq!(ms.verify(), Ok(()));
+
+        assert_eq!(ms.handle_fault(0x3ff8, true, None), FaultResult::StackOverflow, "max stack size");
+        ms.set_max_stack(0x4000);
+        assert_eq!(ms.handle_fault(0x3ff8, true, None), FaultResult::StackGrown);
+        assert_eq!(ms.handle_fault(0x2ff8, true, None), FaultResult::StackOverflow, "data below");
+        assert_eq!(ms.handle_fault(0x8000, true, None), FaultResult::Unhandled, "no stack above");
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn grow_stack_below_guard() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_guard(0x9000, 0xa000, "guard"));
+        ms.push(MemoryArea::new_lazy(0xa000, 0xb000, MemoryAttr::default().user(), "stack"));
+        ms.set_max_stack(0x4000);
+
+        // A large frame skips over the guard
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::Unhandled, "beyond max stack size");
+        assert_eq!(ms.handle_fault(0x8010, true, None), FaultResult::StackGrown);
+        assert_eq!(ms.find_area(0x8010).map(|area| (area.start_addr, area.end_addr)), Some((0x8000, 0xb000)));
+        assert!(ms.find_area(0x7000).unwrap().is_guard());
+        assert_eq!(ms.find_area(0x6fff), None);
+        assert_eq!(ms.rss(), 0, "lazy stack is grown lazily");
+        assert_eq!(ms.handle_fault(0x8010, true, None), FaultResult::Allocated);
+        assert_eq!(ms.rss(), PAGE_SIZE);
+        assert_eq!(ms.verify(), Ok(()));
+
+        assert_eq!(ms.handle_fault(0x6000, true, None), FaultResult::Unhandled);
+        assert_eq!(ms.handle_fault(0x7000, true, None), FaultResult::StackGrown);
+        assert_eq!(ms.handle_fault(0x6000, true, None), FaultResult::StackOverflow);
+    }
+
+    #[test]
+    fn file_area() {
+        /// Byte at offset `i` is `i / 0x100`.
+        struct Pattern(usize);
+        impl ByteSource for Pattern {
+            fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+                let len = buf.len().min(self.0.saturating_sub(offset));
+                for (i, byte) in buf[..len].iter_mut().enumerate() {
+                    *byte = ((offset + i) / 0x100) as u8;
+                }
+                Ok(len)
+            }
+        }
+        static FILE: Pattern = Pattern(0x2000);
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        // Data from offset 0x100 of the file, then bss
+        ms.push(MemoryArea::new_file(0x1800, 0x4000, MemoryAttr::default(), "data", &FILE, 0x100, 0x1000));
+        ms.push(MemoryArea::new_file(0x5000, 0x6000, MemoryAttr::default(), "truncated", &FILE, 0x1800, 0x1000));
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x1000).present()));
+
+        assert_eq!(ms.handle_fault(0x1800, false, None), FaultResult::Allocated);
+        // Split before faulted, still read from the same offset
+        assert_eq!(ms.mprotect(0x2000, 0x1000, MemoryAttr::default()), Ok(()));
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x3000, false, None), FaultResult::Allocated);
+        ms.page_table.edit(|pt| {
+            assert_eq!((pt.read(0x17ff), pt.read(0x1800), pt.read(0x1fff)), (0, 1, 8));
+            assert_eq!((pt.read(0x2000), pt.read(0x27ff), pt.read(0x2800)), (9, 0x10, 0), "zero past the file length");
+            assert_eq!(pt.read(0x3000), 0);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(ms.handle_fault(0x5000, false, None), FaultResult::Refused, "source ends before the length");
+    }
+
+    #[test]
+    fn swap_page() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new_identity(0x3000, 0x4000, MemoryAttr::default(), "mmio"));
+        ms.page_table.edit(|pt| pt.write(0x2345, 7));
+
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        ms.page_table.edit(|pt| {
+            let entry = pt.get_entry(0x2000);
+            assert!(!entry.present());
+            assert_eq!(swap_slot(entry), Some(0));
+        });
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Err(SwapError::AlreadySwapped));
+        assert_eq!(ms.swap_out_page(0x3000, &mut swapper), Err(SwapError::NotSwappable));
+        assert_eq!(ms.swap_out_page(0x5000, &mut swapper), Err(SwapError::NotSwappable));
+
+        // The freed frame is reused
+        ms.push(MemoryArea::new(0x4000, 0x5000, MemoryAttr::default(), "bss"));
+        ms.page_table.edit(|pt| assert_eq!(pt.get_entry(0x4000).target(), 0x1000));
+
+        assert_eq!(ms.swap_in_page(0x2345, &mut swapper), Ok(()));
+        assert_eq!(ms.swap_in_page(0x2345, &mut swapper), Err(SwapError::NotSwapped));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x2000).target(), 0x2000);
+            assert_eq!(pt.read(0x2345), 7);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn swap_out_clean_page() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.page_table.edit(|pt| pt.write(0x2345, 7));
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(ms.swap_in_page(0x2000, &mut swapper), Ok(()));
+        ms.page_table.edit(|pt| {
+            assert!(!pt.get_entry(0x2000).dirty(), "clean after swapped in");
+            assert_eq!(pt.read(0x2345), 7);
+        });
+        assert_eq!(swapper.writes, 1);
+
+        // Only read since swapped in, the slot is reused without writing
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(swapper.writes, 1);
+        ms.page_table.edit(|pt| assert_eq!(swap_slot(pt.get_entry(0x2000)), Some(0)));
+        assert_eq!(ms.swap_in_page(0x2000, &mut swapper), Ok(()));
+
+        // Written, the slot is rewritten
+        ms.page_table.edit(|pt| pt.write(0x2345, 8));
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(swapper.writes, 2);
+        ms.page_table.edit(|pt| assert_eq!(swap_slot(pt.get_entry(0x2000)), Some(0)));
+        assert_eq!(ms.swap_in_page(0x2000, &mut swapper), Ok(()));
+        ms.page_table.edit(|pt| assert_eq!(pt.read(0x2345), 8));
+
+        // The slot is freed once the page is unmapped
+        MockInactivePageTable::take_freed_slots();
+        ms.unmap_range(0x2000, 0x3000);
+        assert_eq!(MockInactivePageTable::take_freed_slots(), vec![0]);
+        ms.push(MemoryArea::new(0x2000, 0x3000, MemoryAttr::default(), "data"));
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(swapper.writes, 3);
+        ms.page_table.edit(|pt| assert_eq!(swap_slot(pt.get_entry(0x2000)), Some(1)));
+    }
+
+    #[test]
+    fn free_swap_slots() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x4000, MemoryAttr::default(), "data"));
+        for &addr in [0x1000, 0x2000, 0x3000].iter() {
+            assert_eq!(ms.swap_out_page(addr, &mut swapper), Ok(()));
+        }
+        MockInactivePageTable::take_freed_slots();
+
+        // Kept while the area is split
+        assert_eq!(ms.swap_in_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(ms.mprotect(0x2000, 0x1000, MemoryAttr::default().readonly()), Ok(()));
+        assert_eq!(MockInactivePageTable::take_freed_slots(), vec![]);
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(swapper.writes, 3, "kept slot reused");
+
+        ms.unmap_range(0x1000, 0x2000);
+        assert_eq!(MockInactivePageTable::take_freed_slots(), vec![0]);
+        ms.remove_area(0x2000);
+        assert_eq!(MockInactivePageTable::take_freed_slots(), vec![1]);
+        drop(ms.take_area(0x3000));
+        assert_eq!(MockInactivePageTable::take_freed_slots(), vec![2]);
+    }
+
+    #[test]
+    fn clone_cow_swapped() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.page_table.edit(|pt| pt.write(0x2345, 7));
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(ms.clone_cow(None).err(), Some(SwapError::NoSwapper));
+
+        let mut child = ms.clone_cow(Some(&mut swapper)).unwrap();
+        for set in [&mut ms, &mut child].iter_mut() {
+            set.page_table.edit(|pt| {
+                let entry = pt.get_entry(0x2000);
+                assert!(entry.present() && !entry.swapped());
+                assert!(entry.writable_shared());
+                assert_eq!(pt.read(0x2345), 7);
+            });
+            assert_eq!(set.verify(), Ok(()));
+        }
+        child.page_table.edit(|pt| {
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x2000), Some(true));
+            pt.write(0x2345, 8);
+        });
+        ms.page_table.edit(|pt| assert_eq!(pt.read(0x2345), 7));
+    }
+
+    #[test]
+    fn select_victim_page() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        assert_eq!(ms.select_victim_page(), None);
+        ms.push(MemoryArea::new(0x1000, 0x4000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new_identity(0x4000, 0x5000, MemoryAttr::default(), "mmio"));
+
+        assert_eq!(ms.select_victim_page(), Some(0x1000));
+        // Accessed page gets a second chance
+        ms.page_table.edit(|pt| { pt.read(0x2000); });
+        assert_eq!(ms.select_victim_page(), Some(0x3000));
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x2000).accessed(), "accessed bit cleared"));
+        // Wraps around after the last page, physical pages are never chosen
+        ms.page_table.edit(|pt| { pt.read(0x1000); pt.read(0x3000); });
+        assert_eq!(ms.select_victim_page(), Some(0x2000));
+
+        // All accessed, the first one is chosen in the second round
+        ms.page_table.edit(|pt| { pt.read(0x1000); pt.read(0x2000); });
+        assert_eq!(ms.select_victim_page(), Some(0x3000));
+
+        // Swapped out pages are skipped
+        let mut swapper = ::swap::MockSwapper::default();
+        assert_eq!(ms.swap_out_page(0x3000, &mut swapper), Ok(()));
+        assert_eq!(ms.select_victim_page(), Some(0x1000));
+        assert_eq!(ms.select_victim_page(), Some(0x2000));
+        assert_eq!(ms.select_victim_page(), Some(0x1000));
+    }
+
+    #[test]
+    fn clear_bits() {
+        let mut swapper = ::swap::MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x4000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new_lazy(0x4000, 0x5000, MemoryAttr::default(), "heap"));
+        ms.page_table.edit(|pt| {
+            pt.write(0x1000, 1);
+            pt.write(0x2000, 2);
+            pt.read(0x3000);
+        });
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+
+        ms.clear_access_bits();
+        ms.clear_dirty_bits();
+        ms.page_table.edit(|pt| {
+            assert!(!pt.get_entry(0x1000).accessed() && !pt.get_entry(0x1000).dirty());
+            assert!(!pt.get_entry(0x3000).accessed());
+            // Swapped out and not faulted in entries are skipped
+            assert!(pt.get_entry(0x2000).swapped());
+            assert!(!pt.get_entry(0x4000).present());
+            // A read after aging sets the accessed bit again
+            pt.read(0x3000);
+            assert!(pt.get_entry(0x3000).accessed() && !pt.get_entry(0x3000).dirty());
+        });
+        assert_eq!(ms.select_victim_page(), Some(0x1000));
+
+        // Written since swapped in with the slot kept, so still to be written out
+        assert_eq!(ms.swap_in_page(0x2000, &mut swapper), Ok(()));
+        ms.page_table.edit(|pt| pt.write(0x2000, 3));
+        ms.clear_dirty_bits();
+        ms.page_table.edit(|pt| assert!(pt.get_entry(0x2000).dirty()));
+        assert_eq!(ms.swap_out_page(0x2000, &mut swapper), Ok(()));
+        assert_eq!(ms.swap_in_page(0x2000, &mut swapper), Ok(()));
+        ms.page_table.edit(|pt| assert_eq!(pt.read(0x2000), 3));
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn fault_dispatch() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new(0x3000, 0x4000, MemoryAttr::default().readonly(), "text"));
+        ms.push(MemoryArea::new_lazy(0x4000, 0x5000, MemoryAttr::default(), "heap"));
+        ms.reserve(0x5000, 0x6000, "guard");
+        let child = ms.clone_cow(None).unwrap();
+
+        assert_eq!(ms.handle_fault(0x2000, false, None), FaultResult::Unhandled, "reading a copy-on-write page");
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::Copied);
+        assert_eq!(ms.handle_fault(0x2000, true, None), FaultResult::Unhandled, "already copied");
+        ms.swap_out_page(0x2000, &mut swapper).unwrap();
+        assert_eq!(ms.handle_fault(0x2000, false, None), FaultResult::Unhandled, "no swapper");
+        assert_eq!(ms.handle_fault(0x2000, false, Some(&mut swapper)), FaultResult::SwappedIn);
+        assert_eq!(ms.handle_fault(0x4000, false, Some(&mut swapper)), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x3000, true, None), FaultResult::Unhandled, "read-only");
+        assert_eq!(ms.handle_fault(0x5000, true, None), FaultResult::Unhandled, "reserved");
+        assert_eq!(ms.handle_fault(0x8000, true, None), FaultResult::Unhandled, "out of any area");
+        assert!(FaultResult::Copied.is_resolved() && !FaultResult::Refused.is_resolved());
+        drop(child);
+    }
+
+    #[test]
+    fn fault_counters() {
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, MemoryAttr::default(), "data"));
+        ms.push(MemoryArea::new_lazy(0x4000, 0x6000, MemoryAttr::default(), "heap"));
+        let child = ms.clone_cow(None).unwrap();
+
+        // Reading a lazy page maps the zero frame, which is copied on the first write
+        assert_eq!(ms.handle_fault(0x4000, false, None), FaultResult::Allocated);
+        assert_eq!(ms.fault_counters(), FaultCounters { minor_faults: 1, major_faults: 0, cow_faults: 0 });
+        ms.page_table.edit(|pt| assert!(pt.get_entry(0x4000).present()));
+        assert_eq!(ms.handle_fault(0x4000, true, None), FaultResult::Copied);
+        assert_eq!(ms.handle_fault(0x1000, true, None), FaultResult::Copied);
+        ms.swap_out_page(0x1000, &mut swapper).unwrap();
+        assert_eq!(ms.handle_fault(0x1000, false, Some(&mut swapper)), FaultResult::SwappedIn);
+        assert_eq!(ms.handle_fault(0x8000, false, None), FaultResult::Unhandled, "not counted");
+        assert_eq!(ms.fault_counters(), FaultCounters { minor_faults: 1, major_faults: 1, cow_faults: 2 });
+        assert_eq!(child.fault_counters(), FaultCounters::default());
+
+        ms.clear();
+        assert_eq!(ms.fault_counters(), FaultCounters::default());
+        drop(child);
+    }
+
+    #[test]
+    fn flushes_once_per_area() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        MockPageTable::take_flushes();
+        ms.push(MemoryArea::new(0x0, 0x8000, attr, "data"));
+        assert_eq!(MockPageTable::take_flushes(), 1, "not one per page");
+        ms.push(MemoryArea::new_identity(0x8000, 0xc000, attr, "mmio"));
+        assert_eq!(MockPageTable::take_flushes(), 1);
+        ms.push(MemoryArea::new_with_fault_handler(0xc000, 0x10000, attr, "handled", |_, _| Ok(())));
+        assert_eq!(MockPageTable::take_flushes(), 1);
+        MockPageTable::take_flushed_ranges();
+
+        assert_eq!(ms.mprotect(0x1000, 0xa000, attr.readonly()), Ok(()));
+        assert_eq!(MockPageTable::take_flushes(), 1, "not one per page nor per area");
+        assert_eq!(MockPageTable::take_flushed_ranges(), [(0x1000, 0xb000)]);
+        ms.clear();
+        assert_eq!(MockPageTable::take_flushes(), 5, "one per area");
+        assert_eq!(MockPageTable::take_flushed_ranges(),
+                   [(0x0, 0x1000), (0x1000, 0x8000), (0x8000, 0xb000), (0xb000, 0xc000), (0xc000, 0x10000)]);
+    }
+
+    #[test]
+    fn find_by_phys() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, attr, "data"));
+        let mut frame = 0;
+        ms.page_table.edit(|pt| frame = pt.get_entry(0x2000).target());
+        assert_eq!(ms.find_by_phys(frame + 0x123), vec![0x2000]);
+
+        // Shared by copy-on-write, until the child writes
+        let mut child = ms.clone_cow(None).unwrap();
+        assert_eq!(ms.find_by_phys(frame), vec![0x2000]);
+        assert_eq!(child.find_by_phys(frame), vec![0x2000]);
+        assert_eq!(child.handle_fault(0x2000, true, None), FaultResult::Copied);
+        assert!(child.find_by_phys(frame).is_empty());
+        assert_eq!(ms.find_by_phys(frame), vec![0x2000]);
+
+        // Aliases in one set
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_identity(0x8000, 0x9000, attr, "mmio"));
+        ms.push(MemoryArea::new_physical(0x8000, 0x9000, 0x4000, attr, "alias"));
+        assert_eq!(ms.find_by_phys(0x8000), vec![0x8000, 0xc000]);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let user = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, user, "data"));
+        ms.push(MemoryArea::new(0x3000, 0x4000, user.readonly(), "rodata"));
+        ms.push_heap(MemoryArea::new_lazy(0x4000, 0x4000, user, "heap"));
+        ms.set_brk(0x7000).unwrap();
+        ms.push(MemoryArea::new_identity(0x8000, 0x9000, user, "mmio"));
+        assert_eq!(ms.handle_fault(0x4000, true, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x5000, false, None), FaultResult::Allocated, "zero frame");
+        ms.page_table.edit(|pt| {
+            pt.write(0x1000, 1);
+            pt.write(0x2fff, 2);
+            pt.write(0x4000, 3);
+        });
+        let snapshot = ms.snapshot();
+        assert_eq!(snapshot.size(), 4 * PAGE_SIZE, "data, rodata and a heap page");
+
+        ms.page_table.edit(|pt| {
+            pt.write(0x1000, 9);
+            pt.write(0x4000, 9);
+        });
+        ms.set_brk(0x5000).unwrap();
+        ms.push(MemoryArea::new(0xa000, 0xb000, user, "new"));
+        assert_eq!(ms.restore(&snapshot), Ok(()));
+
+        assert!(ms.find_area(0xa000).is_none());
+        assert_eq!(ms.brk(), Some(0x7000));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.read(0x1000), 1);
+            assert_eq!(pt.read(0x2fff), 2);
+            assert_eq!(pt.read(0x4000), 3);
+            assert!(!pt.get_entry(0x3000).writable(), "still read-only");
+            assert!(!pt.get_entry(0x5000).present(), "lazy again");
+        });
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn apply_attr() {
+        let mut pt = MockPageTable::new();
+        let entry = pt.map(0x1000, 0x1000);
+        MemoryAttr::default().user().execute().hide().apply(entry);
+        assert!(entry.user() && entry.execute() && entry.writable() && !entry.present());
+
+        MemoryAttr::default().readonly().apply(entry);
+        assert!(!entry.user() && !entry.execute() && !entry.writable() && entry.present());
+
+        MemoryAttr::default().user().apply(entry);
+        assert!(entry.user() && !entry.execute() && entry.writable() && entry.present());
+        assert!(!entry.cache_disable() && !entry.write_through());
+
+        MemoryAttr::default().uncacheable().write_through().apply(entry);
+        assert!(entry.cache_disable() && entry.write_through());
+
+        MemoryAttr::default().apply(entry);
+        assert!(!entry.cache_disable() && !entry.write_through());
+        assert!(!entry.global());
+
+        MemoryAttr::default().global().apply(entry);
+        assert!(entry.global());
+        MemoryAttr::default().apply(entry);
+        assert!(!entry.global());
+    }
+
+    #[test]
+    fn mprotect() {
+        let attr = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, attr, "text"));
+        ms.push(MemoryArea::new(0x3000, 0x4000, attr, "data"));
+        ms.push(MemoryArea::new(0x4000, 0x7000, attr, "bss"));
+        ms.push(MemoryArea::new(0x8000, 0xb000, attr, "heap"));
+        ms.push(MemoryArea::new_lazy(0xb000, 0xd000, attr, "lazy"));
+        ms.reserve(0xd000, 0xe000, "guard");
+
+        // Spanning adjacent areas, starting and ending in the middle
+        assert_eq!(ms.mprotect(0x2000, 0x2800, attr.readonly()), Ok(()));
+        let ranges = |ms: &MemorySet<MockInactivePageTable>| {
+            let mut ranges: Vec<_> = ms.iter().map(|area| (area.start_addr, area.end_addr, area.flags.readonly)).collect();
+            ranges.sort();
+            ranges
+        };
+        assert_eq!(ranges(&ms)[..5], [(0x1000, 0x2000, false), (0x2000, 0x3000, true), (0x3000, 0x4000, true),
+            (0x4000, 0x5000, true), (0x5000, 0x7000, false)]);
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x1000).writable());
+            assert!(!pt.get_entry(0x4000).writable());
+            assert!(pt.get_entry(0x5000).writable());
+        });
+        assert_eq!(ms.find_area(0x4fff).map(|area| area.start_addr), Some(0x4000));
+        assert_eq!(ms.verify(), Ok(()));
+
+        // In the middle of an area, split into three
+        assert_eq!(ms.mprotect(0x9000, 0x1000, attr.execute().hide()), Ok(()));
+        assert_eq!(ranges(&ms)[5..8], [(0x8000, 0x9000, false), (0x9000, 0xa000, false), (0xa000, 0xb000, false)]);
+        ms.page_table.edit(|pt| {
+            assert!(!pt.get_entry(0x9000).present());
+            assert!(!pt.get_entry(0x8000).execute());
+        });
+        assert_eq!(ms.mprotect(0x9000, 0x1000, attr.execute()), Ok(()));
+        ms.page_table.edit(|pt| assert!(pt.get_entry(0x9000).execute() && pt.get_entry(0x9000).present()));
+        assert_eq!(ms.verify(), Ok(()));
+
+        assert_eq!(ms.mprotect(0x6000, 0x2000, attr), Err(()), "gap");
+        assert_eq!(ms.mprotect(0xd000, 0x1000, attr), Err(()), "reserved");
+        assert_eq!(ms.mprotect(0x1800, 0x1000, attr), Err(()), "not aligned");
+        assert_eq!(ms.iter().count(), 10);
+        assert_eq!(ms.mprotect(0xc000, 0x1000, attr.readonly()), Ok(()));
+        assert_eq!(ms.iter().count(), 11);
+        assert_eq!(ms.handle_fault(0xc000, false, None), FaultResult::Allocated);
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn brk() {
+        let attr = MemoryAttr::default().user();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        assert_eq!(ms.set_brk(0x3000), Err(()), "no heap");
+        ms.push(MemoryArea::new(0x1000, 0x2000, attr, "text"));
+        ms.push_heap(MemoryArea::new(0x2000, 0x2000, attr, "heap"));
+        ms.push(MemoryArea::new(0x6000, 0x8000, attr, "user_stack"));
+        assert_eq!(ms.brk(), Some(0x2000));
+
+        assert_eq!(ms.set_brk(0x3800), Ok(()));
+        assert_eq!(ms.brk(), Some(0x3800));
+        assert_eq!(ms.find_area(0x37ff).map(|area| area.start_addr), Some(0x2000));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x2000).target(), 0x3000, "after text and stack");
+            assert_eq!(pt.get_entry(0x3000).target(), 0x4000);
+        });
+        assert_eq!(ms.set_brk(0x3900), Ok(()), "in the last page");
+        assert_eq!(ms.set_brk(0x6001), Err(()), "overlap with the stack");
+        assert_eq!(ms.set_brk(0x1fff), Err(()), "below the heap");
+        assert_eq!(ms.verify(), Ok(()));
+
+        assert_eq!(ms.set_brk(0x2800), Ok(()));
+        assert!(ms.find_area(0x3000).is_none());
+        ms.push(MemoryArea::new(0x4000, 0x5000, attr, "data"));
+        ms.page_table.edit(|pt| assert_eq!(pt.get_entry(0x4000).target(), 0x4000, "shrunk page is freed"));
+        assert_eq!(ms.set_brk(0x2000), Ok(()));
+        assert_eq!(ms.set_brk(0x4000), Ok(()));
+        assert_eq!(ms.set_brk(0x4001), Err(()));
+        assert_eq!(ms.verify(), Ok(()));
+
+        // Split by `mprotect`, and the pieces joined by `coalesce` but the heap end
+        ms.push(MemoryArea::new(0xa000, 0xb000, attr, "data"));
+        assert_eq!(ms.set_brk(0x5000), Err(()), "overlap with data");
+        ms.unmap_range(0x4000, 0x5000);
+        assert_eq!(ms.set_brk(0x6000), Ok(()));
+        assert_eq!(ms.mprotect(0x3000, 0x1000, attr.readonly()), Ok(()));
+        assert_eq!(ms.mprotect(0x3000, 0x1000, attr), Ok(()));
+        assert_eq!(ms.mprotect(0x4000, 0x1000, attr.readonly()), Ok(()));
+        ms.coalesce();
+        let areas = |ms: &MemorySet<MockInactivePageTable>| -> Vec<_> {
+            ms.iter().map(|area| (area.start_addr, area.end_addr)).collect()
+        };
+        assert_eq!(areas(&ms), [(0x1000, 0x2000), (0x2000, 0x4000), (0x4000, 0x5000), (0x5000, 0x6000),
+                                (0x6000, 0x8000), (0xa000, 0xb000)]);
+        assert_eq!(ms.set_brk(0x6800), Err(()), "overlap with the stack");
+        assert_eq!(ms.set_brk(0x5800), Ok(()));
+        assert_eq!(ms.set_brk(0x6000), Ok(()));
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(ms.set_brk(0x4800), Ok(()));
+        assert_eq!(areas(&ms), [(0x1000, 0x2000), (0x2000, 0x4000), (0x4000, 0x4800),
+                                (0x6000, 0x8000), (0xa000, 0xb000)]);
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x4000).writable(), "still read-only"));
+        assert_eq!(ms.set_brk(0x5000), Ok(()));
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x4800).writable(), "grown like the topmost piece"));
+        assert_eq!(ms.set_brk(0x2000), Ok(()));
+        assert_eq!(areas(&ms), [(0x1000, 0x2000), (0x2000, 0x2000), (0x6000, 0x8000), (0xa000, 0xb000)]);
+        assert_eq!(ms.verify(), Ok(()));
+
+        let mut lazy = MemorySet::<MockInactivePageTable>::new();
+        lazy.push_heap(MemoryArea::new_lazy(0x1000, 0x1000, attr, "heap"));
+        assert_eq!(lazy.set_brk(0x3000), Ok(()));
+        lazy.page_table.edit(|pt| assert!(!pt.get_entry(0x2000).present()));
+        assert_eq!(lazy.handle_fault(0x2000, true, None), FaultResult::Allocated);
+        assert_eq!(lazy.set_brk(0x1000), Ok(()));
+
+        // The slot of a swapped out page is freed on shrinking
+        use swap::MockSwapper;
+        let mut swapper = MockSwapper::default();
+        assert_eq!(lazy.set_brk(0x3000), Ok(()));
+        assert_eq!(lazy.handle_fault(0x2000, true, None), FaultResult::Allocated);
+        assert_eq!(lazy.swap_out_page(0x2000, &mut swapper), Ok(()));
+        MockInactivePageTable::take_freed_slots();
+        assert_eq!(lazy.set_brk(0x2000), Ok(()));
+        assert_eq!(MockInactivePageTable::take_freed_slots(), vec![0]);
+        assert_eq!(lazy.verify(), Ok(()));
+    }
+
+    #[test]
+    fn rss() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3800, attr, "data"));
+        ms.push(MemoryArea::new_identity(0x4000, 0x5000, attr, "mmio"));
+        ms.push(MemoryArea::new_lazy(0x5000, 0x8000, attr, "heap"));
+        ms.reserve(0x8000, 0x9000, "guard");
+        assert_eq!(ms.rss(), 0x3000);
+        assert_eq!(ms.mapped_size(), 0x7000);
+
+        // Faulted in, read first then copied from the zero frame
+        assert_eq!(ms.handle_fault(0x5000, true, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x6000, false, None), FaultResult::Allocated);
+        assert_eq!(ms.rss(), 0x5000);
+        assert_eq!(ms.handle_fault(0x6000, true, None), FaultResult::Copied);
+        assert_eq!(ms.rss(), 0x5000);
+
+        let child = ms.clone_cow(None).unwrap();
+        assert_eq!(child.rss(), 0x5000);
+        drop(child);
+
+        ms.unmap_range(0x2000, 0x6000);
+        assert_eq!(ms.rss(), 0x2000);
+        assert_eq!(ms.mapped_size(), 0x3000);
+        ms.clear();
+        assert_eq!((ms.rss(), ms.mapped_size()), (0, 0));
+    }
+
+    #[test]
+    fn shared_memory() {
+        let attr = MemoryAttr::default().user();
+        let shm = SharedMemory::<MockInactivePageTable>::new(0x1800).unwrap();
+        assert_eq!(shm.size(), 0x2000);
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x2000, attr, "data"));
+        assert_eq!(ms.attach_shared(&shm, 0x1000, attr), Err(()), "overlap");
+        assert_eq!(ms.attach_shared(&shm, 0x3800, attr), Err(()), "not aligned");
+        assert_eq!(ms.attach_shared(&shm, 0x4000, attr), Ok(()));
+        assert_eq!(ms.find_area(0x5000).map(|area| area.kind()), Some(AreaKind::Shared));
+        assert!(ms.check_access(0x4000, 0x2000, true, None));
+        assert_eq!(ms.rss(), 0x1000, "shared memory is not resident");
+        assert_eq!(ms.swap_out_page(0x4000, &mut ::swap::MockSwapper::default()), Err(SwapError::NotSwappable));
+
+        // Kept shared by fork, and by another set
+        let mut child = ms.clone_cow(None).unwrap();
+        let mut other = MemorySet::<MockInactivePageTable>::new();
+        assert_eq!(other.attach_shared(&shm, 0x8000, attr.readonly()), Ok(()));
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x4000).writable() && !pt.get_entry(0x4000).writable_shared());
+            pt.write(0x4000, 1);
+        });
+        child.page_table.edit(|pt| {
+            assert_eq!(pt.read(0x4000), 1);
+            pt.write(0x5000, 2);
+        });
+        other.page_table.edit(|pt| {
+            assert_eq!((pt.read(0x8000), pt.read(0x9000)), (1, 2));
+            assert!(!pt.get_entry(0x8000).writable());
+        });
+        assert_eq!(other.handle_fault(0x8000, true, None), FaultResult::Unhandled);
+        for set in [&mut ms, &mut child, &mut other].iter_mut() {
+            assert_eq!(set.verify(), Ok(()));
+        }
+        let frames = shm.frames.clone();
+        assert_eq!(MockInactivePageTable::frame_ref_count(frames[0]), 4);
+
+        // Freed after the last handle and attachment
+        assert!(ms.remove_area(0x4000).is_some());
+        drop(child);
+        assert_eq!(MockInactivePageTable::frame_ref_count(frames[0]), 2);
+        let handle = shm.clone();
+        drop(shm);
+        drop(handle);
+        other.page_table.edit(|pt| assert_eq!(pt.read(0x8000), 1, "not freed while attached"));
+        drop(other);
+        ms.push(MemoryArea::new(0x2000, 0x4000, attr, "bss"));
+        ms.page_table.edit(|pt| {
+            let targets = [pt.get_entry(0x2000).target(), pt.get_entry(0x3000).target()];
+            assert_eq!(targets, [frames[1], frames[0]], "the last freed first reused");
+        });
+    }
+
+    #[test]
+    fn remove_area() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x3000, attr, "data"));
+        ms.push(MemoryArea::new_identity(0x4000, 0x5000, attr, "mmio"));
+        ms.page_table.edit(|pt| pt.write(0x2000, 1));
+
+        assert_eq!(ms.remove_area(0x3000), None);
+        let data = ms.remove_area(0x2fff).unwrap();
+        assert_eq!((data.start_addr(), data.end_addr(), data.name()), (0x1000, 0x3000, "data"));
+        assert!(ms.find_area(0x1000).is_none());
+        ms.page_table.edit(|pt| assert!(!pt.get_entry(0x1000).present() && !pt.get_entry(0x2000).present()));
+        let mmio = ms.remove_area(0x4000).unwrap();
+        assert_eq!(ms.iter().count(), 0);
+
+        // Replaced by fresh frames, the last freed first reused
+        ms.push(data);
+        ms.push(mmio);
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x1000).target(), 0x1000);
+            assert_eq!(pt.get_entry(0x4000).target(), 0x4000);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+    }
+
+    #[test]
+    fn unmap_range() {
+        fn fill(offset: usize, page: &mut [u8]) -> Result<(), ()> {
+            page[0] = (offset / PAGE_SIZE) as u8;
+            Ok(())
+        }
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new(0x1000, 0x5000, attr, "data"));
+        ms.push(MemoryArea::new_identity(0x5000, 0x7000, attr, "mmio"));
+        ms.push(MemoryArea::new_with_fault_handler(0x8000, 0xc000, attr, "file", fill));
+
+        // Strictly inside, split in two
+        ms.unmap_range(0x2000, 0x4000);
+        assert_eq!(ms.find_area(0x1000).map(|area| area.end_addr), Some(0x2000));
+        assert_eq!(ms.find_area(0x4000).map(|area| area.start_addr), Some(0x4000));
+        assert!(ms.find_area(0x2000).is_none() && ms.find_area(0x3fff).is_none());
+        ms.page_table.edit(|pt| {
+            assert!(pt.get_entry(0x1000).present() && pt.get_entry(0x4000).present());
+            assert!(!pt.get_entry(0x2000).present() && !pt.get_entry(0x3000).present());
+        });
+        assert_eq!(ms.verify(), Ok(()));
+
+        // Frames of anonymous pages only are freed, the last freed first reused
+        ms.unmap_range(0x4000, 0x6000);
+        assert_eq!(ms.find_area(0x6000).map(|area| area.start_addr), Some(0x6000));
+        ms.push(MemoryArea::new(0xc000, 0xd000, attr, "bss"));
+        ms.page_table.edit(|pt| assert_eq!(pt.get_entry(0xc000).target(), 0x3000));
+        ms.page_table.edit(|pt| assert_eq!(pt.get_entry(0x6000).target(), 0x6000));
+
+        // Pages keep their offsets after the front is cut
+        ms.unmap_range(0x7000, 0x9000);
+        assert_eq!(ms.handle_fault(0xa000, false, None), FaultResult::Allocated);
+        ms.page_table.edit(|pt| assert_eq!(pt.read(0xa000), 2));
+        ms.unmap_range(0x0, 0x10000);
+        assert_eq!(ms.iter().count(), 0);
+    }
+
+    #[test]
+    fn large_pages() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_identity(0x3000, 0xd000, attr, "physical").large_pages());
+        ms.push(MemoryArea::new_physical(0x0, 0x2000, 0xe000, attr, "not aligned").large_pages());
+        ms.page_table.edit(|pt| {
+            assert!(!pt.is_large(0x3000) && !pt.is_large(0xc000) && !pt.is_large(0xe000));
+            assert!(pt.is_large(0x4000) && pt.is_large(0xb000));
+            assert_eq!(pt.get_entry(0x5000).target(), 0x4000);
+            pt.write(0x5123, 7);
+            assert_eq!(pt.read(0x5123), 7);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+
+        let area = ms.take_area(0x3000).unwrap();
+        let mut other = MemorySet::<MockInactivePageTable>::new();
+        assert!(other.attach_area(area).is_ok());
+        other.page_table.edit(|pt| {
+            assert!(pt.is_large(0x8000));
+            assert_eq!(pt.read(0x5123), 7);
+        });
+        assert_eq!(other.mprotect(0x5000, 0x1000, attr.readonly()), Err(()), "part of a large page");
+        other.unmap_range(0x8000, 0xd000);
+        other.page_table.edit(|pt| assert!(!pt.get_entry(0x8000).present() && pt.is_large(0x4000)));
+        assert_eq!(other.verify(), Ok(()));
+    }
+
+    #[test]
+    fn push_identity_huge() {
+        let attr = MemoryAttr::default();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push_identity_huge(0x1000, 0xe000, attr, "physmap");
+        ms.page_table.edit(|pt| {
+            // 3 normal pages, 2 large pages, then one normal page
+            for addr in (0x1000..0x4000).chain(0xd000..0xe000).step_by(PAGE_SIZE) {
+                assert!(!pt.is_large(addr));
+            }
+            for addr in (0x4000..0xc000).step_by(PAGE_SIZE) {
+                assert!(pt.is_large(addr));
+            }
+            for addr in (0x1000..0xe000).step_by(0x800) {
+                pt.write(addr, (addr >> 8) as u8);
+                assert_eq!(pt.read(addr), (addr >> 8) as u8);
+            }
+            // Entries of large pages are the ones of their first page
+            let targets = [(0x3fff, 0x3000), (0x4000, 0x4000), (0xbfff, 0x8000), (0xc000, 0xc000), (0xdfff, 0xd000)];
+            for &(addr, target) in targets.iter() {
+                assert_eq!(pt.get_entry(addr).target(), target, "{:#x}", addr);
+            }
+        });
+        assert_eq!(ms.verify(), Ok(()));
+
+        // The edges of normal pages can be unmapped alone
+        ms.unmap_range(0x1000, 0x4000);
+        ms.unmap_range(0xc000, 0xe000);
+        ms.page_table.edit(|pt| {
+            assert!(!pt.get_entry(0x3000).present() && !pt.get_entry(0xd000).present());
+            assert_eq!(pt.read(0x4800), 0x48);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(ms.remove_area(0x4000).map(|area| area.name()), Some("physmap"));
+        ms.page_table.edit(|pt| for addr in (0x1000..0xe000).step_by(PAGE_SIZE) {
+            assert!(!pt.get_entry(addr).present() && !pt.is_large(addr));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "unmapping part of a large page")]
+    fn unmap_part_of_large_page() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_identity(0x4000, 0x8000, MemoryAttr::default(), "physical").large_pages());
+        ms.unmap_range(0x5000, 0x6000);
+    }
+
+    #[test]
+    fn zero_page() {
+        let zero = MockInactivePageTable::zero_frame();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(MemoryArea::new_lazy(0x1000, 0x4000, MemoryAttr::default(), "heap"));
+        ms.push(MemoryArea::new_lazy(0x4000, 0x5000, MemoryAttr::default().readonly(), "bss"));
+
+        assert_eq!(ms.handle_fault(0x1000, false, None), FaultResult::Allocated);
+        assert_eq!(ms.handle_fault(0x2000, false, None), FaultResult::Allocated);
+        assert!(ms.handle_page_fault(0x4000));
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x1000).target(), zero);
+            assert_eq!(pt.get_entry(0x2000).target(), zero);
+            assert!(!pt.get_entry(0x1000).writable() && pt.get_entry(0x1000).writable_shared());
+            assert!(pt.get_entry(0x4000).readonly_shared());
+            assert_eq!(pt.read(0x1234), 0);
+        });
+        assert_eq!(ms.verify(), Ok(()));
+        assert_eq!(ms.handle_fault(0x4000, true, None), FaultResult::Unhandled, "read-only");
+
+        // Written after read, copied from the zero frame
+        assert_eq!(ms.handle_fault(0x1000, true, None), FaultResult::Copied);
+        // Written first, filled directly
+        assert_eq!(ms.handle_fault(0x3000, true, None), FaultResult::Allocated);
+        ms.page_table.edit(|pt| {
+            assert_eq!(pt.get_entry(0x1000).target(), 0x0);
+            assert_eq!(pt.get_entry(0x3000).target(), 0x1000);
+            pt.write(0x1234, 1);
+            assert_eq!(pt.read(0x2234), 0, "zero frame is not written");
+        });
+        assert_eq!(ms.verify(), Ok(()));
+
+        // Not freed when unmapped, nor when copied the last reference
+        let mut child = ms.clone_cow(None).unwrap();
+        child.page_table.edit(|pt| {
+            assert_eq!(copy_on_write::<MockInactivePageTable>(pt, 0x2000), Some(true));
+            assert_ne!(pt.get_entry(0x2000).target(), zero);
+        });
+        drop(child);
+        ms.unmap_range(0x2000, 0x3000);
+        drop(ms);
+    }
+
+    #[test]
+    fn lazy_kstack() {
+        let unused = MemorySet::<MockInactivePageTable>::new();
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        assert_eq!(MockInactivePageTable::stacks(), 0);
+        let top = ms.kstack_top().unwrap();
+        assert_eq!(ms.ensure_kstack().unwrap().top, top);
+        assert_eq!(MockInactivePageTable::stacks(), 1, "allocated once");
+        let child = ms.clone_cow(None).unwrap();
+        assert_eq!(MockInactivePageTable::stacks(), 1, "not by clone_cow");
+
+        drop(unused);
+        drop(child);
+        assert_eq!(MockInactivePageTable::stacks(), 1);
+        drop(ms);
+        assert_eq!(MockInactivePageTable::stacks(), 0);
+
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        let top = ms.kstack_top().unwrap();
+        let stack = ms.take_kstack().unwrap();
+        assert_eq!(stack.top, top);
+        drop(ms);
+        assert_eq!(MockInactivePageTable::stacks(), 1, "not freed with the memory set");
+        MockInactivePageTable::dealloc_stack(stack);
+    }
+}
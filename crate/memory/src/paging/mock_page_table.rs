@@ -1,13 +1,20 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use memory_set::{InactivePageTable, Stack};
+use refcount::FrameRefCount;
 use super::*;
 
 const PAGE_COUNT: usize = 16;
 const PAGE_SIZE: usize = 4096;
+/// The last frame, never allocated, see `InactivePageTable::zero_frame`.
+const ZERO_FRAME: PhysAddr = PAGE_SIZE * (PAGE_COUNT - 1);
 
 pub struct MockPageTable {
     entries: [MockEntry; PAGE_COUNT],
-    data: [u8; PAGE_SIZE * PAGE_COUNT],
     page_fault_handler: Option<PageFaultHandler>,
+    /// Page faults triggered by `read` and `write`, as `(addr, write)`.
+    faults: Vec<(VirtAddr, bool)>,
 }
 
 #[derive(Default, Copy, Clone)]
@@ -20,10 +27,20 @@ pub struct MockEntry {
     writable_shared: bool,
     readonly_shared: bool,
     swapped: bool,
+    hidden: bool,
+    user: bool,
+    execute: bool,
+    cache_disable: bool,
+    write_through: bool,
+    global: bool,
+    /// In a large page. Only the entry of its first page is used, the others are placeholders.
+    large: bool,
 }
 
 impl Entry for MockEntry {
-    fn update(&mut self) {}
+    fn update(&mut self) {
+        FLUSHES.with(|flushes| *flushes.borrow_mut() += 1);
+    }
     fn accessed(&self) -> bool { self.accessed }
     fn dirty(&self) -> bool { self.dirty }
     fn writable(&self) -> bool { self.writable }
@@ -46,10 +63,23 @@ impl Entry for MockEntry {
     }
     fn swapped(&self) -> bool { self.swapped }
     fn set_swapped(&mut self, value: bool) { self.swapped = value; }
-    fn user(&self) -> bool { unimplemented!() }
-    fn set_user(&mut self, value: bool) { unimplemented!() }
-    fn execute(&self) -> bool { unimplemented!() }
-    fn set_execute(&mut self, value: bool) { unimplemented!() }
+    fn hidden(&self) -> bool { self.hidden }
+    fn set_hidden(&mut self, value: bool) { self.hidden = value; }
+    fn user(&self) -> bool { self.user }
+    fn set_user(&mut self, value: bool) { self.user = value; }
+    fn execute(&self) -> bool { self.execute }
+    fn set_execute(&mut self, value: bool) { self.execute = value; }
+    fn global(&self) -> bool { self.global }
+    fn set_global(&mut self, value: bool) { self.global = value; }
+    fn set_cache_disable(&mut self, value: bool) { self.cache_disable = value; }
+    fn set_write_through(&mut self, value: bool) { self.write_through = value; }
+}
+
+impl MockEntry {
+    /// If caching is disabled, set by `Entry::set_cache_disable`.
+    pub fn cache_disable(&self) -> bool { self.cache_disable }
+    /// If writes go through the cache, set by `Entry::set_write_through`.
+    pub fn write_through(&self) -> bool { self.write_through }
 }
 
 type PageFaultHandler = Box<FnMut(&mut MockPageTable, VirtAddr)>;
@@ -57,76 +87,351 @@ type PageFaultHandler = Box<FnMut(&mut MockPageTable, VirtAddr)>;
 impl PageTable for MockPageTable {
     type Entry = MockEntry;
 
+    /// A run of 4 pages, so that it fits in the mock.
+    const LARGE_PAGE_SIZE: usize = PAGE_SIZE * 4;
+
     fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut Self::Entry {
         let entry = &mut self.entries[addr / PAGE_SIZE];
-        assert!(!entry.present);
+        assert!(!entry.present && !entry.large);
         entry.present = true;
         entry.writable = true;
         entry.target = target & !(PAGE_SIZE - 1);
         entry
     }
+    fn map_large(&mut self, addr: VirtAddr, target: PhysAddr) -> Option<&mut Self::Entry> {
+        let size = Self::LARGE_PAGE_SIZE;
+        assert!(addr % size == 0 && target % size == 0, "large page is not aligned");
+        for entry in self.entries[addr / PAGE_SIZE..(addr + size) / PAGE_SIZE].iter_mut() {
+            assert!(!entry.present && !entry.large);
+            entry.large = true;
+        }
+        let entry = &mut self.entries[addr / PAGE_SIZE];
+        entry.present = true;
+        entry.writable = true;
+        entry.target = target;
+        Some(entry)
+    }
+    fn is_large(&mut self, addr: VirtAddr) -> bool {
+        self.entries[addr / PAGE_SIZE].large
+    }
+    /// Counted as a single flush, like an architecture flushing the range at once.
+    fn flush_range(&mut self, start: VirtAddr, end: VirtAddr) {
+        FLUSHES.with(|flushes| *flushes.borrow_mut() += 1);
+        FLUSHED_RANGES.with(|ranges| ranges.borrow_mut().push((start, end)));
+    }
+    /// Counted as a flush, like an architecture flushing the page.
     fn unmap(&mut self, addr: VirtAddr) {
+        FLUSHES.with(|flushes| *flushes.borrow_mut() += 1);
+        self.unmap_no_flush(addr);
+    }
+    fn unmap_no_flush(&mut self, addr: VirtAddr) {
+        if self.is_large(addr) {
+            let start = addr / Self::LARGE_PAGE_SIZE * Self::LARGE_PAGE_SIZE;
+            assert!(self.entries[start / PAGE_SIZE].present);
+            for entry in self.entries[start / PAGE_SIZE..(start + Self::LARGE_PAGE_SIZE) / PAGE_SIZE].iter_mut() {
+                *entry = MockEntry::default();
+            }
+            return;
+        }
         let entry = &mut self.entries[addr / PAGE_SIZE];
         assert!(entry.present);
         entry.present = false;
     }
     fn get_entry(&mut self, addr: VirtAddr) -> &mut <Self as PageTable>::Entry {
-        &mut self.entries[addr / PAGE_SIZE]
+        &mut self.entries[self.entry_index(addr)]
     }
     fn get_page_slice_mut<'a,'b>(&'a mut self, addr: VirtAddr) -> &'b mut [u8] {
         self._read(addr);
         let pa = self.translate(addr) & !(PAGE_SIZE - 1);
-        let data = unsafe{ &mut *(&mut self.data as *mut [u8; PAGE_SIZE * PAGE_COUNT])};
-        &mut data[pa..pa + PAGE_SIZE]
+        &mut memory()[pa..pa + PAGE_SIZE]
     }
     fn read(&mut self, addr: usize) -> u8 {
         self._read(addr);
-        self.data[self.translate(addr)]
+        memory()[self.translate(addr)]
     }
     fn write(&mut self, addr: usize, data: u8) {
         self._write(addr);
-        self.data[self.translate(addr)] = data;
+        memory()[self.translate(addr)] = data;
     }
 }
 
 impl MockPageTable {
     pub fn new() -> Self {
-        use core::mem::uninitialized;
         MockPageTable {
             entries: [MockEntry::default(); PAGE_COUNT],
-            data: unsafe { uninitialized() },
             page_fault_handler: None,
+            faults: Vec::new(),
         }
     }
+    /// Set the handler called on a page fault, which should make the access valid.
+    ///
+    /// A read faults if the page is not present, a write also if it is not writable.
     pub fn set_handler(&mut self, page_fault_handler: PageFaultHandler) {
         self.page_fault_handler = Some(page_fault_handler);
     }
-    fn trigger_page_fault(&mut self, addr: VirtAddr) {
+    /// Returns and forgets the page faults triggered so far, as `(addr, write)`.
+    pub fn take_faults(&mut self) -> Vec<(VirtAddr, bool)> {
+        ::core::mem::replace(&mut self.faults, Vec::new())
+    }
+    /// Returns and resets the number of TLB flushes in the current test thread, see `FLUSHES`.
+    pub fn take_flushes() -> usize {
+        FLUSHES.with(|flushes| flushes.replace(0))
+    }
+    /// Returns and forgets the ranges passed to `flush_range` in the current test thread.
+    pub fn take_flushed_ranges() -> Vec<(VirtAddr, VirtAddr)> {
+        FLUSHED_RANGES.with(|ranges| ranges.replace(Vec::new()))
+    }
+    fn trigger_page_fault(&mut self, addr: VirtAddr, write: bool) {
+        self.faults.push((addr, write));
         // In order to call the handler with &mut self as an argument
         // We have to first take the handler out of self, finally put it back
-        let mut handler = self.page_fault_handler.take().unwrap();
+        let mut handler = self.page_fault_handler.take()
+            .unwrap_or_else(|| panic!("page fault @ {:#x} without a handler", addr));
         handler(self, addr);
         self.page_fault_handler = Some(handler);
     }
+    /// Index of the entry for `addr`, the first one of the large page if it is in one.
+    fn entry_index(&self, addr: VirtAddr) -> usize {
+        let i = addr / PAGE_SIZE;
+        let pages = <Self as PageTable>::LARGE_PAGE_SIZE / PAGE_SIZE;
+        match self.entries[i].large {
+            true => i / pages * pages,
+            false => i,
+        }
+    }
     fn translate(&self, addr: VirtAddr) -> PhysAddr {
-        let entry = &self.entries[addr / PAGE_SIZE];
+        let entry = &self.entries[self.entry_index(addr)];
         assert!(entry.present);
-        let pa = (entry.target & !(PAGE_SIZE - 1)) | (addr & (PAGE_SIZE - 1));
-        assert!(pa < self.data.len(), "Physical memory access out of range");
+        let offset = match entry.large {
+            true => addr % <Self as PageTable>::LARGE_PAGE_SIZE,
+            false => addr % PAGE_SIZE,
+        };
+        let pa = entry.target + offset;
+        assert!(pa < PAGE_SIZE * PAGE_COUNT, "Physical memory access out of range");
         pa
     }
     fn _read(&mut self, addr: VirtAddr) {
-        while !self.entries[addr / PAGE_SIZE].present {
-            self.trigger_page_fault(addr);
+        while !self.get_entry(addr).present {
+            self.trigger_page_fault(addr, false);
         }
-        self.entries[addr / PAGE_SIZE].accessed = true;
+        self.get_entry(addr).accessed = true;
     }
     fn _write(&mut self, addr: VirtAddr) {
-        while !(self.entries[addr / PAGE_SIZE].present && self.entries[addr / PAGE_SIZE].writable) {
-            self.trigger_page_fault(addr);
+        while !(self.get_entry(addr).present && self.get_entry(addr).writable) {
+            self.trigger_page_fault(addr, true);
+        }
+        self.get_entry(addr).accessed = true;
+        self.get_entry(addr).dirty = true;
+    }
+}
+
+/// Inactive page table backed by a `MockPageTable`, for testing `MemorySet`.
+///
+/// There is only one address space in a test process,
+/// so `activate` and `with` do not actually switch anything.
+pub struct MockInactivePageTable {
+    table: Box<MockPageTable>,
+    /// How many times `edit` has been called.
+    pub edit_count: usize,
+}
+
+/// Deterministic frame allocator over the physical memory of `MockPageTable`.
+///
+/// Hands out never used frames in strictly increasing order from 0 except the zero frame,
+/// and recycles freed frames in LIFO order before them,
+/// so that a test can predict exactly which frame each allocation gets.
+pub struct MockFrameAllocator {
+    next: PhysAddr,
+    freed: Vec<PhysAddr>,
+}
+
+impl MockFrameAllocator {
+    pub fn new() -> Self {
+        MockFrameAllocator { next: 0, freed: Vec::new() }
+    }
+    pub fn alloc(&mut self) -> Option<PhysAddr> {
+        if let Some(target) = self.freed.pop() {
+            return Some(target);
+        }
+        if self.next == ZERO_FRAME {
+            return None;
+        }
+        self.next += PAGE_SIZE;
+        Some(self.next - PAGE_SIZE)
+    }
+    pub fn dealloc(&mut self, target: PhysAddr) {
+        assert!(target < self.next && target % PAGE_SIZE == 0, "frame {:#x} is not allocated", target);
+        assert!(!self.freed.contains(&target), "frame {:#x} is already free", target);
+        self.freed.push(target);
+    }
+}
+
+thread_local! {
+    /// Physical memory of the mock, shared by all tables in the current test thread,
+    /// so that a frame has the same content in every table mapping it.
+    static MEMORY: *mut [u8; PAGE_SIZE * PAGE_COUNT] = Box::into_raw(Box::new([0; PAGE_SIZE * PAGE_COUNT]));
+    /// Frame allocator of the mock, shared by all tables in the current test thread.
+    static FRAME_ALLOCATOR: RefCell<MockFrameAllocator> = RefCell::new(MockFrameAllocator::new());
+    /// Reference counts of frames shared by copy-on-write.
+    static FRAME_REFS: RefCell<FrameRefCount> = RefCell::new(FrameRefCount::default());
+    /// Number of kernel stacks allocated and not freed in the current test thread.
+    static STACKS: RefCell<usize> = RefCell::new(0);
+    /// Number of `Entry::update`, `PageTable::flush_range` and `PageTable::unmap` calls
+    /// in the current test thread.
+    static FLUSHES: RefCell<usize> = RefCell::new(0);
+    /// Arguments of every `PageTable::flush_range` call in the current test thread.
+    static FLUSHED_RANGES: RefCell<Vec<(VirtAddr, VirtAddr)>> = RefCell::new(Vec::new());
+    /// Swap slots freed by `swap_free` in the current test thread.
+    static FREED_SLOTS: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+fn memory() -> &'static mut [u8; PAGE_SIZE * PAGE_COUNT] {
+    MEMORY.with(|&memory| unsafe { &mut *memory })
+}
+
+impl MockInactivePageTable {
+    /// Returns how many kernel stacks are allocated and not freed.
+    pub fn stacks() -> usize {
+        STACKS.with(|stacks| *stacks.borrow())
+    }
+
+    /// Returns and forgets the swap slots freed so far.
+    pub fn take_freed_slots() -> Vec<usize> {
+        FREED_SLOTS.with(|slots| slots.replace(Vec::new()))
+    }
+}
+
+impl InactivePageTable for MockInactivePageTable {
+    type Active = MockPageTable;
+
+    const LEVELS: usize = 4;
+
+    fn new() -> Self {
+        Self::new_bare()
+    }
+
+    fn new_bare() -> Self {
+        MockInactivePageTable {
+            table: Box::new(MockPageTable::new()),
+            edit_count: 0,
+        }
+    }
+
+    fn edit(&mut self, f: impl FnOnce(&mut Self::Active)) {
+        self.edit_count += 1;
+        f(&mut self.table);
+    }
+
+    unsafe fn activate(&self) {}
+
+    unsafe fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    fn token(&self) -> usize {
+        &*self.table as *const MockPageTable as usize
+    }
+
+    fn alloc_frame() -> Option<PhysAddr> {
+        FRAME_ALLOCATOR.with(|allocator| allocator.borrow_mut().alloc())
+    }
+
+    fn dealloc_frame(target: PhysAddr) {
+        FRAME_ALLOCATOR.with(|allocator| allocator.borrow_mut().dealloc(target));
+    }
+
+    fn zero_frame() -> PhysAddr {
+        ZERO_FRAME
+    }
+
+    fn frame_ref_inc(target: PhysAddr) {
+        FRAME_REFS.with(|refs| refs.borrow_mut().inc(target));
+    }
+
+    fn frame_ref_dec(target: PhysAddr) -> usize {
+        FRAME_REFS.with(|refs| refs.borrow_mut().dec(target))
+    }
+
+    fn frame_ref_count(target: PhysAddr) -> usize {
+        FRAME_REFS.with(|refs| refs.borrow().count(target))
+    }
+
+    fn alloc_stack() -> Option<Stack> {
+        STACKS.with(|stacks| *stacks.borrow_mut() += 1);
+        Some(Stack::new(0, 0))
+    }
+
+    fn dealloc_stack(_stack: Stack) {
+        STACKS.with(|stacks| *stacks.borrow_mut() -= 1);
+    }
+
+    fn swap_free(token: usize) {
+        FREED_SLOTS.with(|slots| slots.borrow_mut().push(token));
+    }
+
+    unsafe fn zero(&self, start: VirtAddr, end: VirtAddr) {
+        // The mock page table can not be activated, go through its mappings instead.
+        for addr in start..end {
+            memory()[self.table.translate(addr)] = 0;
         }
-        self.entries[addr / PAGE_SIZE].accessed = true;
-        self.entries[addr / PAGE_SIZE].dirty = true;
+    }
+}
+
+/// `MockInactivePageTable` with 3 levels like Sv39, for testing level dependent address ranges.
+pub struct MockInactivePageTable3(MockInactivePageTable);
+
+impl InactivePageTable for MockInactivePageTable3 {
+    type Active = MockPageTable;
+
+    const LEVELS: usize = 3;
+
+    fn new() -> Self {
+        MockInactivePageTable3(MockInactivePageTable::new())
+    }
+    fn new_bare() -> Self {
+        MockInactivePageTable3(MockInactivePageTable::new_bare())
+    }
+    fn edit(&mut self, f: impl FnOnce(&mut Self::Active)) {
+        self.0.edit(f)
+    }
+    unsafe fn activate(&self) {
+        self.0.activate()
+    }
+    unsafe fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.0.with(f)
+    }
+    fn token(&self) -> usize {
+        self.0.token()
+    }
+    fn alloc_frame() -> Option<PhysAddr> {
+        MockInactivePageTable::alloc_frame()
+    }
+    fn dealloc_frame(target: PhysAddr) {
+        MockInactivePageTable::dealloc_frame(target)
+    }
+    fn zero_frame() -> PhysAddr {
+        MockInactivePageTable::zero_frame()
+    }
+    fn frame_ref_inc(target: PhysAddr) {
+        MockInactivePageTable::frame_ref_inc(target)
+    }
+    fn frame_ref_dec(target: PhysAddr) -> usize {
+        MockInactivePageTable::frame_ref_dec(target)
+    }
+    fn frame_ref_count(target: PhysAddr) -> usize {
+        MockInactivePageTable::frame_ref_count(target)
+    }
+    fn alloc_stack() -> Option<Stack> {
+        MockInactivePageTable::alloc_stack()
+    }
+    fn dealloc_stack(stack: Stack) {
+        MockInactivePageTable::dealloc_stack(stack)
+    }
+    fn swap_free(token: usize) {
+        MockInactivePageTable::swap_free(token)
+    }
+    unsafe fn zero(&self, start: VirtAddr, end: VirtAddr) {
+        self.0.zero(start, end)
     }
 }
 
@@ -209,5 +514,35 @@ mod test {
         pt.unmap(0);
         pt.read(0);
         assert_eq!(*page_fault_count.borrow(), 2);
+        assert_eq!(pt.take_faults(), vec![(0x1000, true), (0, false)]);
+
+        // Writing a read-only page faults, and the access is retried after the handler
+        pt.get_entry(0).set_writable(false);
+        pt.get_entry(0).clear_accessed();
+        pt.set_handler(Box::new(|pt: &mut MockPageTable, addr: VirtAddr| {
+            pt.get_entry(addr).set_writable(true);
+        }));
+        pt.write(0, 1);
+        assert_eq!(pt.take_faults(), vec![(0, true)]);
+        assert!(pt.get_entry(0).accessed());
+        assert!(pt.get_entry(0).dirty());
+        assert_eq!(pt.take_faults(), vec![]);
+    }
+
+    #[test]
+    fn frame_allocation_order() {
+        let mut allocator = MockFrameAllocator::new();
+        assert_eq!(allocator.alloc(), Some(0x0));
+        assert_eq!(allocator.alloc(), Some(0x1000));
+        assert_eq!(allocator.alloc(), Some(0x2000));
+        allocator.dealloc(0x0);
+        allocator.dealloc(0x2000);
+        assert_eq!(allocator.alloc(), Some(0x2000));
+        assert_eq!(allocator.alloc(), Some(0x0));
+        assert_eq!(allocator.alloc(), Some(0x3000));
+        for _ in 4..PAGE_COUNT - 1 {
+            assert!(allocator.alloc().is_some());
+        }
+        assert_eq!(allocator.alloc(), None);
     }
-}
\ No newline at end of file
+}
@@ -4,11 +4,31 @@
 
 use super::*;
 #[cfg(test)]
-pub use self::mock_page_table::MockPageTable;
+pub use self::mock_page_table::{MockPageTable, MockInactivePageTable, MockInactivePageTable3, MockFrameAllocator};
 
 #[cfg(test)]
 mod mock_page_table;
 
+/// Bits of virtual address translated by each level of page table.
+#[cfg(target_pointer_width = "64")]
+pub const ENTRY_BITS: usize = 9;
+#[cfg(target_pointer_width = "32")]
+pub const ENTRY_BITS: usize = 10;
+
+/// End of user space with a page table of `levels` levels.
+///
+/// Addresses beyond what the levels translate must be a sign extension of it,
+/// so user space is the lower half: 47 bits for 4 levels, 38 bits for 3 levels on 64-bit.
+/// If all bits are translated, like Sv32, it is all the address space except the last page.
+pub fn user_space_end(levels: usize) -> VirtAddr {
+    use core::mem::size_of;
+    let bits = 12 + ENTRY_BITS * levels;
+    match bits < size_of::<VirtAddr>() * 8 {
+        true => 1 << (bits - 1),
+        false => !(PAGE_SIZE - 1),
+    }
+}
+
 /// Adaptable page table interface
 pub trait PageTable {
     type Entry: Entry;
@@ -16,10 +36,46 @@ pub trait PageTable {
     /// Maps a page to a frame, returns corresponding page entry.
     fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut Self::Entry;
 
-    /// Unmap a virtual page.
+    /// Size of a large page, mapped by an entry one level above the last.
+    ///
+    /// 2 MiB on x86_64 and Sv39, 4 MiB on Sv32.
+    const LARGE_PAGE_SIZE: usize = PAGE_SIZE << ENTRY_BITS;
+
+    /// Maps a large page to a frame of `LARGE_PAGE_SIZE`, both aligned to it,
+    /// returns corresponding page entry.
+    ///
+    /// Returns `None` if large pages are not supported, which is the default.
+    fn map_large(&mut self, _addr: VirtAddr, _target: PhysAddr) -> Option<&mut Self::Entry> {
+        None
+    }
+
+    /// If a virtual address is in a large page, see `map_large`.
+    fn is_large(&mut self, _addr: VirtAddr) -> bool {
+        false
+    }
+
+    /// Unmap a virtual page, or the whole large page containing it.
     fn unmap(&mut self, addr: VirtAddr);
 
-    /// Get the page entry of a virtual address.
+    /// Unmap like `unmap`, leaving the TLB to be flushed by `flush_range` after a bulk of them.
+    ///
+    /// The default is `unmap`, which flushes the page at once.
+    fn unmap_no_flush(&mut self, addr: VirtAddr) {
+        self.unmap(addr);
+    }
+
+    /// Flush the TLB for pages in `[start, end)`, whose entries are changed without `Entry::update`,
+    /// or unmapped by `unmap_no_flush`.
+    ///
+    /// The default updates the entry of every page, so their page tables must exist.
+    /// Architectures may flush the range at once, or the whole TLB if that is cheaper.
+    fn flush_range(&mut self, start: VirtAddr, end: VirtAddr) {
+        for page in Page::range_of(start, end) {
+            self.get_entry(page.start_address()).update();
+        }
+    }
+
+    /// Get the page entry of a virtual address, the one of the large page if it is in one.
     fn get_entry(&mut self, addr: VirtAddr) -> &mut Self::Entry;
 
     /// Get raw content in the page. Only used for testing with mock.
@@ -66,8 +122,31 @@ pub trait Entry {
     fn swapped(&self) -> bool;
     fn set_swapped(&mut self, value: bool);
 
+    /// Set on a page made not present by `MemoryAttr::hide`, which keeps its frame
+    fn hidden(&self) -> bool;
+    fn set_hidden(&mut self, value: bool);
+
     fn user(&self) -> bool;
     fn set_user(&mut self, value: bool);
     fn execute(&self) -> bool;
     fn set_execute(&mut self, value: bool);
+    /// Global pages are not flushed from the TLB when switching page tables
+    fn global(&self) -> bool;
+    fn set_global(&mut self, value: bool);
+
+    // For MMIO, where the arch supports it in page entries
+    fn set_cache_disable(&mut self, value: bool);
+    fn set_write_through(&mut self, value: bool);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn user_space() {
+        assert_eq!(user_space_end(4), 0x8000_0000_0000);
+        assert_eq!(user_space_end(3), 0x40_0000_0000);
+        assert_eq!(user_space_end(8), !(PAGE_SIZE - 1));
+    }
 }
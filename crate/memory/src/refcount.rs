@@ -0,0 +1,73 @@
+//! Reference counts of physical frames mapped by several pages.
+//!
+//! Used by copy-on-write fork and shared memory,
+//! to know when the last page mapping a frame goes away.
+//! It works with any frame allocator, see `InactivePageTable::frame_ref_inc`.
+
+use alloc::collections::BTreeMap;
+use super::*;
+
+/// Reference counts of frames, keyed by frame number.
+///
+/// A frame has 1 reference when allocated, from the page it is allocated for.
+/// Only frames with more than 1 reference are stored, so it costs nothing for private frames.
+#[derive(Debug, Default)]
+pub struct FrameRefCount {
+    refs: BTreeMap<usize, usize>,
+}
+
+impl FrameRefCount {
+    /// Adds a reference to frame `target`.
+    ///
+    /// Panics if the count overflows, which can only be a leak of references.
+    pub fn inc(&mut self, target: PhysAddr) {
+        let count = self.refs.entry(target / PAGE_SIZE).or_insert(1);
+        *count = count.checked_add(1).expect("frame reference count overflow");
+    }
+
+    /// Removes a reference to frame `target`, returns how many are left.
+    ///
+    /// The caller should free the frame when it returns 0.
+    /// The frame is then untracked, as if allocated again.
+    pub fn dec(&mut self, target: PhysAddr) -> usize {
+        let number = target / PAGE_SIZE;
+        let count = self.refs.remove(&number).unwrap_or(1) - 1;
+        if count > 1 {
+            self.refs.insert(number, count);
+        }
+        count
+    }
+
+    /// Returns how many references frame `target` has.
+    pub fn count(&self, target: PhysAddr) -> usize {
+        self.refs.get(&(target / PAGE_SIZE)).cloned().unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inc_dec() {
+        let mut refs = FrameRefCount::default();
+        assert_eq!(refs.count(0x1000), 1);
+        refs.inc(0x1000);
+        refs.inc(0x1fff);
+        assert_eq!(refs.count(0x1000), 3, "same frame");
+        assert_eq!(refs.count(0x2000), 1);
+        assert_eq!(refs.dec(0x1000), 2);
+        assert_eq!(refs.dec(0x1000), 1);
+        assert_eq!(refs.count(0x1000), 1);
+        assert_eq!(refs.dec(0x1000), 0);
+        assert!(refs.refs.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "frame reference count overflow")]
+    fn overflow() {
+        let mut refs = FrameRefCount::default();
+        refs.refs.insert(1, usize::max_value());
+        refs.inc(0x1000);
+    }
+}
@@ -0,0 +1,69 @@
+use access_sampler::AccessSampler;
+use super::*;
+
+/// Swap out the page not accessed for the longest time, by the ages from an `AccessSampler`.
+///
+/// It never sweeps the page table itself.
+/// The owner should call `sampler_mut().sample()` periodically.
+#[derive(Default)]
+pub struct AgingSwapManager {
+    sampler: AccessSampler,
+}
+
+impl SwapManager for AgingSwapManager {
+    fn tick(&mut self) {
+    }
+
+    fn push(&mut self, addr: usize) {
+        self.sampler.insert(addr);
+    }
+
+    fn remove(&mut self, addr: usize) {
+        self.sampler.remove(addr);
+    }
+
+    fn pop<T, S>(&mut self, _page_table: &mut T, _swapper: &mut S) -> Option<VirtAddr>
+        where T: PageTable, S: Swapper
+    {
+        let addr = self.sampler.oldest()?;
+        self.sampler.remove(addr);
+        Some(addr)
+    }
+}
+
+impl AgingSwapManager {
+    pub fn sampler(&self) -> &AccessSampler {
+        &self.sampler
+    }
+    pub fn sampler_mut(&mut self) -> &mut AccessSampler {
+        &mut self.sampler
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use swap::mock_swapper::MockSwapper;
+    use paging::MockPageTable;
+
+    #[test]
+    fn pop_oldest() {
+        let mut pt = MockPageTable::new();
+        let mut swapper = MockSwapper::default();
+        let mut manager = AgingSwapManager::default();
+        for i in 1..4 {
+            pt.map(i * PAGE_SIZE, i * PAGE_SIZE);
+            manager.push(i * PAGE_SIZE);
+        }
+        pt.read(0x1000);
+        pt.read(0x3000);
+        manager.sampler_mut().sample(&mut pt);
+        pt.read(0x1000);
+        manager.sampler_mut().sample(&mut pt);
+
+        assert_eq!(manager.pop(&mut pt, &mut swapper), Some(0x2000));
+        assert_eq!(manager.pop(&mut pt, &mut swapper), Some(0x3000));
+        assert_eq!(manager.pop(&mut pt, &mut swapper), Some(0x1000));
+        assert_eq!(manager.pop(&mut pt, &mut swapper), None);
+    }
+}
@@ -7,10 +7,13 @@ const PAGE_SIZE: usize = 4096;
 #[derive(Default)]
 pub struct MockSwapper {
     map: BTreeMap<usize, [u8; PAGE_SIZE]>,
+    /// Number of pages written by `swap_out` and `swap_update`.
+    pub writes: usize,
 }
 
 impl Swapper for MockSwapper {
     fn swap_out(&mut self, data: &[u8]) -> Result<usize, ()> {
+        self.writes += 1;
         let id = self.alloc_id();
         let mut slice: [u8; PAGE_SIZE] = unsafe{ uninitialized() };
         slice.copy_from_slice(data);
@@ -22,6 +25,7 @@ impl Swapper for MockSwapper {
         if !self.map.contains_key(&token) {
             return Err(());
         }
+        self.writes += 1;
         let mut slice: [u8; PAGE_SIZE] = unsafe{ uninitialized() };
         slice.copy_from_slice(data);
         self.map.insert(token, slice);
@@ -34,6 +38,13 @@ impl Swapper for MockSwapper {
         }
         Ok(())
     }
+    fn swap_read(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        match self.map.get(&token) {
+            Some(d) => data.copy_from_slice(d.as_ref()),
+            None => return Err(()),
+        }
+        Ok(())
+    }
 }
 
 impl MockSwapper {
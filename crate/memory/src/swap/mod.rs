@@ -6,9 +6,13 @@ use core::ops::{Deref, DerefMut};
 
 //pub use self::fifo::FifoSwapManager;
 pub use self::enhanced_clock::EnhancedClockSwapManager;
+pub use self::aging::AgingSwapManager;
+#[cfg(test)]
+pub use self::mock_swapper::MockSwapper;
 
 mod fifo;
 mod enhanced_clock;
+mod aging;
 #[cfg(test)]
 mod mock_swapper;
 
@@ -35,6 +39,28 @@ pub trait Swapper {
     fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()>;
     /// Recover data from device and deallocate the space.
     fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()>;
+    /// Recover data from device, keeping the space and the data in it,
+    /// so that it can be rewritten by `swap_update`, or left as is if the page is not changed.
+    fn swap_read(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()>;
+}
+
+/// Marks a page entry as swapped out to slot `token` of the swapper.
+///
+/// A swapped entry is not present, and its target holds `token * PAGE_SIZE`,
+/// so that the slot takes the place of the frame number and survives `Entry::set_target`.
+pub fn set_swap_slot(entry: &mut impl Entry, token: usize) {
+    entry.set_target(token * PAGE_SIZE);
+    entry.set_swapped(true);
+    entry.set_present(false);
+    entry.update();
+}
+
+/// Returns the swap slot of a page entry, or `None` if it is not swapped out.
+pub fn swap_slot(entry: &impl Entry) -> Option<usize> {
+    match entry.swapped() {
+        true => Some(entry.target() / PAGE_SIZE),
+        false => None,
+    }
 }
 
 /// Wrapper for page table, supporting swap functions
@@ -76,20 +102,14 @@ impl<T: PageTable, M: SwapManager, S: Swapper> SwapExt<T, M, S> {
         }
         let token = self.swapper.swap_out(data).map_err(|_| SwapError::IOError)?;
         let target = entry.target();
-        entry.set_target(token * PAGE_SIZE);
-        entry.set_swapped(true);
-        entry.set_present(false);
-        entry.update();
+        set_swap_slot(entry, token);
         Ok(target)
     }
     /// Map page of `addr` to `target`, then swap in the data.
     fn swap_in(&mut self, addr: VirtAddr, target: PhysAddr) -> Result<(), SwapError> {
         let token = {
             let entry = self.page_table.get_entry(addr);
-            if !entry.swapped() {
-                return Err(SwapError::NotSwapped);
-            }
-            let token = entry.target() / PAGE_SIZE;
+            let token = swap_slot(entry).ok_or(SwapError::NotSwapped)?;
             entry.set_target(target);
             entry.set_swapped(false);
             entry.set_present(true);
@@ -112,10 +132,17 @@ impl<T: PageTable, M: SwapManager, S: Swapper> SwapExt<T, M, S> {
     }
 }
 
+#[derive(Debug, Eq, PartialEq)]
 pub enum SwapError {
     AlreadySwapped,
     NotSwapped,
     NoSwapped,
+    /// The page is not a private page of an anonymous area.
+    NotSwappable,
+    /// The page is swapped out, but there is no swapper to swap it in.
+    NoSwapper,
+    /// No frame to swap the page in.
+    OutOfMemory,
     IOError,
 }
 
@@ -13,6 +13,7 @@ extern crate std;
 
 pub mod processor;
 pub mod scheduler;
+pub mod signal;
 pub mod thread;
 mod util;
 mod event_hub;
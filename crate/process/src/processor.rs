@@ -1,16 +1,30 @@
 //! Process manager implementation.
 
-use alloc::{boxed::Box, collections::BTreeMap};
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, string::String};
 use scheduler::*;
+use signal::{self, SigAction, SigActions, Signal, SignalSet};
 use event_hub::EventHub;
 use util::GetMut2;
 use core::fmt::Debug;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug)]
 pub struct Process<T> {
     pid: Pid,
     parent: Pid,
     status: Status,
+    /// Pinned processes never move to another processor.
+    pinned: bool,
+    /// Signals sent to this process but not delivered yet.
+    pending_signals: SignalSet,
+    /// Handlers of signals installed by the user.
+    sigactions: SigActions,
+    /// Ticks to run before being preempted, or `None` to let the scheduler decide.
+    time_slice: Option<usize>,
+    /// Ticks left in the current slice, only used if `time_slice` is set.
+    rest_slice: usize,
+    /// The parent blocked by `vfork_wait` until this process stops using its memory.
+    vfork_parent: Option<Pid>,
     context: T,
 }
 
@@ -29,15 +43,55 @@ pub enum Status {
 pub trait Context: Debug {
     unsafe fn switch(&mut self, target: &mut Self);
     fn new_kernel(entry: extern fn(usize) -> !, arg: usize) -> Self;
+
+    /// Human readable name, like the program of a user process. Kernel threads may have none.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Bytes of memory resident in frames, like `MemorySet::rss`.
+    fn rss(&self) -> usize {
+        0
+    }
+}
+
+/// State of a process shown in `ProcInfo`, like the one of `ps`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProcState {
+    Running,
+    Ready,
+    /// Waiting for a child, or sleeping.
+    Sleeping,
+    /// Exited but not waited for by its parent yet.
+    Zombie,
+}
+
+/// Information about a process for debugging, see `Processor_::process_info`.
+#[derive(Debug, Clone)]
+pub struct ProcInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: Option<String>,
+    pub state: ProcState,
+    /// Resident memory in bytes, see `Context::rss`.
+    pub rss: usize,
 }
 
 /// Process manager.
 pub struct Processor_<T: Context, S: Scheduler> {
     procs: BTreeMap<Pid, Process<T>>,
     current_pid: Pid,
+    /// Adopts orphans, and is never reaped.
+    init_pid: Pid,
+    /// Pids of processes reaped here, reused oldest first, see `alloc_pid`.
+    free_pids: VecDeque<Pid>,
     event_hub: EventHub<Event>,
     /// Choose what on next schedule ?
     next: Option<Pid>,
+    /// Run when nothing else is ready. It is never in the scheduler.
+    idle_pid: Option<Pid>,
+    /// Preemption is deferred while it is not zero.
+    preempt_count: usize,
     // WARNING: if MAX_PROCESS_NUM is too large, will cause stack overflow
     scheduler: S,
 }
@@ -54,21 +108,32 @@ impl<T> Process<T> {
 // TODO: 除schedule()外的其它函数，应该只设置进程状态，不应调用schedule
 impl<T: Context, S: Scheduler> Processor_<T, S> {
     pub fn new(init_context: T, scheduler: S) -> Self {
+        let pid = next_pid();
         let init_proc = Process {
-            pid: 0,
-            parent: 0,
+            pid,
+            parent: pid,
             status: Status::Running,
+            pinned: true,
+            pending_signals: SignalSet::default(),
+            sigactions: SigActions::default(),
+            time_slice: None,
+            rest_slice: 0,
+            vfork_parent: None,
             context: init_context,
         };
         Processor_ {
             procs: {
                 let mut map = BTreeMap::<Pid, Process<T>>::new();
-                map.insert(0, init_proc);
+                map.insert(pid, init_proc);
                 map
             },
-            current_pid: 0,
+            current_pid: pid,
+            init_pid: pid,
+            free_pids: VecDeque::new(),
             event_hub: EventHub::new(),
             next: None,
+            idle_pid: None,
+            preempt_count: 0,
             scheduler,
         }
     }
@@ -77,27 +142,58 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         self.scheduler.set_priority(self.current_pid, priority);
     }
 
+    /// Let process `pid` run `ticks` ticks before being preempted.
+    ///
+    /// By default the scheduler decides when to preempt on every tick.
+    pub fn set_time_slice(&mut self, pid: Pid, ticks: usize) {
+        assert_ne!(ticks, 0, "time slice must not be zero");
+        let process = self.get_mut(pid);
+        process.time_slice = Some(ticks);
+        process.rest_slice = ticks;
+    }
+
+    /// Defer preemption until the matching `preempt_enable`. Can be nested.
+    pub fn preempt_disable(&mut self) {
+        self.preempt_count += 1;
+    }
+
+    /// Allow preemption again when every `preempt_disable` is matched.
+    ///
+    /// A reschedule requested meanwhile is kept, and done on next `schedule`.
+    pub fn preempt_enable(&mut self) {
+        assert_ne!(self.preempt_count, 0, "unbalanced preempt_enable");
+        self.preempt_count -= 1;
+    }
+
+    /// Whether `schedule` should be called before returning from interrupt.
+    ///
+    /// The current process is only preempted if its slice is exhausted and
+    /// preemption is enabled. It is always switched out if it stops running.
+    pub fn need_schedule(&self) -> bool {
+        match self.get(self.current_pid).status {
+            Status::Running => false,
+            Status::Ready => self.preempt_count == 0,
+            _ => true,
+        }
+    }
+
     pub fn set_reschedule(&mut self) {
         let pid = self.current_pid;
         self.set_status(pid, Status::Ready);
     }
 
-    fn alloc_pid(&self) -> Pid {
-        let mut next: Pid = 0;
-        for &i in self.procs.keys() {
-            if i != next {
-                return next;
-            } else {
-                next = i + 1;
-            }
-        }
-        return next;
+    /// Reuses a pid reaped on this processor if any, or allocates a new one by `next_pid`.
+    ///
+    /// A reaped pid is free everywhere, so processes on different CPUs still never share a pid.
+    fn alloc_pid(&mut self) -> Pid {
+        self.free_pids.pop_front().unwrap_or_else(next_pid)
     }
 
     fn set_status(&mut self, pid: Pid, status: Status) {
         let status0 = self.get(pid).status.clone();
         match (&status0, &status) {
             (&Status::Ready, &Status::Ready) => return,
+            _ if self.idle_pid == Some(pid) => {}
             (&Status::Ready, _) => self.scheduler.remove(pid),
             (_, &Status::Ready) => self.scheduler.insert(pid),
             _ => {}
@@ -110,7 +206,7 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
     /// Handle events.
     pub fn tick(&mut self) {
         let current_pid = self.current_pid;
-        if self.scheduler.tick(current_pid) {
+        if self.tick_slice(current_pid) {
             self.set_reschedule();
         }
         self.event_hub.tick();
@@ -130,6 +226,27 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         }
     }
 
+    /// Consume a tick of `pid`'s slice, returns whether it is exhausted.
+    ///
+    /// The idle process is rescheduled every tick, to run anything ready.
+    fn tick_slice(&mut self, pid: Pid) -> bool {
+        if self.idle_pid == Some(pid) {
+            return true;
+        }
+        let process = self.get_mut(pid);
+        match process.time_slice {
+            Some(ticks) => {
+                process.rest_slice = process.rest_slice.saturating_sub(1);
+                if process.rest_slice == 0 {
+                    process.rest_slice = ticks;
+                    return true;
+                }
+                false
+            }
+            None => self.scheduler.tick(pid),
+        }
+    }
+
     pub fn get_time(&self) -> usize {
         self.event_hub.get_time()
     }
@@ -140,6 +257,12 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
             pid,
             parent: self.current_pid,
             status: Status::Ready,
+            pinned: false,
+            pending_signals: SignalSet::default(),
+            sigactions: SigActions::default(),
+            time_slice: None,
+            rest_slice: 0,
+            vfork_parent: None,
             context,
         };
         self.scheduler.insert(pid);
@@ -147,13 +270,77 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         pid
     }
 
+    /// Set the idle process, which runs when no other process is ready.
+    ///
+    /// It is pinned, never exits, and is nobody's child, so nobody can wait for it.
+    pub fn set_idle(&mut self, context: T) -> Pid {
+        assert!(self.idle_pid.is_none(), "idle process already set");
+        let pid = self.alloc_pid();
+        let process = Process {
+            pid,
+            parent: pid,
+            status: Status::Ready,
+            pinned: true,
+            pending_signals: SignalSet::default(),
+            sigactions: SigActions::default(),
+            time_slice: None,
+            rest_slice: 0,
+            vfork_parent: None,
+            context,
+        };
+        self.procs.insert(pid, process);
+        self.idle_pid = Some(pid);
+        pid
+    }
+
+    /// Pin a process to this processor, so that it will never be stolen.
+    pub fn pin(&mut self, pid: Pid) {
+        self.get_mut(pid).pinned = true;
+    }
+
+    /// Whether this processor has nothing to do except pinned processes (like idle).
+    pub fn is_idle(&self) -> bool {
+        !self.procs.values().any(|p| !p.pinned &&
+            (p.status == Status::Ready || p.status == Status::Running))
+    }
+
+    /// Steal a ready process from `other`, returns its pid.
+    ///
+    /// Pinned processes and the one `other` is going to run next are never stolen.
+    /// Neither are processes with their parent or a child on `other`,
+    /// as `wait` only finds processes on its own processor.
+    pub fn steal_from(&mut self, other: &mut Self) -> Option<Pid> {
+        let pid = other.procs.values()
+            .find(|p| !p.pinned && p.status == Status::Ready && other.next != Some(p.pid)
+                && !other.has_relatives(p.pid))
+            .map(|p| p.pid)?;
+        other.scheduler.remove(pid);
+        let process = other.procs.remove(&pid).unwrap();
+        self.scheduler.insert(pid);
+        self.procs.insert(pid, process);
+        debug!("steal process {}", pid);
+        Some(pid)
+    }
+
+    /// Whether the parent or a child of `pid` is on this processor.
+    fn has_relatives(&self, pid: Pid) -> bool {
+        let parent = self.get(pid).parent;
+        (parent != pid && self.procs.contains_key(&parent))
+            || self.procs.values().any(|p| p.parent == pid && p.pid != pid)
+    }
+
     /// Called every interrupt end
     /// Do schedule ONLY IF current status != Running
+    ///
+    /// Falls back to the idle process if nothing is ready.
     pub fn schedule(&mut self) {
         if self.get(self.current_pid).status == Status::Running {
             return;
         }
-        let pid = self.next.take().unwrap_or_else(|| self.scheduler.select().unwrap());
+        let pid = self.next.take()
+            .or_else(|| self.scheduler.select())
+            .or(self.idle_pid)
+            .expect("nothing to run and no idle process");
         self.switch_to(pid);
     }
 
@@ -177,7 +364,12 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         assert_ne!(from.status, Status::Running);
         assert_eq!(to.status, Status::Ready);
         to.status = Status::Running;
-        self.scheduler.remove(pid);
+        if let Some(ticks) = to.time_slice {
+            to.rest_slice = ticks;
+        }
+        if self.idle_pid != Some(pid) {
+            self.scheduler.remove(pid);
+        }
 
         info!("switch from {} to {} {:x?}", pid0, pid, to.context);
         unsafe { from.context.switch(&mut to.context); }
@@ -189,9 +381,36 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
     fn get_mut(&mut self, pid: Pid) -> &mut Process<T> {
         self.procs.get_mut(&pid).unwrap()
     }
+    /// Returns information about process `pid`, or `None` if it is not on this processor.
+    pub fn process_info(&self, pid: Pid) -> Option<ProcInfo> {
+        let process = self.procs.get(&pid)?;
+        let state = match process.status {
+            Status::Running => ProcState::Running,
+            Status::Ready => ProcState::Ready,
+            Status::Waiting(_) | Status::Sleeping => ProcState::Sleeping,
+            Status::Exited(_) => ProcState::Zombie,
+        };
+        Some(ProcInfo {
+            pid,
+            parent: process.parent,
+            name: process.context.name().map(String::from),
+            state,
+            rss: process.context.rss(),
+        })
+    }
+
+    /// Pids of all processes on this processor, in ascending order.
+    pub fn pids<'a>(&'a self) -> impl Iterator<Item=Pid> + 'a {
+        self.procs.keys().cloned()
+    }
+
     pub fn current_context(&self) -> &T {
         &self.get(self.current_pid).context
     }
+    pub fn current_context_mut(&mut self) -> &mut T {
+        let pid = self.current_pid;
+        &mut self.get_mut(pid).context
+    }
     pub fn current_pid(&self) -> Pid {
         self.current_pid
     }
@@ -202,7 +421,13 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
 
     pub fn exit(&mut self, pid: Pid, error_code: ErrorCode) {
         info!("{} exit, code: {}", pid, error_code);
+        self.release_vfork(pid);
         self.set_status(pid, Status::Exited(error_code));
+        // Orphans are adopted by the init process
+        let init_pid = self.init_pid;
+        for child in self.procs.values_mut().filter(|p| p.parent == pid && p.pid != pid) {
+            child.parent = init_pid;
+        }
         if let Some(waiter) = self.find_waiter(pid) {
             info!("  then wakeup {}", waiter);
             self.set_status(waiter, Status::Ready);
@@ -210,6 +435,76 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         }
     }
 
+    /// Block the current process until its child `child` stops using the memory borrowed from it,
+    /// like the parent of `vfork`. The child releases it by `release_vfork`, or by exiting.
+    ///
+    /// The child is pinned meanwhile, so that it is not stolen from this processor,
+    /// which has to wake the parent.
+    pub fn vfork_wait(&mut self, child: Pid) {
+        let parent = self.current_pid;
+        {
+            let child = self.get_mut(child);
+            assert_eq!(child.parent, parent, "only the parent can wait for a vfork child");
+            child.vfork_parent = Some(parent);
+            child.pinned = true;
+        }
+        while self.get(child).vfork_parent.is_some() {
+            self.set_status(parent, Status::Sleeping);
+            self.schedule(); // yield
+        }
+    }
+
+    /// Wake the parent blocked by `vfork_wait` for `pid`, if any,
+    /// as `pid` no longer uses its memory, e.g. after `exec`.
+    pub fn release_vfork(&mut self, pid: Pid) {
+        let parent = match self.get_mut(pid).vfork_parent.take() {
+            Some(parent) => parent,
+            None => return,
+        };
+        self.get_mut(pid).pinned = false;
+        info!("{} release vfork parent {}", pid, parent);
+        if self.get(parent).status == Status::Sleeping {
+            self.set_status(parent, Status::Ready);
+        }
+    }
+
+    /// Queue `signal` to process `pid`.
+    ///
+    /// It will be delivered by `handle_signals` next time `pid` returns to user mode.
+    pub fn send_signal(&mut self, pid: Pid, signal: Signal) {
+        info!("send signal {} to {}", signal, pid);
+        self.get_mut(pid).pending_signals.insert(signal);
+    }
+
+    /// Set the user handler of `signal` for process `pid`, see `SigActions::set`.
+    pub fn set_sigaction(&mut self, pid: Pid, signal: Signal, action: Option<SigAction>) -> Result<(), ()> {
+        self.get_mut(pid).sigactions.set(signal, action)
+    }
+
+    /// Copy signal handlers of the current process to `child`, as `fork` does.
+    pub fn inherit_sigactions(&mut self, child: Pid) {
+        let sigactions = self.get(self.current_pid).sigactions.clone();
+        self.get_mut(child).sigactions = sigactions;
+    }
+
+    /// Deliver pending signals of the current process.
+    /// Should be called right before returning to user mode.
+    ///
+    /// Returns a signal and its handler, which the caller should run in user mode.
+    /// Otherwise the signal takes its default action, which is to terminate the process,
+    /// and the caller should `schedule` after this.
+    pub fn handle_signals(&mut self) -> Option<(Signal, SigAction)> {
+        let pid = self.current_pid;
+        let signal = self.get_mut(pid).pending_signals.pop()?;
+        if let Some(action) = self.get(pid).sigactions.get(signal) {
+            info!("{} handle signal {} at {:#x}", pid, signal, action.handler);
+            return Some((signal, action));
+        }
+        info!("{} killed by signal {}", pid, signal);
+        self.exit(pid, signal::exit_code(signal));
+        None
+    }
+
     pub fn sleep(&mut self, pid: Pid, time: usize) {
         self.set_status(pid, Status::Sleeping);
         self.event_hub.push(time, Event::Wakeup(pid));
@@ -221,31 +516,53 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         self.set_status(pid, Status::Ready);
     }
 
-    /// Let current process wait for another
+    /// Let current process wait for another.
+    /// `pid` = 0 means any child.
     pub fn current_wait_for(&mut self, pid: Pid) -> WaitResult {
-        info!("current {} wait for {:?}", self.current_pid, pid);
-        if self.procs.values().filter(|&p| p.parent == self.current_pid).next().is_none() {
+        let current_pid = self.current_pid;
+        self.wait(current_pid, match pid {
+            0 => None,
+            _ => Some(pid),
+        })
+    }
+
+    /// Block `parent` until `child` (or any child if `None`) exits, then reap it.
+    ///
+    /// Returns the pid and exit code of the reaped child, whose `Context` is dropped here.
+    /// Returns immediately if such a child has already exited,
+    /// or `NotExist` if `parent` has no such child.
+    /// `parent` must be the current process, since it may sleep.
+    pub fn wait(&mut self, parent: Pid, child: Option<Pid>) -> WaitResult {
+        info!("{} wait for {:?}", parent, child);
+        assert_eq!(parent, self.current_pid, "only the current process can wait");
+        let has_child = match child {
+            Some(pid) => self.procs.get(&pid).map_or(false, |p| p.parent == parent && p.pid != parent),
+            None => self.procs.values().any(|p| p.parent == parent && p.pid != parent),
+        };
+        if !has_child {
             return WaitResult::NotExist;
         }
-        let pid = self.try_wait(pid).unwrap_or_else(|| {
-            let current_pid = self.current_pid;
-            self.set_status(current_pid, Status::Waiting(pid));
+        let pid = loop {
+            if let Some(pid) = self.try_wait(parent, child) {
+                break pid;
+            }
+            self.set_status(parent, Status::Waiting(child.unwrap_or(0)));
             self.schedule(); // yield
-            self.try_wait(pid).unwrap()
-        });
+        };
         let exit_code = self.get(pid).exit_code().unwrap();
-        info!("{} wait end and remove {}", self.current_pid, pid);
+        info!("{} wait end and remove {}", parent, pid);
         self.procs.remove(&pid);
+        self.free_pids.push_back(pid);
         WaitResult::Ok(pid, exit_code)
     }
 
-    /// Try to find a exited wait target
-    fn try_wait(&mut self, pid: Pid) -> Option<Pid> {
-        match pid {
-            0 => self.procs.values()
-                .find(|&p| p.parent == self.current_pid && p.exit_code().is_some())
+    /// Try to find a exited child of `parent` to reap
+    fn try_wait(&self, parent: Pid, child: Option<Pid>) -> Option<Pid> {
+        match child {
+            None => self.procs.values()
+                .find(|&p| p.parent == parent && p.pid != parent && p.exit_code().is_some())
                 .map(|p| p.pid),
-            _ => self.get(pid).exit_code().map(|_| pid),
+            Some(pid) => self.get(pid).exit_code().map(|_| pid),
         }
     }
 
@@ -257,11 +574,19 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
     }
 }
 
+/// Allocates a pid never used before, globally instead of per processor,
+/// so that processes on different CPUs never share a pid. 0 is never allocated,
+/// as it means any child in `current_wait_for`.
+fn next_pid() -> Pid {
+    static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+    NEXT_PID.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Debug)]
 pub enum WaitResult {
     /// The target process is exited with `ErrorCode`.
     Ok(Pid, ErrorCode),
-    /// The target process is not exist.
+    /// The target process is not exist, or is not a child of the waiter.
     NotExist,
 }
 
@@ -276,4 +601,110 @@ impl<T: Context> GetMut2<Pid> for BTreeMap<Pid, Process<T>> {
     fn get_mut(&mut self, id: Pid) -> &mut Process<T> {
         self.get_mut(&id).unwrap()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct MockContext;
+
+    impl Context for MockContext {
+        /// Nothing to switch, the test keeps running whatever process is current.
+        unsafe fn switch(&mut self, _target: &mut Self) {}
+        fn new_kernel(_entry: extern fn(usize) -> !, _arg: usize) -> Self {
+            MockContext
+        }
+    }
+
+    type MockProcessor = Processor_<MockContext, StrideScheduler>;
+
+    fn processor() -> MockProcessor {
+        MockProcessor::new(MockContext, StrideScheduler::new(5))
+    }
+
+    #[test]
+    fn pid_reuse() {
+        let mut p1 = processor();
+        let mut p2 = processor();
+        let init = p1.current_pid();
+        let a = p1.add(MockContext);
+        let b = p1.add(MockContext);
+        p1.exit(a, 0);
+        p1.exit(b, 0);
+        match p1.wait(init, None) {
+            WaitResult::Ok(pid, 0) => assert_eq!(pid, a),
+            result => panic!("{:?}", result),
+        }
+        match p1.wait(init, None) {
+            WaitResult::Ok(pid, 0) => assert_eq!(pid, b),
+            result => panic!("{:?}", result),
+        }
+        match p1.wait(init, None) {
+            WaitResult::NotExist => {}
+            result => panic!("{:?}", result),
+        }
+
+        // Reused oldest first, and only on the processor which reaped it
+        let c = p2.add(MockContext);
+        assert!(c != a && c != b);
+        assert_eq!(p1.add(MockContext), a);
+        assert_eq!(p1.add(MockContext), b);
+        let d = p1.add(MockContext);
+        assert!(d != a && d != b && d != c && d != init);
+    }
+
+    #[test]
+    fn init_pid_unique() {
+        let mut p1 = processor();
+        let mut p2 = processor();
+        let pids = [p1.current_pid(), p2.current_pid(), p1.set_idle(MockContext), p2.set_idle(MockContext)];
+        for (i, pid) in pids.iter().enumerate() {
+            assert_ne!(*pid, 0, "0 means any child");
+            assert!(!pids[i + 1..].contains(pid));
+        }
+    }
+
+    #[test]
+    fn steal_pinned() {
+        let mut p1 = processor();
+        let mut p2 = processor();
+        p1.set_idle(MockContext);
+        assert!(p1.is_idle());
+        assert_eq!(p2.steal_from(&mut p1), None, "init and idle are pinned");
+        let child = p1.add(MockContext);
+        assert!(!p1.is_idle());
+        p1.pin(child);
+        assert!(p1.is_idle());
+        assert_eq!(p2.steal_from(&mut p1), None);
+    }
+
+    #[test]
+    fn steal_then_wait() {
+        let mut p1 = processor();
+        let mut p2 = processor();
+        let init = p1.current_pid();
+        let child = p1.add(MockContext);
+        assert_eq!(p2.steal_from(&mut p1), None, "parent on p1");
+
+        // A child of `child` keeps it from being stolen too
+        p1.set_reschedule();
+        p1.schedule();
+        assert_eq!(p1.current_pid(), child);
+        let grandchild = p1.add(MockContext);
+        assert_eq!(p2.steal_from(&mut p1), None);
+        p1.exit(grandchild, 0);
+        match p1.wait(child, Some(grandchild)) {
+            WaitResult::Ok(pid, 0) => assert_eq!(pid, grandchild),
+            result => panic!("{:?}", result),
+        }
+
+        p1.exit(child, 3);
+        p1.schedule();
+        assert_eq!(p1.current_pid(), init);
+        match p1.wait(init, Some(child)) {
+            WaitResult::Ok(pid, 3) => assert_eq!(pid, child),
+            result => panic!("{:?}", result),
+        }
+    }
+}
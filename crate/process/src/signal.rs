@@ -0,0 +1,83 @@
+//! Unix-like signals.
+
+use alloc::collections::BTreeMap;
+use processor::ErrorCode;
+
+/// Signal number, same as Linux.
+pub type Signal = usize;
+
+pub const SIGILL: Signal = 4;
+pub const SIGTRAP: Signal = 5;
+pub const SIGBUS: Signal = 7;
+pub const SIGFPE: Signal = 8;
+pub const SIGKILL: Signal = 9;
+pub const SIGSEGV: Signal = 11;
+pub const SIGSYS: Signal = 31;
+
+/// Exit code of a process terminated by `signal`.
+pub fn exit_code(signal: Signal) -> ErrorCode {
+    0x100 | signal
+}
+
+/// A user handler of a signal.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SigAction {
+    /// Address of the handler, called as `handler(signal)`.
+    pub handler: usize,
+    /// Address the handler returns to, which should call `sigreturn`.
+    pub restorer: usize,
+}
+
+/// User handlers of a process by signal. Other signals take the default action.
+#[derive(Debug, Default, Clone)]
+pub struct SigActions(BTreeMap<Signal, SigAction>);
+
+impl SigActions {
+    pub fn get(&self, signal: Signal) -> Option<SigAction> {
+        self.0.get(&signal).cloned()
+    }
+    /// Set the handler of `signal`, or restore the default action if `None`.
+    ///
+    /// Returns `Err` if `signal` is invalid or `SIGKILL`, which can't be caught.
+    pub fn set(&mut self, signal: Signal, action: Option<SigAction>) -> Result<(), ()> {
+        if signal == 0 || signal >= 64 || signal == SIGKILL {
+            return Err(());
+        }
+        match action {
+            Some(action) => self.0.insert(signal, action),
+            None => self.0.remove(&signal),
+        };
+        Ok(())
+    }
+}
+
+/// A set of signals, as a bitmask.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct SignalSet(u64);
+
+impl SignalSet {
+    pub fn insert(&mut self, signal: Signal) {
+        assert!(signal > 0 && signal < 64, "invalid signal: {}", signal);
+        self.0 |= 1 << signal;
+    }
+    pub fn remove(&mut self, signal: Signal) {
+        assert!(signal > 0 && signal < 64, "invalid signal: {}", signal);
+        self.0 &= !(1 << signal);
+    }
+    pub fn contains(&self, signal: Signal) -> bool {
+        assert!(signal > 0 && signal < 64, "invalid signal: {}", signal);
+        self.0 & (1 << signal) != 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+    /// Remove and return the lowest numbered signal in the set.
+    pub fn pop(&mut self) -> Option<Signal> {
+        if self.is_empty() {
+            return None;
+        }
+        let signal = self.0.trailing_zeros() as Signal;
+        self.remove(signal);
+        Some(signal)
+    }
+}
@@ -7,6 +7,10 @@ pub const KERNEL_OFFSET: usize = 0;
 pub const KERNEL_PML4: usize = 0x8000_0000 >> 22;
 pub const KERNEL_HEAP_OFFSET: usize = 0x8020_0000;
 pub const KERNEL_HEAP_SIZE: usize = 0x0020_0000;
+// Kernel broadcast window, where mappings are shared by all page tables
+pub const KERNEL_BROADCAST_PML4: usize = 0x3fd;
+pub const KERNEL_BROADCAST_OFFSET: usize = KERNEL_BROADCAST_PML4 << 22;
+pub const KERNEL_BROADCAST_SIZE: usize = P2_SIZE;
 pub const MEMORY_OFFSET: usize = 0x8000_0000;
 pub const MEMORY_END: usize = 0x8080_0000;
 pub const USER_STACK_OFFSET: usize = 0x70000000;
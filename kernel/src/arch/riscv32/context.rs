@@ -26,7 +26,8 @@ impl TrapFrame {
         tf.sstatus.set_spp(sstatus::SPP::Supervisor);
         tf
     }
-    fn new_user_thread(entry_addr: usize, sp: usize) -> Self {
+    /// Trap frame returning to a user program at `entry_addr`. RV32 programs are all 32-bit.
+    pub fn new_user_thread(entry_addr: usize, sp: usize, _is32: bool) -> Self {
         use core::mem::zeroed;
         let mut tf: Self = unsafe { zeroed() };
         tf.x[2] = sp;
@@ -37,7 +38,21 @@ impl TrapFrame {
         tf
     }
     pub fn is_user(&self) -> bool {
-        unimplemented!()
+        self.sstatus.spp() == sstatus::SPP::User
+    }
+    pub fn user_sp(&self) -> usize {
+        self.x[2]
+    }
+    /// Make the user program call `handler(signal)` on the stack at `sp`, returning to `restorer`.
+    ///
+    /// Everything is passed in registers, so `write_user` is unused.
+    pub fn call_signal_handler(&mut self, handler: usize, signal: usize, restorer: usize, sp: usize,
+                               _write_user: &mut FnMut(usize, &[u8]) -> Result<(), ()>) -> Result<(), ()> {
+        self.x[1] = restorer;   // ra
+        self.x[2] = sp & !0xf;
+        self.x[10] = signal;    // a0
+        self.sepc = handler;
+        Ok(())
     }
 }
 
@@ -147,9 +162,12 @@ impl Context {
     pub unsafe fn new_user_thread(entry_addr: usize, ustack_top: usize, kstack_top: usize, is32: bool, cr3: usize) -> Self {
         InitStack {
             context: ContextData::new(cr3),
-            tf: TrapFrame::new_user_thread(entry_addr, ustack_top),
+            tf: TrapFrame::new_user_thread(entry_addr, ustack_top, is32),
         }.push_at(kstack_top)
     }
+    /// Copies `tf` onto the new kernel stack at `kstack_top`, to return from the syscall with 0 in `a0`.
+    ///
+    /// `sepc` of `tf` must be past the `ecall` already.
     pub unsafe fn new_fork(tf: &TrapFrame, kstack_top: usize, cr3: usize) -> Self {
         InitStack {
             context: ContextData::new(cr3),
@@ -160,4 +178,17 @@ impl Context {
             },
         }.push_at(kstack_top)
     }
+    /// Like `new_fork`, but returns to `entry_addr` with `arg` on another user stack.
+    pub unsafe fn new_clone(tf: &TrapFrame, entry_addr: usize, arg: usize, ustack_top: usize, kstack_top: usize, cr3: usize) -> Self {
+        InitStack {
+            context: ContextData::new(cr3),
+            tf: {
+                let mut tf = tf.clone();
+                tf.sepc = entry_addr;
+                tf.x[2] = ustack_top; // sp
+                tf.x[10] = arg; // a0
+                tf
+            },
+        }.push_at(kstack_top)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,15 @@
+//! CPU related functions
+
+/// Returns the id of the current hart.
+///
+/// Only a single hart is booted on riscv32 now.
+pub fn id() -> usize {
+    0
+}
+
+/// Stall the hart until the next interrupt.
+///
+/// Interrupts must be enabled, or it will never wake up.
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi" :::: "volatile"); }
+}
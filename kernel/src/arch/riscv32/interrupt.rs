@@ -47,12 +47,39 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         Trap::Interrupt(I::SupervisorTimer) => timer(),
         Trap::Exception(E::IllegalInstruction) => illegal_inst(tf),
         Trap::Exception(E::UserEnvCall) => syscall(tf),
-        _ => ::trap::error(tf),
+        Trap::Exception(E::InstructionPageFault) => page_fault(tf, false),
+        Trap::Exception(E::LoadPageFault) => page_fault(tf, false),
+        Trap::Exception(E::StorePageFault) => page_fault(tf, true),
+        Trap::Exception(E::InstructionFault) |
+        Trap::Exception(E::LoadFault) |
+        Trap::Exception(E::StoreFault) => ::trap::fault(tf, ::process::SIGSEGV),
+        Trap::Exception(cause) => ::trap::error(tf, signal_of(cause)),
+        _ => ::trap::error(tf, ::process::SIGKILL),
     }
-    ::trap::before_return();
+    ::trap::before_return(tf);
     trace!("Interrupt end");
 }
 
+/// Signal for an exception which is an error, see `trap::error`.
+fn signal_of(cause: scause::Exception) -> ::process::Signal {
+    use super::riscv::register::scause::Exception as E;
+    use process::{SIGBUS, SIGILL, SIGKILL, SIGSEGV, SIGTRAP};
+    match cause {
+        E::InstructionFault | E::LoadFault | E::StoreFault |
+        E::InstructionPageFault | E::LoadPageFault | E::StorePageFault => SIGSEGV,
+        E::InstructionMisaligned | E::StoreMisaligned => SIGBUS,
+        E::IllegalInstruction => SIGILL,
+        E::Breakpoint => SIGTRAP,
+        _ => SIGKILL,
+    }
+}
+
+fn page_fault(tf: &mut TrapFrame, write: bool) {
+    let addr = tf.sbadaddr;
+    error!("\nEXCEPTION: Page Fault @ {:#x}, cause: {:?}", addr, tf.scause.cause());
+    ::trap::page_fault(tf, addr, write);
+}
+
 fn timer() {
     ::trap::timer();
     super::timer::set_next();
@@ -66,7 +93,7 @@ fn syscall(tf: &mut TrapFrame) {
 
 fn illegal_inst(tf: &mut TrapFrame) {
     if !emulate_mul_div(tf) {
-        ::trap::error(tf);
+        ::trap::fault(tf, ::process::SIGILL);
     }
 }
 
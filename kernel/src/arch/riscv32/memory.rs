@@ -1,7 +1,7 @@
 //! Memory initialization.
 
 use core::slice;
-use memory::{active_table, FRAME_ALLOCATOR, init_heap, MemoryArea, MemoryAttr, MemorySet, Stack};
+use memory::{active_table, FRAME_ALLOCATOR, init_heap, MemoryArea, MemoryAttr, MemorySet, register_kernel_mapping, Stack};
 use super::riscv::{addr::*, register::sstatus};
 use ucore_memory::PAGE_SIZE;
 
@@ -16,6 +16,9 @@ pub fn init() {
     init_frame_allocator();
     remap_the_kernel();
     init_heap();
+    register_kernel_mappings();
+    super::paging::init_broadcast_window();
+    super::paging::init_zero_frame();
 }
 
 /// Init FrameAllocator and add kernel heap into allocator.
@@ -39,23 +42,38 @@ fn init_frame_allocator() {
 /// map kernel datas in page table and activate new page table.
 fn remap_the_kernel() {
     use consts::{KERNEL_HEAP_OFFSET, KERNEL_HEAP_SIZE};
-    let kstack = Stack {
-        top: bootstacktop as usize,
-        bottom: bootstack as usize + PAGE_SIZE,
-    };
+    let kstack = Stack::new(bootstack as usize + PAGE_SIZE, bootstacktop as usize);
     static mut SPACE: [u8; 0x1000] = [0; 0x1000];
     let mut ms = unsafe { MemorySet::new_from_raw_space(&mut SPACE, kstack) };
-    ms.push(MemoryArea::new_identity(0x10000000, 0x10000008, MemoryAttr::default(), "serial"));
-    ms.push(MemoryArea::new_identity(stext as usize, etext as usize, MemoryAttr::default().execute().readonly(), "text"));
-    ms.push(MemoryArea::new_identity(sdata as usize, edata as usize, MemoryAttr::default(), "data"));
-    ms.push(MemoryArea::new_identity(srodata as usize, erodata as usize, MemoryAttr::default().readonly(), "rodata"));
-    ms.push(MemoryArea::new_identity(sbss as usize, ebss as usize, MemoryAttr::default(), "bss"));
+    ms.set_enforce_wx(true);
+    for area in kernel_areas().iter() {
+        ms.push(*area);
+    }
     unsafe { ms.activate(); }
     use core::mem::forget;
     forget(ms);
     info!("kernel remap end");
 }
 
+/// Register kernel areas, so that every new page table maps them. Heap must be ready.
+fn register_kernel_mappings() {
+    for area in kernel_areas().iter() {
+        register_kernel_mapping(*area);
+    }
+}
+
+/// Kernel areas are the same in all page tables, so they are global.
+fn kernel_areas() -> [MemoryArea; 5] {
+    let attr = MemoryAttr::default().global();
+    [
+        MemoryArea::new_identity(0x10000000, 0x10000008, attr, "serial"),
+        MemoryArea::new_identity(stext as usize, etext as usize, attr.execute().readonly(), "text"),
+        MemoryArea::new_identity(sdata as usize, edata as usize, attr, "data"),
+        MemoryArea::new_identity(srodata as usize, erodata as usize, attr.readonly(), "rodata"),
+        MemoryArea::new_identity(sbss as usize, ebss as usize, attr, "bss"),
+    ]
+}
+
 // Symbols provided by linker script
 extern {
     fn stext();
@@ -8,6 +8,7 @@ pub mod timer;
 pub mod paging;
 pub mod memory;
 pub mod compiler_rt;
+pub mod cpu;
 
 #[no_mangle]
 pub extern fn rust_main() -> ! {
@@ -1,8 +1,9 @@
 //! riscv32 page table implementations.
 
-use consts::{KERNEL_PML4, RECURSIVE_PAGE_PML4};
+use consts::{KERNEL_BROADCAST_PML4, KERNEL_PML4, RECURSIVE_PAGE_PML4};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 // Depends on kernel
-use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame};
+use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame, dealloc_stack, frame_refs, kernel_mappings};
 use super::riscv::addr::*;
 use super::riscv::asm::{sfence_vma, sfence_vma_all};
 use super::riscv::paging::{Mapper, PageTable as RvPageTable, PageTableEntry, PageTableFlags as EF, RecursivePageTable};
@@ -54,6 +55,28 @@ impl PageTable for ActivePageTable {
         flush.flush();
     }
 
+    fn unmap_no_flush(&mut self, addr: usize) {
+        let page = Page::of_addr(VirtAddr::new(addr));
+        let (_, flush) = self.0.unmap(page).unwrap();
+        flush.ignore();
+    }
+
+    /// Flushes page by page, or the whole TLB if there are more than `FLUSH_ALL_PAGES`.
+    fn flush_range(&mut self, start: usize, end: usize) {
+        const FLUSH_ALL_PAGES: usize = 32;
+        let start = start & !(PAGE_SIZE - 1);
+        if end <= start {
+            return;
+        }
+        if (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES {
+            sfence_vma_all();
+            return;
+        }
+        for addr in (start..end).step_by(PAGE_SIZE) {
+            sfence_vma(0, VirtAddr::new(addr));
+        }
+    }
+
     fn get_entry(&mut self, addr: usize) -> &mut PageEntry {
         let page = Page::of_addr(VirtAddr::new(addr));
         let _ = self.0.translate_page(page);
@@ -138,10 +161,25 @@ impl Entry for PageEntry {
     fn clear_shared(&mut self) { self.as_flags().remove(EF::RESERVED1 | EF::RESERVED2); }
     fn swapped(&self) -> bool { unimplemented!() }
     fn set_swapped(&mut self, value: bool) { unimplemented!() }
+    // Both reserved bits are taken by copy-on-write. The entry is not valid, so READABLE is free to use.
+    fn hidden(&self) -> bool { self.0.flags().contains(EF::READABLE) && !self.0.flags().contains(EF::VALID) }
+    fn set_hidden(&mut self, value: bool) {
+        if value {
+            self.as_flags().remove(EF::VALID);
+            self.as_flags().insert(EF::READABLE);
+        } else if self.hidden() {
+            self.as_flags().remove(EF::READABLE);
+        }
+    }
     fn user(&self) -> bool { self.0.flags().contains(EF::USER) }
     fn set_user(&mut self, value: bool) { self.as_flags().set(EF::USER, value); }
     fn execute(&self) -> bool { self.0.flags().contains(EF::EXECUTABLE) }
     fn set_execute(&mut self, value: bool) { self.as_flags().set(EF::EXECUTABLE, value); }
+    fn global(&self) -> bool { self.0.flags().contains(EF::GLOBAL) }
+    fn set_global(&mut self, value: bool) { self.as_flags().set(EF::GLOBAL, value); }
+    // Sv32 entries have no cache bits, caching is decided by the physical memory attributes
+    fn set_cache_disable(&mut self, value: bool) {}
+    fn set_write_through(&mut self, value: bool) {}
 }
 
 impl PageEntry {
@@ -159,6 +197,9 @@ pub struct InactivePageTable0 {
 impl InactivePageTable for InactivePageTable0 {
     type Active = ActivePageTable;
 
+    // Sv32
+    const LEVELS: usize = 2;
+
     fn new() -> Self {
         let mut pt = Self::new_bare();
         pt.map_kernel();
@@ -202,7 +243,7 @@ impl InactivePageTable for InactivePageTable0 {
         }
     }
 
-    unsafe fn with(&self, f: impl FnOnce()) {
+    unsafe fn with<R>(&self, f: impl FnOnce() -> R) -> R {
         let old_frame = satp::read().frame();
         let new_frame = self.p2_frame.clone();
         debug!("switch table {:x?} -> {:x?}", old_frame, new_frame);
@@ -210,12 +251,13 @@ impl InactivePageTable for InactivePageTable0 {
             satp::set(satp::Mode::Sv32, 0, new_frame);
             sfence_vma_all();
         }
-        f();
+        let ret = f();
         debug!("switch table {:x?} -> {:x?}", new_frame, old_frame);
         if old_frame != new_frame {
             satp::set(satp::Mode::Sv32, 0, old_frame);
             sfence_vma_all();
         }
+        ret
     }
 
     fn token(&self) -> usize {
@@ -230,26 +272,79 @@ impl InactivePageTable for InactivePageTable0 {
         dealloc_frame(target)
     }
 
-    fn alloc_stack() -> Stack {
+    fn zero_frame() -> usize {
+        let frame = ZERO_FRAME.load(Ordering::Relaxed);
+        assert_ne!(frame, 0, "zero frame is not initialized");
+        frame
+    }
+
+    fn frame_ref_inc(target: usize) {
+        frame_refs().inc(target);
+    }
+
+    fn frame_ref_dec(target: usize) -> usize {
+        frame_refs().dec(target)
+    }
+
+    fn frame_ref_count(target: usize) -> usize {
+        frame_refs().count(target)
+    }
+
+    fn alloc_stack() -> Option<Stack> {
         alloc_stack()
     }
+
+    fn dealloc_stack(stack: Stack) {
+        dealloc_stack(stack)
+    }
 }
 
 impl InactivePageTable0 {
-    /// Maps kernel space.
+    /// Maps kernel space, as registered in `kernel_mappings`.
+    /// The broadcast window is shared by copying its root entry.
     fn map_kernel(&mut self) {
         let table = unsafe { &mut *ROOT_PAGE_TABLE };
-        let e0 = table[0x40];
-        let e1 = table[KERNEL_PML4];
-        assert!(!e1.is_unused());
-
-        self.edit(|_| {
-            table[0x40] = e0;
-            table[KERNEL_PML4].set(e1.frame(), EF::VALID | EF::GLOBAL);
+        let ewindow = table[KERNEL_BROADCAST_PML4];
+        assert!(!ewindow.is_unused(), "kernel broadcast window is not initialized");
+        let mappings = kernel_mappings();
+        assert!(mappings.iter().next().is_some(), "kernel mappings are not registered");
+        self.edit(|pt| {
+            table[KERNEL_BROADCAST_PML4] = ewindow;
+            mappings.map_into::<Self>(pt);
         });
     }
 }
 
+/// Allocates the second level table of the kernel broadcast window, and sets it to the active root table.
+///
+/// Must be called at boot before any page table is created,
+/// then all page tables share it, see `memory::broadcast_kernel_mapping`.
+pub fn init_broadcast_window() {
+    let frame = alloc_frame().map(|addr| Frame::of_addr(PhysAddr::new(addr as u32)))
+        .expect("failed to allocate frame");
+    active_table().with_temporary_map(&frame, |_, table: &mut RvPageTable| {
+        table.zero();
+    });
+    let table = unsafe { &mut *ROOT_PAGE_TABLE };
+    table[KERNEL_BROADCAST_PML4].set(frame, EF::VALID);
+    sfence_vma_all();
+}
+
+/// Frame filled with zeros, see `InactivePageTable::zero_frame`. 0 until initialized.
+static ZERO_FRAME: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Allocates and zeroes the zero frame, shared by pages of lazy areas until written.
+///
+/// Must be called at boot before any user process is created.
+pub fn init_zero_frame() {
+    let frame = alloc_frame().map(|addr| Frame::of_addr(PhysAddr::new(addr as u32)))
+        .expect("failed to allocate frame");
+    active_table().with_temporary_map(&frame, |_, table: &mut RvPageTable| {
+        table.zero();
+    });
+    ZERO_FRAME.store(frame.start_address().as_u32() as usize, Ordering::Relaxed);
+}
+
 impl Drop for InactivePageTable0 {
     /// Release p4_frame after inactive page table is no longer used.
     fn drop(&mut self) {
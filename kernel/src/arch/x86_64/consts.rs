@@ -25,6 +25,12 @@ pub const KERNEL_HEAP_PML4: usize = (KERNEL_HEAP_OFFSET & PML4_MASK) / PML4_SIZE
 /// Size of kernel heap
 pub const KERNEL_HEAP_SIZE: usize = 8 * 1024 * 1024; // 8 MB
 
+/// Offset to kernel broadcast window, where mappings are shared by all page tables
+pub const KERNEL_BROADCAST_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
+pub const KERNEL_BROADCAST_PML4: usize = (KERNEL_BROADCAST_OFFSET & PML4_MASK) / PML4_SIZE;
+/// Size of kernel broadcast window
+pub const KERNEL_BROADCAST_SIZE: usize = PML4_SIZE;
+
 pub const MEMORY_OFFSET: usize = 0;
 
 /// Offset to kernel percpu variables
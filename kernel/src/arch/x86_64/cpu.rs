@@ -1,6 +1,21 @@
-//! QEMU related functions, currently unused
+//! CPU related functions
 
-/// Exit qemu
+/// Returns the id of the current CPU, which is its local APIC id.
+///
+/// Always 0 for now, as the local APIC is not enabled and other CPUs are never started.
+pub fn id() -> usize {
+    use super::driver::apic::lapic_id;
+    lapic_id() as usize
+}
+
+/// Halt the CPU until the next interrupt.
+///
+/// Interrupts must be enabled, or it will never wake up.
+pub fn wait_for_interrupt() {
+    unsafe { asm!("hlt" :::: "volatile"); }
+}
+
+/// Exit qemu (currently unused)
 /// See: https://wiki.osdev.org/Shutdown
 /// Must run qemu with `-device isa-debug-exit`
 /// The error code is `value written to 0x501` *2 +1, so it should be odd
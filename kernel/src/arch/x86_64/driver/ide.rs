@@ -7,6 +7,8 @@ use spin::Mutex;
 lazy_static! {
     pub static ref DISK0: LockedIde = LockedIde(Mutex::new(DmaController::new(0)));
     pub static ref DISK1: LockedIde = LockedIde(Mutex::new(DmaController::new(1)));
+    /// Swap space, see `swap::with_swapper`.
+    pub static ref DISK2: LockedIde = LockedIde(Mutex::new(DmaController::new(2)));
 }
 pub const BLOCK_SIZE: usize = 512;
 
@@ -64,7 +66,7 @@ impl DmaController
         //ide_devices[ideno].valid = 0;
 
         //let iobase = IO_BASE(ideno);
-        let iobase = CHANNELS[if ideno > 2 { 1 } else { 0 }].0;
+        let iobase = CHANNELS[(ideno >> 1) as usize].0;
 
         /* wait device ready */
         self.ide_wait_ready(iobase, 0);
@@ -153,8 +155,8 @@ impl DmaController
     fn ide_read_secs<'a>(&'a self, ideno: u8, secno: u64, dst: &'a mut [u32], nsecs: u8) -> Result<usize, ()> {
         //assert(nsecs <= MAX_NSECS && VALID_IDE(ideno));
         //assert(secno < MAX_DISK_NSECS && secno + nsecs <= MAX_DISK_NSECS);
-        let iobase = CHANNELS[if ideno > 2 { 1 } else { 0 }].0;
-        let ioctrl = CHANNELS[if ideno > 2 { 1 } else { 0 }].1;
+        let iobase = CHANNELS[(ideno >> 1) as usize].0;
+        let ioctrl = CHANNELS[(ideno >> 1) as usize].1;
 
         //ide_wait_ready(iobase, 0);
 
@@ -200,8 +202,8 @@ impl DmaController
     fn ide_write_secs<'a>(&'a self, ideno: u8, secno: u64, src: &'a [u32], nsecs: u8) -> Result<usize, ()> {
         //assert(nsecs <= MAX_NSECS && VALID_IDE(ideno));
         //assert(secno < MAX_DISK_NSECS && secno + nsecs <= MAX_DISK_NSECS);
-        let iobase = CHANNELS[if ideno > 2 { 1 } else { 0 }].0;
-        let ioctrl = CHANNELS[if ideno > 2 { 1 } else { 0 }].1;
+        let iobase = CHANNELS[(ideno >> 1) as usize].0;
+        let ioctrl = CHANNELS[(ideno >> 1) as usize].1;
 
         //ide_wait_ready(iobase, 0);
 
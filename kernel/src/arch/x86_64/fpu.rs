@@ -0,0 +1,150 @@
+//! Lazy switching of FPU/SIMD state.
+//!
+//! The kernel is built without SSE, so only user programs use the FPU.
+//! Its registers are left to the context which used it last, the owner on this CPU.
+//! On a switch to another context, the FPU is disabled by setting `CR0.TS`,
+//! then the first FPU instruction faults with device not available, see `device_not_available`.
+//! Only then the registers are saved to the owner and loaded from the current context,
+//! so contexts never using the FPU, like kernel threads, never pay for it.
+//!
+//! Contexts never migrate, as other CPUs are never started, see `smp`.
+//! So there is no IPI to ask another CPU to save a state held in its registers.
+
+use alloc::{boxed::Box, vec::Vec};
+use arch::cpu;
+use consts::MAX_CPU_NUM;
+use core::fmt::{Debug, Error, Formatter};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// FPU/SIMD registers of a context, in the format of `fxsave`.
+#[repr(C, align(16))]
+pub struct FpuState {
+    area: [u8; 512],
+    /// The CPU whose registers hold the latest state, or `NO_CPU` if it is in `area`.
+    cpu: AtomicUsize,
+}
+
+const NO_CPU: usize = usize::max_value();
+
+impl FpuState {
+    /// The state after `fninit`, with all exceptions masked.
+    pub fn new() -> Box<Self> {
+        let mut state = Box::new(FpuState { area: [0; 512], cpu: AtomicUsize::new(NO_CPU) });
+        // FCW
+        state.area[0..2].copy_from_slice(&[0x7f, 0x03]);
+        // MXCSR
+        state.area[24..28].copy_from_slice(&[0x80, 0x1f, 0, 0]);
+        state
+    }
+
+    fn addr(&self) -> usize {
+        self as *const _ as usize
+    }
+}
+
+impl Debug for FpuState {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self.cpu.load(Ordering::Relaxed) {
+            NO_CPU => write!(f, "FpuState(saved)"),
+            cpu => write!(f, "FpuState(on CPU {})", cpu),
+        }
+    }
+}
+
+lazy_static! {
+    /// State of the context running on each CPU, as an address.
+    static ref CURRENT: Vec<AtomicUsize> = (0..MAX_CPU_NUM).map(|_| AtomicUsize::new(0)).collect();
+    /// State held in the FPU registers of each CPU, as an address, or 0 if there is none.
+    static ref OWNER: Vec<AtomicUsize> = (0..MAX_CPU_NUM).map(|_| AtomicUsize::new(0)).collect();
+}
+
+/// Enables SSE for user programs, and disables the FPU until the first use.
+///
+/// Must be called once on every CPU.
+pub fn init() {
+    unsafe {
+        let mut cr0: usize;
+        asm!("mov %cr0, $0" : "=r" (cr0));
+        // Clear EM, set MP and TS
+        cr0 = cr0 & !(1 << 2) | (1 << 1) | (1 << 3);
+        asm!("mov $0, %cr0" :: "r" (cr0) :: "volatile");
+        let mut cr4: usize;
+        asm!("mov %cr4, $0" : "=r" (cr4));
+        // Set OSFXSR and OSXMMEXCPT
+        cr4 |= (1 << 9) | (1 << 10);
+        asm!("mov $0, %cr4" :: "r" (cr4) :: "volatile");
+    }
+}
+
+/// Called by `Context::switch` before switching to the context with `state`.
+///
+/// The FPU is disabled unless `state` is still in its registers.
+pub fn switch_to(state: &FpuState) {
+    let id = cpu::id();
+    assert_not_elsewhere(state, id);
+    CURRENT[id].store(state.addr(), Ordering::Relaxed);
+    unsafe {
+        match OWNER[id].load(Ordering::Relaxed) == state.addr() {
+            true => clts(),
+            false => set_ts(),
+        }
+    }
+}
+
+/// Called when the context with `state` is dropped, so that its state is never saved again.
+pub fn release(state: &FpuState) {
+    use super::interrupt::no_interrupt;
+    no_interrupt(|| {
+        let id = cpu::id();
+        assert_not_elsewhere(state, id);
+        if OWNER[id].compare_and_swap(state.addr(), 0, Ordering::Relaxed) == state.addr() {
+            state.cpu.store(NO_CPU, Ordering::Relaxed);
+        }
+        CURRENT[id].compare_and_swap(state.addr(), 0, Ordering::Relaxed);
+    });
+}
+
+/// Called on the device not available fault, which is the first FPU instruction after a switch.
+///
+/// Saves the registers to the owner, and loads the ones of the current context.
+pub fn device_not_available() {
+    let id = cpu::id();
+    let current = CURRENT[id].load(Ordering::Relaxed);
+    assert_ne!(current, 0, "FPU used without a context");
+    unsafe {
+        clts();
+        let owner = OWNER[id].load(Ordering::Relaxed);
+        if owner == current {
+            return;
+        }
+        if owner != 0 {
+            save(&*(owner as *const FpuState));
+        }
+        let current = &*(current as *const FpuState);
+        asm!("fxrstor ($0)" :: "r" (current.area.as_ptr()) :: "volatile");
+        current.cpu.store(id, Ordering::Release);
+        OWNER[id].store(current.addr(), Ordering::Relaxed);
+    }
+}
+
+/// Checks that `state` is saved or held by the current CPU `id`, as contexts never migrate.
+fn assert_not_elsewhere(state: &FpuState, id: usize) {
+    let cpu = state.cpu.load(Ordering::Acquire);
+    assert!(cpu == NO_CPU || cpu == id, "FPU state held by CPU {}", cpu);
+}
+
+/// Saves the registers to `state`. The FPU must be enabled.
+unsafe fn save(state: &FpuState) {
+    asm!("fxsave ($0)" :: "r" (state.area.as_ptr()) : "memory" : "volatile");
+    state.cpu.store(NO_CPU, Ordering::Release);
+}
+
+unsafe fn clts() {
+    asm!("clts" :::: "volatile");
+}
+
+unsafe fn set_ts() {
+    let cr0: usize;
+    asm!("mov %cr0, $0" : "=r" (cr0));
+    asm!("mov $0, %cr0" :: "r" (cr0 | (1 << 3)) :: "volatile");
+}
@@ -66,6 +66,7 @@
 
 use super::consts::*;
 use super::TrapFrame;
+use process::{SIGFPE, SIGILL, SIGSEGV};
 
 global_asm!(include_str!("trap.asm"));
 global_asm!(include_str!("vector.asm"));
@@ -78,6 +79,7 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         T_BRKPT => breakpoint(),
         T_DBLFLT => double_fault(tf),
         T_PGFLT => page_fault(tf),
+        T_DEVICE => device_not_available(),
         T_IRQ0...63 => {
             let irq = tf.trap_num as u8 - T_IRQ0;
             match irq {
@@ -98,10 +100,12 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         T_SWITCH_TOU => to_user(tf),
         T_SYSCALL => syscall(tf),
         T_SYSCALL32 => syscall32(tf),
-        T_DIVIDE | T_GPFLT | T_ILLOP => error(tf),
+        T_DIVIDE | T_FPERR | T_SIMDERR => ::trap::fault(tf, SIGFPE),
+        T_GPFLT => ::trap::fault(tf, SIGSEGV),
+        T_ILLOP => ::trap::fault(tf, SIGILL),
         _ => panic!("Unhandled interrupt {:x}", tf.trap_num),
     }
-    ::trap::before_return();
+    ::trap::before_return(tf);
 }
 
 fn breakpoint() {
@@ -118,12 +122,14 @@ fn page_fault(tf: &mut TrapFrame) {
     unsafe { asm!("mov %cr2, $0" : "=r" (addr)); }
     error!("\nEXCEPTION: Page Fault @ {:#x}, code: {:#x}", addr, tf.error_code);
 
-    use memory::page_fault_handler;
-    if page_fault_handler(addr) {
-        return;
-    }
+    // Bit 1 of error code: caused by a write
+    ::trap::page_fault(tf, addr, tf.error_code & 0x2 != 0);
+}
 
-    error(tf);
+fn device_not_available() {
+    trace!("\nEXCEPTION: Device Not Available");
+    use arch::fpu;
+    fpu::device_not_available();
 }
 
 fn keyboard() {
@@ -177,10 +183,6 @@ fn syscall32(tf: &mut TrapFrame) {
     tf.rax = ret as usize;
 }
 
-fn error(tf: &TrapFrame) {
-    ::trap::error(tf);
-}
-
 #[no_mangle]
 pub extern fn set_return_rsp(tf: &TrapFrame) {
     use arch::gdt::Cpu;
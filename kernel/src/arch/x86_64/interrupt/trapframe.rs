@@ -1,5 +1,8 @@
 //! Trapframe and context definitions.
 
+use alloc::boxed::Box;
+use arch::fpu::{self, FpuState};
+
 #[derive(Debug, Clone, Default)]
 #[repr(C)]
 pub struct TrapFrame {
@@ -48,7 +51,8 @@ impl TrapFrame {
         tf.rflags = 0x282;
         tf
     }
-    fn new_user_thread(entry_addr: usize, rsp: usize, is32: bool) -> Self {
+    /// Trap frame returning to a user program at `entry_addr`, in compatibility mode if `is32`.
+    pub fn new_user_thread(entry_addr: usize, rsp: usize, is32: bool) -> Self {
         use arch::gdt;
         let mut tf = TrapFrame::default();
         tf.cs = if is32 { gdt::UCODE32_SELECTOR.0 } else { gdt::UCODE_SELECTOR.0 } as usize;
@@ -61,6 +65,35 @@ impl TrapFrame {
     pub fn is_user(&self) -> bool {
         self.cs & 0x3 == 0x3
     }
+    pub fn user_sp(&self) -> usize {
+        self.rsp
+    }
+    /// Make the user program call `handler(signal)` on the stack at `sp`, returning to `restorer`.
+    ///
+    /// The return address (and the argument in compatibility mode) are written by `write_user`,
+    /// the trap frame is unchanged if it fails.
+    pub fn call_signal_handler(&mut self, handler: usize, signal: usize, restorer: usize, sp: usize,
+                               write_user: &mut FnMut(usize, &[u8]) -> Result<(), ()>) -> Result<(), ()> {
+        use arch::gdt;
+        use core::mem::transmute;
+        let sp = if self.cs == gdt::UCODE32_SELECTOR.0 as usize {
+            // cdecl: the argument is on the stack, above the return address
+            let args: [u8; 8] = unsafe { transmute([restorer as u32, signal as u32]) };
+            let sp = ((sp - 4) & !0xf) - 4;
+            write_user(sp, &args)?;
+            sp
+        } else {
+            // Skip the red zone, and align as if the handler was called
+            let ret: [u8; 8] = unsafe { transmute(restorer) };
+            let sp = ((sp - 128) & !0xf) - 8;
+            write_user(sp, &ret)?;
+            self.rdi = signal;
+            sp
+        };
+        self.rip = handler;
+        self.rsp = sp;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default)]
@@ -95,7 +128,7 @@ impl InitStack {
     unsafe fn push_at(self, stack_top: usize) -> Context {
         let ptr = (stack_top as *mut Self).offset(-1);
         *ptr = self;
-        Context(ptr as usize)
+        Context { rsp: ptr as usize, fpu: FpuState::new() }
     }
 }
 
@@ -110,19 +143,29 @@ extern fn forkret() {
 }
 
 #[derive(Debug)]
-pub struct Context(usize);
+#[repr(C)]
+pub struct Context {
+    /// Must be the first field, see `switch_stack`.
+    rsp: usize,
+    /// Switched lazily, see `fpu`.
+    fpu: Box<FpuState>,
+}
 
 impl Context {
     /// Switch to another kernel thread.
     ///
-    /// Defined in `trap.asm`.
-    ///
+    /// The FPU is disabled, and handed over to the target on its first use, see `fpu`.
+    pub unsafe fn switch(&mut self, target: &mut Self) {
+        fpu::switch_to(&target.fpu);
+        self.switch_stack(target);
+    }
+
     /// Push all callee-saved registers at the current kernel stack.
     /// Store current rsp, switch to target.
     /// Pop all callee-saved registers, then return to the target.
     #[naked]
     #[inline(never)]
-    pub unsafe extern fn switch(&mut self, target: &mut Self) {
+    unsafe extern fn switch_stack(&mut self, target: &mut Self) {
         asm!(
         "
         // push rip (by caller)
@@ -157,7 +200,7 @@ impl Context {
     }
 
     pub unsafe fn null() -> Self {
-        Context(0)
+        Context { rsp: 0, fpu: FpuState::new() }
     }
 
     pub unsafe fn new_kernel_thread(entry: extern fn(usize) -> !, arg: usize, kstack_top: usize, cr3: usize) -> Self {
@@ -174,6 +217,9 @@ impl Context {
             tf: TrapFrame::new_user_thread(entry_addr, ustack_top, is32),
         }.push_at(kstack_top)
     }
+    /// Copies `tf` onto the new kernel stack at `kstack_top`, to return from the syscall with 0 in `rax`.
+    ///
+    /// The child starts with a clean FPU state.
     pub unsafe fn new_fork(tf: &TrapFrame, kstack_top: usize, cr3: usize) -> Self {
         InitStack {
             context: ContextData::new(cr3),
@@ -185,4 +231,25 @@ impl Context {
             },
         }.push_at(kstack_top)
     }
-}
\ No newline at end of file
+    /// Like `new_fork`, but returns to `entry_addr` with `arg` on another user stack.
+    pub unsafe fn new_clone(tf: &TrapFrame, entry_addr: usize, arg: usize, ustack_top: usize, kstack_top: usize, cr3: usize) -> Self {
+        InitStack {
+            context: ContextData::new(cr3),
+            trapret: trap_ret as usize,
+            tf: {
+                let mut tf = tf.clone();
+                tf.rip = entry_addr;
+                tf.rsp = ustack_top;
+                tf.rdi = arg;
+                tf.rax = 0;
+                tf
+            },
+        }.push_at(kstack_top)
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        fpu::release(&self.fpu);
+    }
+}
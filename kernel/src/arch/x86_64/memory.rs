@@ -12,6 +12,8 @@ pub fn init(boot_info: &BootInfo) {
     assert_has_not_been_called!("memory::init must be called only once");
     init_frame_allocator(boot_info);
     init_heap();
+    super::paging::init_broadcast_window();
+    super::paging::init_zero_frame();
     info!("memory: init end");
 }
 
@@ -5,6 +5,7 @@ use self::bootloader::bootinfo::{BootInfo, MemoryRegionType};
 pub mod consts;
 pub mod driver;
 pub mod cpu;
+pub mod fpu;
 pub mod interrupt;
 pub mod paging;
 pub mod gdt;
@@ -24,6 +25,7 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
 
     // Init trap handling.
     idt::init();
+    fpu::init();
 
     // Init physical memory management and heap.
     memory::init(boot_info);
@@ -40,11 +42,14 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
 #[no_mangle]
 pub extern "C" fn other_main() -> ! {
     idt::init();
+    fpu::init();
     gdt::init();
     driver::apic::other_init();
     let cpu_id = driver::apic::lapic_id();
 //    let ms = unsafe { smp::notify_started(cpu_id) };
     println!("Hello world! from CPU {}!", cpu_id);
+    // Every CPU schedules its own processes
+    ::process::init();
 //    unsafe{ let a = *(0xdeadbeaf as *const u8); } // Page fault
     loop {}
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
 //! x64_64 page table implementations.
 
 use bit_allocator::{BitAlloc, BitAlloc64K};
+use consts::KERNEL_BROADCAST_PML4;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 // Depends on kernel
-use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame};
+use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame, dealloc_stack, frame_refs, kernel_mappings};
 use spin::{Mutex, MutexGuard};
 use ucore_memory::cow::CowExt;
 use ucore_memory::memory_set::*;
@@ -12,7 +14,7 @@ use x86_64::instructions::tlb;
 use x86_64::PhysAddr;
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::{Mapper, PageTable as x86PageTable, PageTableEntry, PageTableFlags as EF, RecursivePageTable};
-use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageRange, PhysFrame as Frame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageRange, PhysFrame as Frame, Size2MiB, Size4KiB};
 use x86_64::ux::u9;
 
 pub trait PageExt {
@@ -40,6 +42,11 @@ impl FrameExt for Frame {
     }
 }
 
+fn large_page_of(address: usize) -> Page<Size2MiB> {
+    use x86_64;
+    Page::containing_address(x86_64::VirtAddr::new(address as u64))
+}
+
 /// Page table in use, mainly implemented by x86_64 crate.
 pub struct ActivePageTable(RecursivePageTable<'static>);
 
@@ -56,12 +63,62 @@ impl PageTable for ActivePageTable {
         self.get_entry(addr)
     }
 
+    fn map_large(&mut self, addr: usize, target: usize) -> Option<&mut PageEntry> {
+        let flags = EF::PRESENT | EF::WRITABLE | EF::NO_EXECUTE;
+        let page = large_page_of(addr);
+        let frame = Frame::<Size2MiB>::containing_address(PhysAddr::new(target as u64));
+        self.0.map_to(page, frame, flags, &mut FrameAllocatorForX86).unwrap().flush();
+        Some(self.get_entry(addr))
+    }
+
+    fn is_large(&mut self, addr: usize) -> bool {
+        self.p2_entry(addr).map_or(false, |entry| entry.0.flags().contains(EF::HUGE_PAGE))
+    }
+
     fn unmap(&mut self, addr: usize) {
+        if self.is_large(addr) {
+            let page = large_page_of(addr);
+            let (_, flush) = self.0.unmap(page).unwrap();
+            flush.flush();
+            return;
+        }
         let (frame, flush) = self.0.unmap(Page::of_addr(addr)).unwrap();
         flush.flush();
     }
 
+    fn unmap_no_flush(&mut self, addr: usize) {
+        if self.is_large(addr) {
+            let page = large_page_of(addr);
+            let (_, flush) = self.0.unmap(page).unwrap();
+            flush.ignore();
+            return;
+        }
+        let (_, flush) = self.0.unmap(Page::of_addr(addr)).unwrap();
+        flush.ignore();
+    }
+
+    /// Flushes page by page, or the whole TLB if there are more than `FLUSH_ALL_PAGES`.
+    ///
+    /// Reloading CR3 keeps global pages, but they are never used on x86_64.
+    fn flush_range(&mut self, start: usize, end: usize) {
+        const FLUSH_ALL_PAGES: usize = 32;
+        let start = start & !(PAGE_SIZE - 1);
+        if end <= start {
+            return;
+        }
+        if (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES {
+            tlb::flush_all();
+            return;
+        }
+        for addr in (start..end).step_by(PAGE_SIZE) {
+            tlb::flush(::x86_64::VirtAddr::new_unchecked(addr as u64));
+        }
+    }
+
     fn get_entry(&mut self, addr: usize) -> &mut PageEntry {
+        if self.is_large(addr) {
+            return self.p2_entry(addr).unwrap();
+        }
         let entry_addr = ((addr >> 9) & 0o777_777_777_7770) | 0xffffff80_00000000;
         unsafe { &mut *(entry_addr as *mut PageEntry) }
     }
@@ -85,6 +142,24 @@ impl ActivePageTable {
         ActivePageTable(RecursivePageTable::new(&mut *(0xffffffff_fffff000 as *mut _)).unwrap())
     }
 
+    /// Returns the P2 entry of `addr` by recursive mapping, or `None` if there is no P2 table.
+    fn p2_entry(&mut self, addr: usize) -> Option<&mut PageEntry> {
+        let p4_entry_addr = ((addr >> 36) & 0o7770) | 0xffffffff_fffff000;
+        let p3_entry_addr = ((addr >> 27) & 0o7_777_770) | 0xffffffff_ffe00000;
+        let p2_entry_addr = ((addr >> 18) & 0o7_777_777_770) | 0xffffffff_c0000000;
+        unsafe {
+            let p4_entry = &*(p4_entry_addr as *const PageEntry);
+            if !p4_entry.present() {
+                return None;
+            }
+            let p3_entry = &*(p3_entry_addr as *const PageEntry);
+            if !p3_entry.present() || p3_entry.0.flags().contains(EF::HUGE_PAGE) {
+                return None;
+            }
+            Some(&mut *(p2_entry_addr as *mut PageEntry))
+        }
+    }
+
     /// Apply a function to a currently unmapped frame.
     /// 
     /// Temporarily maps the frame and unmaps it after function is applied.
@@ -140,6 +215,8 @@ impl Entry for PageEntry {
     fn clear_shared(&mut self) { self.as_flags().remove(EF::BIT_9 | EF::BIT_10); }
     fn swapped(&self) -> bool { self.0.flags().contains(EF::BIT_11) }
     fn set_swapped(&mut self, value: bool) { self.as_flags().set(EF::BIT_11, value); }
+    fn hidden(&self) -> bool { self.0.flags().contains(EF::BIT_52) }
+    fn set_hidden(&mut self, value: bool) { self.as_flags().set(EF::BIT_52, value); }
     fn user(&self) -> bool { self.0.flags().contains(EF::USER_ACCESSIBLE) }
     fn set_user(&mut self, value: bool) {
         self.as_flags().set(EF::USER_ACCESSIBLE, value);
@@ -155,6 +232,10 @@ impl Entry for PageEntry {
     }
     fn execute(&self) -> bool { !self.0.flags().contains(EF::NO_EXECUTE) }
     fn set_execute(&mut self, value: bool) { self.as_flags().set(EF::NO_EXECUTE, !value); }
+    fn global(&self) -> bool { self.0.flags().contains(EF::GLOBAL) }
+    fn set_global(&mut self, value: bool) { self.as_flags().set(EF::GLOBAL, value); }
+    fn set_cache_disable(&mut self, value: bool) { self.as_flags().set(EF::NO_CACHE, value); }
+    fn set_write_through(&mut self, value: bool) { self.as_flags().set(EF::WRITE_THROUGH, value); }
 }
 
 impl PageEntry {
@@ -172,6 +253,9 @@ pub struct InactivePageTable0 {
 impl InactivePageTable for InactivePageTable0 {
     type Active = ActivePageTable;
 
+    // 4-level paging
+    const LEVELS: usize = 4;
+
     fn new() -> Self {
         let mut pt = Self::new_bare();
         pt.map_kernel();
@@ -215,18 +299,19 @@ impl InactivePageTable for InactivePageTable0 {
         }
     }
 
-    unsafe fn with(&self, f: impl FnOnce()) {
+    unsafe fn with<R>(&self, f: impl FnOnce() -> R) -> R {
         let old_frame = Cr3::read().0;
         let new_frame = self.p4_frame.clone();
         debug!("switch table {:?} -> {:?}", old_frame, new_frame);
         if old_frame != new_frame {
             Cr3::write(new_frame, Cr3Flags::empty());
         }
-        f();
+        let ret = f();
         debug!("switch table {:?} -> {:?}", new_frame, old_frame);
         if old_frame != new_frame {
             Cr3::write(old_frame, Cr3Flags::empty());
         }
+        ret
     }
 
     fn token(&self) -> usize {
@@ -241,26 +326,90 @@ impl InactivePageTable for InactivePageTable0 {
         dealloc_frame(target)
     }
 
-    fn alloc_stack() -> Stack {
+    fn zero_frame() -> usize {
+        let frame = ZERO_FRAME.load(Ordering::Relaxed);
+        assert_ne!(frame, 0, "zero frame is not initialized");
+        frame
+    }
+
+    fn frame_ref_inc(target: usize) {
+        frame_refs().inc(target);
+    }
+
+    fn frame_ref_dec(target: usize) -> usize {
+        frame_refs().dec(target)
+    }
+
+    fn frame_ref_count(target: usize) -> usize {
+        frame_refs().count(target)
+    }
+
+    fn swap_free(token: usize) {
+        ::swap::swap_free(token);
+    }
+
+    fn alloc_stack() -> Option<Stack> {
         alloc_stack()
     }
+
+    fn dealloc_stack(stack: Stack) {
+        dealloc_stack(stack)
+    }
 }
 
 impl InactivePageTable0 {
     /// Maps kernel space.
+    ///
+    /// The kernel image and stack mapped by the bootloader, and the broadcast window
+    /// are shared by copying their P4 entries, then areas registered in `kernel_mappings` are mapped.
     fn map_kernel(&mut self) {
         let mut table = unsafe { &mut *(0xffffffff_fffff000 as *mut x86PageTable) };
         // Kernel at 0xffff_ff00_0000_0000
         // Kernel stack at 0x0000_57ac_0000_0000 (defined in bootloader crate)
         let e510 = table[510].clone();
         let estack = table[175].clone();
-        self.edit(|_| {
+        let ewindow = table[KERNEL_BROADCAST_PML4].clone();
+        assert!(!ewindow.is_unused(), "kernel broadcast window is not initialized");
+        let mappings = kernel_mappings();
+        self.edit(|pt| {
             table[510].set_addr(e510.addr(), e510.flags() | EF::GLOBAL);
             table[175].set_addr(estack.addr(), estack.flags() | EF::GLOBAL);
+            table[KERNEL_BROADCAST_PML4].set_addr(ewindow.addr(), ewindow.flags());
+            mappings.map_into::<Self>(pt);
         });
     }
 }
 
+/// Allocates the P3 table of the kernel broadcast window, and sets it to the active P4 table.
+///
+/// Must be called at boot before any page table is created,
+/// then all page tables share it, see `memory::broadcast_kernel_mapping`.
+pub fn init_broadcast_window() {
+    let frame = alloc_frame().map(|addr| Frame::of_addr(addr))
+        .expect("failed to allocate frame");
+    active_table().with_temporary_map(&frame, |_, table: &mut x86PageTable| {
+        table.zero();
+    });
+    let table = unsafe { &mut *(0xffffffff_fffff000 as *mut x86PageTable) };
+    table[KERNEL_BROADCAST_PML4].set_frame(frame, EF::PRESENT | EF::WRITABLE);
+    tlb::flush_all();
+}
+
+/// Frame filled with zeros, see `InactivePageTable::zero_frame`. 0 until initialized.
+static ZERO_FRAME: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Allocates and zeroes the zero frame, shared by pages of lazy areas until written.
+///
+/// Must be called at boot before any user process is created.
+pub fn init_zero_frame() {
+    let frame = alloc_frame().map(|addr| Frame::of_addr(addr))
+        .expect("failed to allocate frame");
+    active_table().with_temporary_map(&frame, |_, table: &mut x86PageTable| {
+        table.zero();
+    });
+    ZERO_FRAME.store(frame.start_address().as_u64() as usize, Ordering::Relaxed);
+}
+
 impl Drop for InactivePageTable0 {
     /// Release p4_frame after inactive page table is no longer used.
     fn drop(&mut self) {
@@ -12,9 +12,9 @@ pub fn start_other_cores(acpi: &AcpiResult) {
     let args = unsafe { &mut *(0x8000 as *mut EntryArgs).offset(-1) };
     for i in 1 .. acpi.cpu_num {
         let apic_id = acpi.cpu_acpi_ids[i as usize];
-        let ms = MemorySet::new();
+        let mut ms = MemorySet::new();
         *args = EntryArgs {
-            kstack: ms.kstack_top() as u64,
+            kstack: ms.kstack_top().expect("failed to allocate kernel stack") as u64,
             page_table: Cr3::read().0.start_address().as_u64() as u32,
             stack: args as *const _ as u32, // just enough stack to get us to entry64mp
         };
@@ -6,3 +6,15 @@ pub const MAX_CPU_NUM: usize = 8;
 pub const MAX_PROCESS_NUM: usize = 48;
 
 pub const STACK_SIZE: usize = 0x8000;
+/// Load address of position independent executables, which have segments from 0.
+pub const USER_PIE_BIAS: usize = 0x1000_0000;
+/// Mask of the random number of pages added to `USER_PIE_BIAS`, unless feature `no_aslr`.
+pub const USER_PIE_RANDOM_MASK: usize = 0xfff;
+/// Size of the user stack mapped at first, more if the initial content needs.
+pub const USER_STACK_INIT_SIZE: usize = 0x4000;
+/// Size the user stack can grow down to on faults, by default, see `MemoryLayout`.
+pub const USER_STACK_MAX_SIZE: usize = 0x80_0000;
+/// Mask of the random number of pages the user stack top is lowered by, unless feature `no_aslr`.
+pub const USER_STACK_RANDOM_MASK: usize = 0xff;
+/// Refuse user pages both writable and executable, see `MemorySet::set_enforce_wx`, unless feature `allow_wx`.
+pub const USER_ENFORCE_WX: bool = !cfg!(feature = "allow_wx");
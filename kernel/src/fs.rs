@@ -2,6 +2,8 @@
 
 use simple_filesystem::*;
 use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
 #[cfg(target_arch = "x86_64")]
 use arch::driver::ide;
 use spin::Mutex;
@@ -16,7 +18,8 @@ _binary_user_riscv_img_start:
 _binary_user_riscv_img_end:
 "#);
 
-pub fn shell() {
+/// Open the file system of user programs.
+fn open_sfs() -> Rc<SimpleFileSystem> {
     #[cfg(target_arch = "riscv32")]
     let device = {
         extern {
@@ -26,8 +29,21 @@ pub fn shell() {
         Box::new(unsafe { MemBuf::new(_binary_user_riscv_img_start, _binary_user_riscv_img_end) })
     };
     #[cfg(target_arch = "x86_64")]
-    let device = Box::new(&ide::DISK1);
-    let sfs = SimpleFileSystem::open(device).expect("failed to open SFS");
+    let device = Box::new(&*ide::DISK1);
+    SimpleFileSystem::open(device).expect("failed to open SFS")
+}
+
+/// Read user program `name` into `buf`, returns its length, or `None` if it does not exist.
+pub fn read_program(name: &str, buf: &mut [u8]) -> Option<usize> {
+    let sfs = open_sfs();
+    let root = sfs.root_inode();
+    let file = root.borrow().lookup(name).ok()?;
+    let len = file.borrow().read_at(0, buf).ok()?;
+    Some(len)
+}
+
+pub fn shell() {
+    let sfs = open_sfs();
     let root = sfs.root_inode();
     let files = root.borrow().list().unwrap();
     println!("Available programs: {:?}", files);
@@ -41,15 +57,22 @@ pub fn shell() {
     loop {
         print!(">> ");
         use console::get_line;
-        let name = get_line();
-        if name == "" {
-            continue;
-        }
-        if let Ok(file) = root.borrow().lookup(name.as_str()) {
+        let line = get_line();
+        let args: Vec<&str> = line.split(' ').filter(|arg| !arg.is_empty()).collect();
+        let name = match args.first() {
+            Some(&name) => name,
+            None => continue,
+        };
+        if let Ok(file) = root.borrow().lookup(name) {
             use process::*;
             let len = file.borrow().read_at(0, &mut *buf).unwrap();
-            let pid = processor().add(Context::new_user(&buf[..len]));
-            processor().current_wait_for(pid);
+            match Context::new_user(&buf[..len], &args, &[]) {
+                Ok(context) => {
+                    let pid = processor().add(context);
+                    processor().current_wait_for(pid);
+                }
+                Err(err) => println!("Failed to load {}: {}", name, err),
+            }
         } else {
             println!("Program not exist");
         }
@@ -81,7 +104,7 @@ impl Device for MemBuf {
 use core::slice;
 
 #[cfg(target_arch = "x86_64")]
-impl BlockedDevice for &'static ide::DISK1 {
+impl BlockedDevice for &'static ide::LockedIde {
     const BLOCK_SIZE_LOG2: u8 = 9;
     fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
         assert!(buf.len() >= ide::BLOCK_SIZE);
@@ -45,6 +45,7 @@ mod consts;
 mod process;
 mod syscall;
 mod fs;
+mod swap;
 
 use process::{thread, thread_};
 mod sync;
@@ -1,11 +1,16 @@
 pub use arch::paging::*;
+use alloc::vec::Vec;
 use bit_allocator::{BitAlloc, BitAlloc4K, BitAlloc64K};
-use consts::{MEMORY_OFFSET, STACK_SIZE};
+use consts::{KERNEL_BROADCAST_OFFSET, KERNEL_BROADCAST_SIZE, MEMORY_OFFSET, STACK_SIZE};
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use spin::{Mutex, MutexGuard};
 use super::HEAP_ALLOCATOR;
-use ucore_memory::{*, paging::PageTable};
+use ucore_memory::{*, paging::{Entry, PageTable}};
+use ucore_memory::access_sampler::AccessSampler;
 use ucore_memory::cow::CowExt;
-pub use ucore_memory::memory_set::{MemoryArea, MemoryAttr, MemorySet as MemorySet_, Stack};
+use ucore_memory::refcount::FrameRefCount;
+pub use ucore_memory::memory_set::{copy_on_write, AreaKind, FaultHandler, FaultResult, KernelMappings, MemoryArea, MemoryAttr, MemorySet as MemorySet_, PushError, Stack};
 
 pub type MemorySet = MemorySet_<InactivePageTable0>;
 
@@ -22,24 +27,174 @@ lazy_static! {
 }
 
 /// alloc a frame with cascaded bitmap allocator
+///
+/// If no frame is free, ask the pressure callbacks to release some and retry.
 pub fn alloc_frame() -> Option<usize> {
-    let ret = FRAME_ALLOCATOR.lock().alloc().map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
+    let ret = alloc_frame_fast().or_else(alloc_frame_slow);
     trace!("Allocate frame: {:x?}", ret);
     ret
 }
 
+fn alloc_frame_fast() -> Option<usize> {
+    FRAME_ALLOCATOR.lock().alloc().map(|id| id * PAGE_SIZE + MEMORY_OFFSET)
+}
+
+/// Reclaim-then-retry loop, run when the frame allocator is exhausted.
+///
+/// It gives up after `MAX_RECLAIM_ROUNDS`, or when no callback released anything.
+/// Allocations made during reclaiming (e.g. by the callbacks themselves) fail fast
+/// instead of reclaiming recursively.
+fn alloc_frame_slow() -> Option<usize> {
+    const MAX_RECLAIM_ROUNDS: usize = 4;
+    static RECLAIMING: AtomicBool = ATOMIC_BOOL_INIT;
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    // Copy out, so that callbacks may register others without deadlock
+    let callbacks = PRESSURE_CALLBACKS.lock().clone();
+    let mut ret = None;
+    for round in 0..MAX_RECLAIM_ROUNDS {
+        let released: usize = callbacks.iter().map(|f| f()).sum();
+        debug!("memory pressure: round {}, released {} frames", round, released);
+        if released == 0 {
+            break;
+        }
+        ret = alloc_frame_fast();
+        if ret.is_some() {
+            break;
+        }
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    ret
+}
+
+/// A callback to release memory when frames run out, such as evicting pages to swap
+/// or dropping clean pages of the page cache.
+///
+/// Returns the number of frames released.
+/// It's called with no lock held, and should deallocate frames by `dealloc_frame`.
+pub type PressureCallback = fn() -> usize;
+
+lazy_static! {
+    static ref PRESSURE_CALLBACKS: Mutex<Vec<PressureCallback>> = Mutex::new(Vec::new());
+}
+
+/// Register a callback to be called when frame allocation is failing.
+pub fn register_pressure_callback(f: PressureCallback) {
+    PRESSURE_CALLBACKS.lock().push(f);
+}
+
 /// alloc a frame with cascaded bitmap allocator
 pub fn dealloc_frame(target: usize) {
     trace!("Deallocate frame: {:x}", target);
     FRAME_ALLOCATOR.lock().dealloc((target - MEMORY_OFFSET) / PAGE_SIZE);
 }
 
-/// alloc a stack from heap
-pub fn alloc_stack() -> Stack {
-    use alloc::alloc::{alloc, Layout};
-    let bottom = unsafe{ alloc(Layout::from_size_align(STACK_SIZE, 0x8000).unwrap()) } as usize;
-    let top = bottom + STACK_SIZE;
-    Stack { top, bottom }
+/// Kernel stacks are laid out in slots from the start of the broadcast window,
+/// each a stack above an unmapped guard page.
+const KSTACK_SLOT_SIZE: usize = STACK_SIZE + PAGE_SIZE;
+
+/// The upper half of the broadcast window is kept for the kernel heap, see `extend_kernel_heap`.
+const HEAP_EXTENSION_OFFSET: usize = KERNEL_BROADCAST_OFFSET + KERNEL_BROADCAST_SIZE / 2;
+const HEAP_EXTENSION_END: usize = KERNEL_BROADCAST_OFFSET + KERNEL_BROADCAST_SIZE;
+
+/// Number of kernel stack slots below the heap extension.
+const KSTACK_SLOT_COUNT: usize = (HEAP_EXTENSION_OFFSET - KERNEL_BROADCAST_OFFSET) / KSTACK_SLOT_SIZE;
+
+lazy_static! {
+    /// Free kernel stack slots, as many as the bitmap holds if the window has room for more.
+    static ref KSTACK_SLOTS: Mutex<BitAlloc4K> = {
+        let mut slots = BitAlloc4K::default();
+        slots.insert(0..min(KSTACK_SLOT_COUNT, BitAlloc4K::CAP));
+        Mutex::new(slots)
+    };
+}
+
+/// alloc a kernel stack with a guard page below it
+///
+/// Mapped in the broadcast window, so the stack is visible in every address space.
+/// Returns `None` if all slots are used or frames ran out.
+pub fn alloc_stack() -> Option<Stack> {
+    let slot = KSTACK_SLOTS.lock().alloc()?;
+    let bottom = KERNEL_BROADCAST_OFFSET + slot * KSTACK_SLOT_SIZE + PAGE_SIZE;
+    for page in (bottom..bottom + STACK_SIZE).step_by(PAGE_SIZE) {
+        let frame = match alloc_frame() {
+            Some(frame) => frame,
+            None => {
+                unmap_stack_pages(bottom, page);
+                KSTACK_SLOTS.lock().dealloc(slot);
+                return None;
+            }
+        };
+        broadcast_kernel_mapping(MemoryArea::new_physical(frame, frame + PAGE_SIZE, page - frame, MemoryAttr::default(), "kstack"));
+    }
+    Some(Stack::new(bottom, bottom + STACK_SIZE))
+}
+
+/// Frees a kernel stack from `alloc_stack`, which must not be in use on any CPU.
+pub fn dealloc_stack(stack: Stack) {
+    unmap_stack_pages(stack.bottom, stack.top);
+    KSTACK_SLOTS.lock().dealloc((stack.bottom - PAGE_SIZE - KERNEL_BROADCAST_OFFSET) / KSTACK_SLOT_SIZE);
+}
+
+/// Unmaps pages `[start, end)` of a kernel stack, and frees their frames.
+fn unmap_stack_pages(start: usize, end: usize) {
+    let mut mappings = KERNEL_MAPPINGS.lock();
+    let mut table = active_table();
+    for page in (start..end).step_by(PAGE_SIZE) {
+        let frame = table.get_entry(page).target();
+        mappings.unbroadcast::<InactivePageTable0>(page, &mut **table)
+            .expect("kernel stack page is not mapped");
+        dealloc_frame(frame);
+    }
+}
+
+/// If `addr` is in the guard page of a kernel stack slot, see `alloc_stack`.
+fn is_kstack_guard(addr: usize) -> bool {
+    if addr < KERNEL_BROADCAST_OFFSET {
+        return false;
+    }
+    let offset = addr - KERNEL_BROADCAST_OFFSET;
+    offset / KSTACK_SLOT_SIZE < KSTACK_SLOT_COUNT && offset % KSTACK_SLOT_SIZE < PAGE_SIZE
+}
+
+lazy_static! {
+    static ref FRAME_REFS: Mutex<FrameRefCount> = Mutex::new(FrameRefCount::default());
+}
+
+/// Reference counts of frames shared by copy-on-write pages.
+pub fn frame_refs() -> MutexGuard<'static, FrameRefCount> {
+    FRAME_REFS.lock()
+}
+
+lazy_static! {
+    static ref KERNEL_MAPPINGS: Mutex<KernelMappings> = Mutex::new(KernelMappings::default());
+}
+
+/// Registers a kernel area to be mapped into every page table created later.
+///
+/// Must be called before any user process is created, see `KernelMappings::register`.
+pub fn register_kernel_mapping(area: MemoryArea) {
+    KERNEL_MAPPINGS.lock().register(area);
+}
+
+/// Maps a kernel area into all address spaces, including the existing ones.
+///
+/// The area must lie in the kernel broadcast window, whose page table pages are shared
+/// by all page tables, so mapping it into the active table is enough.
+pub fn broadcast_kernel_mapping(area: MemoryArea) {
+    assert!(area.start_addr() >= KERNEL_BROADCAST_OFFSET &&
+                area.end_addr() <= KERNEL_BROADCAST_OFFSET + KERNEL_BROADCAST_SIZE,
+            "broadcast kernel mapping must lie in the broadcast window");
+    // Same lock order as `InactivePageTable::new`
+    let mut mappings = KERNEL_MAPPINGS.lock();
+    let mut table = active_table();
+    mappings.broadcast::<InactivePageTable0>(area, &mut **table);
+}
+
+/// Kernel areas mapped by `InactivePageTable::new`.
+pub fn kernel_mappings() -> MutexGuard<'static, KernelMappings> {
+    KERNEL_MAPPINGS.lock()
 }
 
 lazy_static! {
@@ -53,15 +208,73 @@ pub fn active_table() -> MutexGuard<'static, CowExt<ActivePageTable>> {
     ACTIVE_TABLE.lock()
 }
 
+lazy_static! {
+    static ref ACCESS_SAMPLER: Mutex<AccessSampler> = Mutex::new(AccessSampler::default());
+}
+
+/// Access recency of the tracked pages in the active page table.
+pub fn access_sampler() -> MutexGuard<'static, AccessSampler> {
+    ACCESS_SAMPLER.lock()
+}
+
+/// Samples accessed bits of the tracked pages. Called periodically by timer.
+///
+/// Skipped if the active table is being edited by the interrupted code.
+pub fn sample_access() {
+    if let Some(mut table) = ACTIVE_TABLE.try_lock() {
+        access_sampler().sample(&mut **table);
+    }
+}
+
 /// Platform independent page fault handler.
 /// 
 /// Calls pf handler in ucore memory crate.
 /// 
 /// Return true to continue, false to halt.
-pub fn page_fault_handler(addr: usize) -> bool {
-    // Handle copy on write
+pub fn page_fault_handler(addr: usize, write: bool) -> bool {
     unsafe { ACTIVE_TABLE.force_unlock(); }
-    active_table().page_fault_handler(addr, || alloc_frame().unwrap())
+    if is_kstack_guard(addr) {
+        error!("kernel stack overflow @ {:#x}", addr);
+        return false;
+    }
+    // Let the memory set of the current process decide what the fault means.
+    // Skipped if the processor or the memory set is locked by the interrupted code, e.g. a syscall.
+    use process::local_processor;
+    let processor = local_processor().and_then(|processor| processor.try_lock())
+        .filter(|processor| processor.current_context().try_memory_set().is_some());
+    if let Some(mut processor) = processor {
+        // A child of `fork_lazy` stops borrowing the memory of its parent, which may run again
+        if processor.current_context().is_lazy_fork() {
+            if processor.current_context_mut().unshare_memory().is_err() {
+                error!("failed to unshare memory @ {:#x}", addr);
+                return false;
+            }
+            let pid = processor.current_pid();
+            processor.release_vfork(pid);
+        }
+        let result = {
+            let mut memory_set = processor.current_context().memory_set();
+            ::swap::with_swapper(|swapper| memory_set.handle_fault(addr, write, swapper))
+        };
+        match result {
+            FaultResult::Unhandled => {}
+            FaultResult::StackOverflow => {
+                error!("stack overflow @ {:#x}", addr);
+                return false;
+            }
+            FaultResult::OutOfMemory => {
+                error!("out of memory @ {:#x}", addr);
+                return false;
+            }
+            result => return result.is_resolved(),
+        }
+    }
+    // Handle copy on write, e.g. a syscall writing to user memory
+    let mut table = active_table();
+    if let Some(resolved) = copy_on_write::<InactivePageTable0>(&mut **table, addr) {
+        return resolved;
+    }
+    table.page_fault_handler(addr, alloc_frame)
 }
 
 /// init heap allocator
@@ -72,6 +285,58 @@ pub fn init_heap() {
     info!("heap init end");
 }
 
+lazy_static! {
+    /// End of the kernel heap extended by `extend_kernel_heap`.
+    static ref HEAP_EXTENSION_TOP: Mutex<usize> = Mutex::new(HEAP_EXTENSION_OFFSET);
+}
+
+/// Grow the kernel heap by `pages` pages, mapped above the last extension.
+///
+/// Extensions are mapped in the broadcast window, so they are visible in every address space.
+/// Nothing is allocated from the heap meanwhile, so it works even if the heap is exhausted.
+/// Concurrent calls are serialized and each maps a range of its own.
+/// Returns `Err` with nothing changed if frames run out, or the window is full.
+pub fn extend_kernel_heap(pages: usize) -> Result<(), ()> {
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    if pages == 0 {
+        return Ok(());
+    }
+    let mut top = HEAP_EXTENSION_TOP.lock();
+    let start = *top;
+    let end = start + pages * PAGE_SIZE;
+    if end > HEAP_EXTENSION_END {
+        return Err(());
+    }
+    {
+        let mut table = active_table();
+        let table: &mut ActivePageTable = &mut **table;
+        for page in (start..end).step_by(PAGE_SIZE) {
+            // Not reclaiming, as the pressure callbacks may allocate from the heap
+            match alloc_frame_fast() {
+                Some(frame) => { table.map(page, frame); }
+                None => {
+                    for page in (start..page).step_by(PAGE_SIZE) {
+                        let frame = table.get_entry(page).target();
+                        table.unmap(page);
+                        dealloc_frame(frame);
+                    }
+                    return Err(());
+                }
+            }
+        }
+    }
+    // The static heap can only be extended right at its top, which is not here.
+    // Instead the new range is freed into it, so it becomes a free hole of the heap.
+    unsafe {
+        let layout = Layout::from_size_align_unchecked(end - start, 1);
+        HEAP_ALLOCATOR.lock().deallocate(NonNull::new_unchecked(start as *mut u8), layout);
+    }
+    info!("kernel heap extended by {:#x} bytes at {:#x}", end - start, start);
+    *top = end;
+    Ok(())
+}
+
 //pub mod test {
 //    pub fn cow() {
 //        use super::*;
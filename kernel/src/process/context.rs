@@ -1,24 +1,41 @@
 //! Context definitions used by processor.
 
 use arch::interrupt::{TrapFrame, Context as ArchContext};
-use memory::{MemoryArea, MemoryAttr, MemorySet};
-use xmas_elf::{ElfFile, header, program::{Flags, ProgramHeader, Type}};
-use core::fmt::{Debug, Error, Formatter};
+use memory::{MemoryArea, MemoryAttr, MemorySet, PushError, Stack};
+use ucore_process::signal::{SigAction, Signal};
+use xmas_elf::{ElfFile, header, program::{Flags, Type}};
+use alloc::{arc::Arc, string::String, vec::Vec};
+use sync::{MutexGuard, SpinNoIrq, SpinNoIrqLock};
+use core::fmt::{Debug, Display, Error, Formatter};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use consts::USER_ENFORCE_WX;
 
 /// Context data to be saved during process switching.
 /// Including:
 ///     + arch: arch-dependent context data;
-///     + memory_set: memory info (memory areas, page table, kernel stack)
+///     + memory_set: memory info (memory areas, page table, kernel stack), shared by threads
+///     + kstack: kernel stack of its own, for contexts not running on the one of the memory set
+///     + signal_frames: user trap frames saved when signal handlers are entered
+///     + name: the program name of a user process, none for kernel threads
 pub struct Context {
     arch: ArchContext,
-    memory_set: MemorySet,
+    memory_set: Arc<SpinNoIrqLock<MemorySet>>,
+    /// Allocated by `new_thread` and `fork_lazy`, or taken from the memory set replaced by `exec`.
+    /// Freed on drop.
+    kstack: Option<Stack>,
+    signal_frames: Vec<TrapFrame>,
+    /// Set by `sigreturn`, and restored by `restore_signal_frame`.
+    restore_frame: Option<TrapFrame>,
+    name: Option<String>,
+    /// The memory set is borrowed from the parent by `fork_lazy`, until `exec` or `unshare_memory`.
+    lazy_fork: bool,
 }
 
 impl ::ucore_process::processor::Context for Context {
     /// Switch to another context. 
     /// Switch logic is mainly implemented in arch::Context.
     unsafe fn switch(&mut self, target: &mut Self) {
-        super::PROCESSOR.try().unwrap().force_unlock();
+        super::local_processor().unwrap().force_unlock();
         self.arch.switch(&mut target.arch);
         use core::mem::forget;
         forget(super::processor());
@@ -26,102 +43,590 @@ impl ::ucore_process::processor::Context for Context {
 
     /// Create a new context for a kernel thread.
     fn new_kernel(entry: extern fn(usize) -> !, arg: usize) -> Self {
-        let ms = MemorySet::new();
+        let mut ms = MemorySet::new();
+        let kstack_top = ms.kstack_top().expect("failed to allocate kernel stack");
         Context {
-            arch: unsafe { ArchContext::new_kernel_thread(entry, arg, ms.kstack_top(), ms.token()) },
-            memory_set: ms,
+            arch: unsafe { ArchContext::new_kernel_thread(entry, arg, kstack_top, ms.token()) },
+            memory_set: Arc::new(SpinNoIrqLock::new(ms)),
+            kstack: None,
+            signal_frames: Vec::new(),
+            restore_frame: None,
+            name: None,
+            lazy_fork: false,
         }
     }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| name.as_str())
+    }
+
+    fn rss(&self) -> usize {
+        self.memory_set.lock().rss()
+    }
 }
 
 impl Context {
     pub unsafe fn new_init() -> Self {
         Context {
             arch: ArchContext::null(),
-            memory_set: MemorySet::new(),
+            memory_set: Arc::new(SpinNoIrqLock::new(MemorySet::new())),
+            kstack: None,
+            signal_frames: Vec::new(),
+            restore_frame: None,
+            name: None,
+            lazy_fork: false,
         }
     }
 
     /// Make a new user thread from ELF data
-    pub fn new_user(data: &[u8]) -> Self {
+    ///
+    /// `args` and `envs` are passed on the user stack, see `InitUserStack`.
+    /// Position independent executables (`ET_DYN`) are loaded at `USER_PIE_BIAS`,
+    /// they must be static, as there is no dynamic linker to load.
+    /// The load bias and the user stack are moved by random pages, see `aslr_random`.
+    /// It is named after `args[0]`, see `program_name`.
+    /// Returns `Err` if the ELF is malformed, not executable, or not for this machine,
+    /// or frames run out when loading it.
+    pub fn new_user(data: &[u8], args: &[&str], envs: &[&str]) -> Result<Self, LoadError> {
+        Self::new_user_with_layout(data, args, envs, &MemoryLayout::default())
+    }
+
+    /// Make a new user thread from ELF data like `new_user`, with the stack and heap placed by `layout`.
+    ///
+    /// Returns `Err(LoadError::Overlap)` if the stack or the heap of `layout` collide with the program.
+    pub fn new_user_with_layout(data: &[u8], args: &[&str], envs: &[&str], layout: &MemoryLayout)
+        -> Result<Self, LoadError>
+    {
+        let program = Program::parse(data, args, envs, layout, &mut aslr_random)?;
+        let mut memory_set = MemorySet::new();
+        memory_set.set_enforce_wx(USER_ENFORCE_WX);
+        program.load(&mut memory_set)?;
+        let kstack_top = memory_set.kstack_top().ok_or(LoadError::OutOfMemory)?;
+        Ok(Context {
+            arch: unsafe {
+                ArchContext::new_user_thread(
+                    program.entry, program.sp, kstack_top, program.is32, memory_set.token())
+            },
+            memory_set: Arc::new(SpinNoIrqLock::new(memory_set)),
+            kstack: None,
+            signal_frames: Vec::new(),
+            restore_frame: None,
+            name: program_name(args),
+            lazy_fork: false,
+        })
+    }
+
+    /// Replace the program of the current thread with one from ELF data, like `exec`.
+    ///
+    /// The new program is loaded into a new memory set, which replaces the old one only on success.
+    /// The kernel stack we are running on is kept, taken from the old memory set if it is there.
+    /// `tf` is the trap frame of the syscall, which is set to return to the entry of the new program.
+    /// Returns `Err` with the old program untouched if the ELF can not be loaded, see `new_user`,
+    /// even when frames run out.
+    ///
+    /// A child made by `fork_lazy` drops the memory set borrowed from the parent, which is never touched.
+    ///
+    /// Returns `Err(LoadError::Shared)` if other threads share the memory set made by `new_thread`,
+    /// as their program would be gone too.
+    pub fn exec(&mut self, data: &[u8], args: &[&str], tf: &mut TrapFrame) -> Result<(), LoadError> {
+        if !self.lazy_fork && Arc::strong_count(&self.memory_set) > 1 {
+            return Err(LoadError::Shared);
+        }
+        let program = Program::parse(data, args, &[], &MemoryLayout::default(), &mut aslr_random)?;
+        let mut memory_set = MemorySet::new();
+        memory_set.set_enforce_wx(USER_ENFORCE_WX);
+        program.load(&mut memory_set)?;
+        // Freed with the context instead of the old memory set
+        if self.kstack.is_none() {
+            self.kstack = self.memory_set.lock().take_kstack();
+        }
+        self.replace_memory_set(memory_set);
+        *tf = TrapFrame::new_user_thread(program.entry, program.sp, program.is32);
+        self.signal_frames.clear();
+        self.restore_frame = None;
+        self.name = program_name(args);
+        Ok(())
+    }
+
+    /// Lock the memory set, which may be shared with other threads running on other CPUs.
+    ///
+    /// A page fault meanwhile is not handled by `MemorySet::handle_fault`, see `page_fault_handler`,
+    /// so user memory must be accessed by `MemorySet::copy_from_user` and the like.
+    pub fn memory_set(&self) -> MutexGuard<MemorySet, SpinNoIrq> {
+        self.memory_set.lock()
+    }
+
+    /// Lock the memory set to change mappings, like `memory_set`.
+    ///
+    /// Must only be used on the current context, whose page table is active.
+    pub fn memory_set_mut(&mut self) -> MutexGuard<MemorySet, SpinNoIrq> {
+        self.memory_set.lock()
+    }
+
+    /// Lock the memory set, or `None` if it is locked already, e.g. by the code a page fault interrupted.
+    pub fn try_memory_set(&self) -> Option<MutexGuard<MemorySet, SpinNoIrq>> {
+        self.memory_set.try_lock()
+    }
+
+    /// The memory set, to be locked after the processor is unlocked, see `with_current_memory_set`.
+    pub fn shared_memory_set(&self) -> Arc<SpinNoIrqLock<MemorySet>> {
+        self.memory_set.clone()
+    }
+
+    /// Fork
+    ///
+    /// Memory is shared with the child by copy-on-write, and copied on the first write.
+    /// The kernel stack is not, the child gets a fresh one with a copy of the syscall trap frame `tf`
+    /// on top, so that it returns to user mode right after the syscall, with 0 as the return value.
+    /// Returns `Err` if a page swapped out can not be swapped in to be shared, see `MemorySet::clone_cow`,
+    /// or the kernel stack can not be allocated.
+    pub fn fork(&mut self, tf: &TrapFrame) -> Result<Self, ()> {
+        let mut memory_set = self.clone_memory_set()?;
+        let kstack_top = memory_set.kstack_top().ok_or(())?;
+        Ok(Context {
+            arch: unsafe { ArchContext::new_fork(tf, kstack_top, memory_set.token()) },
+            memory_set: Arc::new(SpinNoIrqLock::new(memory_set)),
+            kstack: None,
+            signal_frames: self.signal_frames.clone(),
+            restore_frame: None,
+            name: self.name.clone(),
+            lazy_fork: false,
+        })
+    }
+
+    /// Fork without copying anything, for a child which is going to `exec` right away, like `vfork`.
+    ///
+    /// Unlike `fork`, the child runs in the memory set of the parent, which is not even marked
+    /// copy-on-write, so its writes are seen by the parent. The parent must not run until
+    /// the child calls `exec`, exits or unshares its memory, see `Processor_::vfork_wait`.
+    /// `exec` of the child then loads into a new memory set, leaving the parent's untouched.
+    /// The first page fault of the child makes it a full fork before resolving the fault,
+    /// see `unshare_memory`, but earlier writes of the child are still in the parent's memory.
+    ///
+    /// The child gets a kernel stack of its own, as the memory set's belongs to the parent.
+    /// Like the one of `new_thread`, it is freed when the child is dropped.
+    /// Returns `Err` if it can not be allocated.
+    pub fn fork_lazy(&self, tf: &TrapFrame) -> Result<Self, ()> {
+        let kstack = ::memory::alloc_stack().ok_or(())?;
+        let token = self.memory_set.lock().token();
+        Ok(Context {
+            arch: unsafe { ArchContext::new_fork(tf, kstack.top, token) },
+            memory_set: self.memory_set.clone(),
+            kstack: Some(kstack),
+            signal_frames: self.signal_frames.clone(),
+            restore_frame: None,
+            name: self.name.clone(),
+            lazy_fork: true,
+        })
+    }
+
+    /// If it is a child made by `fork_lazy`, still borrowing the memory set of its parent.
+    pub fn is_lazy_fork(&self) -> bool {
+        self.lazy_fork
+    }
+
+    /// Give a child made by `fork_lazy` a memory set of its own, shared with the parent by copy-on-write
+    /// like `fork`. Does nothing for other contexts.
+    ///
+    /// Must be called on the current context, as the new page table is activated.
+    /// Returns `Err` with the memory set still borrowed if it can not be cloned, see `fork`.
+    pub fn unshare_memory(&mut self) -> Result<(), ()> {
+        if !self.lazy_fork {
+            return Ok(());
+        }
+        let memory_set = self.clone_memory_set()?;
+        self.replace_memory_set(memory_set);
+        Ok(())
+    }
+
+    /// Clone the memory set by copy-on-write, swapping in pages from the swap space of the kernel.
+    fn clone_memory_set(&self) -> Result<MemorySet, ()> {
+        let mut memory_set = self.memory_set.lock();
+        ::swap::with_swapper(|swapper| memory_set.clone_cow(swapper)).map_err(|_| ())
+    }
+
+    /// Switch the current context to `memory_set`, dropping the old one, or the borrowed one of `fork_lazy`.
+    fn replace_memory_set(&mut self, memory_set: MemorySet) {
+        unsafe { memory_set.activate(); }
+        self.memory_set = Arc::new(SpinNoIrqLock::new(memory_set));
+        self.lazy_fork = false;
+    }
+
+    /// Make a new thread sharing the memory set, like `clone` with `CLONE_VM`.
+    ///
+    /// It has its own kernel stack, and returns from the syscall `tf` to user `entry`
+    /// with `arg` as the first argument, on the user stack at `ustack_top` prepared by the caller.
+    /// Writes of either thread are seen by the other, as they use the same page table.
+    /// The kernel stack is freed when the thread is dropped.
+    /// Returns `Err` if it can not be allocated.
+    pub fn new_thread(&self, tf: &TrapFrame, entry: usize, arg: usize, ustack_top: usize) -> Result<Self, ()> {
+        let kstack = ::memory::alloc_stack().ok_or(())?;
+        let token = self.memory_set.lock().token();
+        Ok(Context {
+            arch: unsafe { ArchContext::new_clone(tf, entry, arg, ustack_top, kstack.top, token) },
+            memory_set: self.memory_set.clone(),
+            kstack: Some(kstack),
+            signal_frames: Vec::new(),
+            restore_frame: None,
+            name: self.name.clone(),
+            lazy_fork: false,
+        })
+    }
+
+    /// Make the user program run `action` for `signal` when returning to user mode with `tf`.
+    ///
+    /// `tf` is saved, and restored after the handler returns to the restorer which calls `sigreturn`.
+    /// The handler runs on the alternate signal stack if there is one, see `MemorySet::signal_stack_top`.
+    /// Returns `Err` if the user stack is not writable, or handlers nest too deep.
+    pub fn enter_signal_handler(&mut self, tf: &mut TrapFrame, signal: Signal, action: SigAction) -> Result<(), ()> {
+        const MAX_NESTED_SIGNALS: usize = 16;
+        if self.signal_frames.len() >= MAX_NESTED_SIGNALS {
+            return Err(());
+        }
+        let saved = tf.clone();
+        {
+            let mut memory_set = self.memory_set.lock();
+            let sp = memory_set.signal_stack_top(tf.user_sp());
+            tf.call_signal_handler(action.handler, signal, action.restorer, sp, &mut |addr, data|
+                ::swap::with_swapper(|swapper| memory_set.copy_to_user(addr, data, swapper)))?;
+        }
+        self.signal_frames.push(saved);
+        Ok(())
+    }
+
+    /// Return from the latest signal handler, like `sigreturn`.
+    ///
+    /// The saved trap frame is restored by `restore_signal_frame` before returning to user mode,
+    /// so that it is not overwritten by the return value of the syscall.
+    /// Returns `Err` if no signal handler is running.
+    pub fn sigreturn(&mut self) -> Result<(), ()> {
+        self.restore_frame = Some(self.signal_frames.pop().ok_or(())?);
+        Ok(())
+    }
+
+    /// Overwrite `tf` with the trap frame to restore after `sigreturn` if any.
+    pub fn restore_signal_frame(&mut self, tf: &mut TrapFrame) {
+        if let Some(saved) = self.restore_frame.take() {
+            *tf = saved;
+        }
+    }
+}
+
+impl Drop for Context {
+    /// Frees the kernel stack of its own. It is dropped when reaped by `wait`, so it is not running.
+    fn drop(&mut self) {
+        if let Some(kstack) = self.kstack.take() {
+            ::memory::dealloc_stack(kstack);
+        }
+    }
+}
+
+/// Where the user stack and heap of a program are placed, see `Context::new_user_with_layout`.
+///
+/// Segments are placed by the ELF, and the stack top is lowered randomly like in `Program::parse`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MemoryLayout {
+    /// Top of the user stack of 64-bit programs, page aligned.
+    pub stack_top: usize,
+    /// Top of the user stack of 32-bit programs, page aligned.
+    pub stack32_top: usize,
+    /// Size the user stack can grow down to on faults, page aligned.
+    pub stack_size: usize,
+    /// Start of the heap, or `None` for right above the program.
+    pub heap_base: Option<usize>,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        use consts::{USER_STACK_OFFSET, USER_STACK_SIZE, USER_STACK_MAX_SIZE, USER32_STACK_OFFSET};
+        MemoryLayout {
+            stack_top: USER_STACK_OFFSET + USER_STACK_SIZE,
+            stack32_top: USER32_STACK_OFFSET + USER_STACK_SIZE,
+            stack_size: USER_STACK_MAX_SIZE,
+            heap_base: None,
+        }
+    }
+}
+
+/// Why a program can not be loaded by `Context::new_user` or `Context::exec`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LoadError {
+    /// Not a valid ELF file, for the reason.
+    Malformed(&'static str),
+    /// Built for another machine, see `check_machine`.
+    WrongMachine,
+    /// A 32-bit ELF where only 64-bit ones run, or the other way around.
+    WrongClass,
+    /// Neither an executable nor a position independent executable.
+    NotExecutable,
+    /// A valid executable which can not be loaded, for the reason.
+    Unsupported(&'static str),
+    /// Segments overlap with each other, or with the user stack or heap.
+    Overlap,
+    /// Frames ran out when mapping the program.
+    OutOfMemory,
+    /// The memory set is shared with other threads, see `Context::exec`.
+    Shared,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            LoadError::Malformed(reason) => write!(f, "malformed ELF: {}", reason),
+            LoadError::WrongMachine => write!(f, "ELF is for another machine"),
+            LoadError::WrongClass => write!(f, "ELF class is not supported on this machine"),
+            LoadError::NotExecutable => write!(f, "ELF is not executable"),
+            LoadError::Unsupported(reason) => write!(f, "{}", reason),
+            LoadError::Overlap => write!(f, "segments overlap"),
+            LoadError::OutOfMemory => write!(f, "out of memory"),
+            LoadError::Shared => write!(f, "memory is shared with other threads"),
+        }
+    }
+}
+
+impl From<PushError> for LoadError {
+    fn from(err: PushError) -> Self {
+        match err {
+            PushError::Overlap(_) => LoadError::Overlap,
+            PushError::OutOfMemory(_) => LoadError::OutOfMemory,
+            PushError::WriteExecute(_) => LoadError::Unsupported("writable and executable area"),
+        }
+    }
+}
+
+/// Checks the ELF is built for the machine the kernel runs on, returns whether it is 32-bit.
+///
+/// x86_64 runs 64-bit programs, and 32-bit ones for i386 in compatibility mode.
+/// RISC-V 32 runs 32-bit programs only, which `xmas_elf` knows no name for.
+fn check_machine(elf: &ElfFile) -> Result<bool, LoadError> {
+    use xmas_elf::header::{HeaderPt2, Machine};
+    let is32 = match elf.header.pt2 {
+        HeaderPt2::Header32(_) => true,
+        HeaderPt2::Header64(_) => false,
+    };
+    let machine = elf.header.pt2.machine().as_machine();
+    #[cfg(target_arch = "x86_64")]
+    return match (machine, is32) {
+        (Machine::X86_64, false) | (Machine::X86, true) => Ok(is32),
+        (Machine::X86_64, true) | (Machine::X86, false) => Err(LoadError::WrongClass),
+        _ => Err(LoadError::WrongMachine),
+    };
+    #[cfg(target_arch = "riscv32")]
+    return match (machine, is32) {
+        (Machine::Other(EM_RISCV), true) => Ok(is32),
+        (Machine::Other(EM_RISCV), false) => Err(LoadError::WrongClass),
+        _ => Err(LoadError::WrongMachine),
+    };
+}
+
+#[cfg(target_arch = "riscv32")]
+const EM_RISCV: u16 = 0xf3;
+
+/// A program parsed from ELF data, checked so that loading it can only fail if frames run out.
+struct Program<'a> {
+    elf: ElfFile<'a>,
+    data: &'a [u8],
+    is32: bool,
+    /// Added to every address in the ELF
+    bias: usize,
+    segments: Vec<(usize, usize, Flags)>,
+    init_stack: InitUserStack<'a>,
+    /// Initially mapped `[bottom, top)` of the user stack
+    stack: (usize, usize),
+    /// Size the user stack can grow down to, from `stack.1`
+    max_stack: usize,
+    heap_start: usize,
+    entry: usize,
+    sp: usize,
+}
+
+impl<'a> Program<'a> {
+    /// Parses and checks the program, placing it and its stack randomly by `rand`, see `aslr_random`.
+    ///
+    /// The stack and the heap are placed by `layout`.
+    fn parse(data: &'a [u8], args: &'a [&'a str], envs: &'a [&'a str], layout: &MemoryLayout,
+             rand: &mut FnMut() -> usize) -> Result<Self, LoadError>
+    {
+        use ucore_memory::{PAGE_SIZE, memory_set::STACK_ALIGN};
         // Parse elf
-        let elf = ElfFile::new(data).expect("failed to read elf");
-        let is32 = match elf.header.pt2 {
-            header::HeaderPt2::Header32(_) => true,
-            header::HeaderPt2::Header64(_) => false,
+        let elf = ElfFile::new(data).map_err(LoadError::Malformed)?;
+        let is32 = check_machine(&elf)?;
+        let bias = match elf.header.pt2.type_().as_type() {
+            header::Type::Executable => 0,
+            header::Type::SharedObject => {
+                if elf.program_iter().any(|ph| ph.get_type() == Ok(Type::Interp)) {
+                    return Err(LoadError::Unsupported("dynamically linked ELF is not supported"));
+                }
+                use consts::{USER_PIE_BIAS, USER_PIE_RANDOM_MASK};
+                USER_PIE_BIAS + (rand() & USER_PIE_RANDOM_MASK) * PAGE_SIZE
+            }
+            _ => return Err(LoadError::NotExecutable),
         };
-        assert_eq!(elf.header.pt2.type_().as_type(), header::Type::Executable, "ELF is not executable");
+        for ph in elf.program_iter().filter(|ph| ph.get_type() == Ok(Type::Load)) {
+            if ph.offset() + ph.file_size() > data.len() as u64 || ph.file_size() > ph.mem_size() {
+                return Err(LoadError::Malformed("segment out of ELF"));
+            }
+        }
+        let segments = segment_pages(&elf, bias);
+        // Refused early, `MemorySet::set_enforce_wx` would only refuse them halfway through `load`
+        if USER_ENFORCE_WX {
+            if segments.iter().any(|&(_, _, flags)| memory_attr_from(flags).is_wx()) {
+                return Err(LoadError::Unsupported("writable and executable segment"));
+            }
+            if stack_attr_from(&elf).is_wx() {
+                return Err(LoadError::Unsupported("executable stack"));
+            }
+        }
 
-        // User stack
-        use consts::{USER_STACK_OFFSET, USER_STACK_SIZE, USER32_STACK_OFFSET};
-        let (user_stack_buttom, user_stack_top) = match is32 {
-            true => (USER32_STACK_OFFSET, USER32_STACK_OFFSET + USER_STACK_SIZE),
-            false => (USER_STACK_OFFSET, USER_STACK_OFFSET + USER_STACK_SIZE),
+        // User stack, only the top is mapped at first and it grows down on faults
+        use consts::{USER_STACK_INIT_SIZE, USER_STACK_RANDOM_MASK};
+        let fixed_top = match is32 {
+            true => layout.stack32_top,
+            false => layout.stack_top,
+        };
+        let max_stack = layout.stack_size;
+        // The stack lowered the most, its guard page, and a page for other areas must fit below the top
+        let lowest = (USER_STACK_RANDOM_MASK + 3) * PAGE_SIZE;
+        if fixed_top % PAGE_SIZE != 0 || max_stack % PAGE_SIZE != 0
+            || max_stack.checked_add(lowest).map_or(true, |size| size > fixed_top) {
+            return Err(LoadError::Unsupported("invalid memory layout"));
+        }
+        let init_stack = InitUserStack::new(args, envs, is32);
+        // Room for the initial content, which is written before the stack can grow
+        let init_size = (init_stack.size() + STACK_ALIGN + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        if init_size > max_stack {
+            return Err(LoadError::Unsupported("arguments are too long"));
+        }
+        let size = init_size.max(USER_STACK_INIT_SIZE).min(max_stack);
+        // All the stack may grow to, including the guard page below
+        let overlaps = |top: usize| segments.iter()
+            .any(|&(start, end, _)| start < top && top - max_stack - PAGE_SIZE < end);
+        // Lowered randomly, back to the fixed place if that hits the segments
+        let top = match fixed_top - (rand() & USER_STACK_RANDOM_MASK) * PAGE_SIZE {
+            top if !overlaps(top) => top,
+            _ if !overlaps(fixed_top) => fixed_top,
+            _ => return Err(LoadError::Overlap),
         };
+        let bottom = top - size;
 
-        // Make page table
-        let mut memory_set = memory_set_from(&elf);
-        memory_set.push(MemoryArea::new(user_stack_buttom, user_stack_top, MemoryAttr::default().user(), "user_stack"));
-        trace!("{:#x?}", memory_set);
+        // Empty heap, grown by `set_brk` up to the stack
+        let program_end = segments.iter().map(|&(_, end, _)| end).max().unwrap_or(0);
+        let heap_start = match layout.heap_base {
+            None => program_end,
+            Some(base) if base >= program_end && base <= top - max_stack - PAGE_SIZE => base,
+            Some(_) => return Err(LoadError::Overlap),
+        };
 
-        let entry_addr = elf.header.pt2.entry_point() as usize;
+        let entry = elf.header.pt2.entry_point() as usize + bias;
+        let sp = Stack::new(bottom, top).aligned_top(init_stack.size());
+        Ok(Program { elf, data, is32, bias, segments, init_stack, stack: (bottom, top), max_stack, heap_start,
+                     entry, sp })
+    }
+
+    /// Maps the program and the user stack into the empty `memory_set`, and copies them.
+    ///
+    /// Returns `Err` if they can not be mapped, then `memory_set` may be left partly loaded,
+    /// and should be dropped.
+    fn load(&self, memory_set: &mut MemorySet) -> Result<(), LoadError> {
+        use ucore_memory::PAGE_SIZE;
+        let (bottom, top) = self.stack;
+        push_segments(memory_set, &self.segments)?;
+        memory_set.try_push(MemoryArea::new(bottom, top, stack_attr_from(&self.elf), "user_stack"))
+            .map_err(LoadError::from)?;
+        // Overflowing the stack faults in the guard page, instead of corrupting areas below
+        memory_set.try_push(MemoryArea::new_guard(bottom - PAGE_SIZE, bottom, "user_stack_guard"))
+            .map_err(LoadError::from)?;
+        // Empty heap, grown by `set_brk`
+        let heap = MemoryArea::new_lazy(self.heap_start, self.heap_start, MemoryAttr::default().user(), "heap");
+        memory_set.push_heap(heap);
+        // The stack grows down on faults in or below the guard, up to the max size.
+        // Place areas without a fixed address below that, so that the stack can grow.
+        memory_set.set_max_stack(self.max_stack);
+        memory_set.set_user_space(PAGE_SIZE, top - self.max_stack - PAGE_SIZE);
+        trace!("{:#x?}", memory_set);
 
         // Temporary switch to it, in order to copy data
         unsafe {
             memory_set.with(|| {
-                for ph in elf.program_iter() {
-                    let virt_addr = ph.virtual_addr() as usize;
+                // Zero the freshly allocated frames first, so that the bss and the parts of pages
+                // not in any segment are zero, even in a page shared by segments.
+                for &(start, end, _) in self.segments.iter() {
+                    memory_set.zero_range(start, end);
+                }
+                for ph in self.elf.program_iter().filter(|ph| ph.get_type() == Ok(Type::Load)) {
+                    let virt_addr = ph.virtual_addr() as usize + self.bias;
                     let offset = ph.offset() as usize;
                     let file_size = ph.file_size() as usize;
-                    if file_size == 0 {
-                        return;
-                    }
                     use core::slice;
                     let target = unsafe { slice::from_raw_parts_mut(virt_addr as *mut u8, file_size) };
-                    target.copy_from_slice(&data[offset..offset + file_size]);
-                }
-                if is32 {
-                    unsafe {
-                        // TODO: full argc & argv
-                        *(user_stack_top as *mut u32).offset(-1) = 0; // argv
-                        *(user_stack_top as *mut u32).offset(-2) = 0; // argc
-                    }
+                    target.copy_from_slice(&self.data[offset..offset + file_size]);
                 }
+                unsafe { self.init_stack.write(self.sp); }
             });
         }
-
-        Context {
-            arch: unsafe {
-                ArchContext::new_user_thread(
-                    entry_addr, user_stack_top - 8, memory_set.kstack_top(), is32, memory_set.token())
-            },
-            memory_set,
+        // Segments are writable to be copied above, now set their actual attributes
+        for &(start, end, flags) in self.segments.iter() {
+            memory_set.mprotect(start, end - start, memory_attr_from(flags))
+                .map_err(|()| LoadError::Unsupported("segment can not be protected"))?;
         }
+        Ok(())
     }
+}
 
-    /// Fork
-    pub fn fork(&self, tf: &TrapFrame) -> Self {
-        // Clone memory set, make a new page table
-        let memory_set = self.memory_set.clone();
+/// Initial content of user stack at program entry, as System V ABI:
+///
+///     sp -> argc
+///           argv[0..argc], NULL
+///           envp[..], NULL
+///           auxv: AT_NULL, 0
+///           strings of argv & envp
+///
+/// Words are 4 bytes for 32-bit programs, 8 bytes for 64-bit ones.
+struct InitUserStack<'a> {
+    args: &'a [&'a str],
+    envs: &'a [&'a str],
+    word_size: usize,
+}
 
-        // Copy data to temp space
-        use alloc::vec::Vec;
-        let datas: Vec<Vec<u8>> = memory_set.iter().map(|area| {
-            Vec::from(unsafe { area.as_slice() })
-        }).collect();
+impl<'a> InitUserStack<'a> {
+    fn new(args: &'a [&'a str], envs: &'a [&'a str], is32: bool) -> Self {
+        InitUserStack { args, envs, word_size: if is32 { 4 } else { 8 } }
+    }
 
-        // Temporary switch to it, in order to copy data
-        unsafe {
-            memory_set.with(|| {
-                for (area, data) in memory_set.iter().zip(datas.iter()) {
-                    unsafe { area.as_slice_mut() }.copy_from_slice(data.as_slice())
-                }
-            });
-        }
+    fn words(&self) -> usize {
+        1 + self.args.len() + 1 + self.envs.len() + 1 + 2
+    }
 
-        Context {
-            arch: unsafe { ArchContext::new_fork(tf, memory_set.kstack_top(), memory_set.token()) },
-            memory_set,
+    /// Bytes taken above `sp`.
+    fn size(&self) -> usize {
+        let strings: usize = self.args.iter().chain(self.envs.iter()).map(|s| s.len() + 1).sum();
+        self.words() * self.word_size + strings
+    }
+
+    /// Writes to the user stack at `sp`, with the page table of the program activated.
+    unsafe fn write(&self, sp: usize) {
+        use core::ptr;
+        let mut words = Vec::with_capacity(self.words());
+        let mut string = sp + self.words() * self.word_size;
+        let mut push_strings = |words: &mut Vec<usize>, strings: &[&str]| {
+            for s in strings {
+                ptr::copy_nonoverlapping(s.as_ptr(), string as *mut u8, s.len());
+                *((string + s.len()) as *mut u8) = 0;
+                words.push(string);
+                string += s.len() + 1;
+            }
+            words.push(0);
+        };
+        words.push(self.args.len());
+        push_strings(&mut words, self.args);
+        push_strings(&mut words, self.envs);
+        words.extend_from_slice(&[0, 0]);   // AT_NULL
+
+        for (i, &word) in words.iter().enumerate() {
+            match self.word_size {
+                4 => *(sp as *mut u32).add(i) = word as u32,
+                _ => *(sp as *mut u64).add(i) = word as u64,
+            }
         }
     }
 }
@@ -132,26 +637,126 @@ impl Debug for Context {
     }
 }
 
-/// Construct memory area & page table from elf file.
-fn memory_set_from<'a>(elf: &'a ElfFile<'a>) -> MemorySet {
-    let mut set = MemorySet::new();
-    for ph in elf.program_iter() {
-        if ph.get_type() != Ok(Type::Load) {
-            continue;
-        }
-        let (virt_addr, mem_size, flags) = match ph {
-            ProgramHeader::Ph32(ph) => (ph.virtual_addr as usize, ph.mem_size as usize, ph.flags),
-            ProgramHeader::Ph64(ph) => (ph.virtual_addr as usize, ph.mem_size as usize, ph.flags),
+/// Name of a program run with `args`: the file name of `args[0]`, without the directories.
+fn program_name(args: &[&str]) -> Option<String> {
+    args.first().map(|&path| String::from(path.rsplit('/').next().unwrap()))
+}
+
+/// State of the generator of `aslr_random`.
+static ASLR_STATE: AtomicUsize = AtomicUsize::new(0x2545_f491);
+
+/// Sets the seed of `aslr_random`, e.g. from a timer at boot, or a constant to reproduce layouts.
+pub fn seed_aslr(seed: usize) {
+    // xorshift gets stuck at 0
+    ASLR_STATE.store(seed as u32 as usize | 1, Ordering::Relaxed);
+}
+
+/// Random number for address space layout randomization, by 32-bit xorshift.
+///
+/// It scatters layouts, but it's not cryptographically secure.
+/// Always 0 with feature `no_aslr`, so that programs are placed at fixed addresses.
+fn aslr_random() -> usize {
+    if cfg!(feature = "no_aslr") {
+        return 0;
+    }
+    // Concurrent callers may get the same number, which only makes layouts less random.
+    let mut x = ASLR_STATE.load(Ordering::Relaxed) as u32;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    ASLR_STATE.store(x as usize, Ordering::Relaxed);
+    x as usize
+}
+
+/// Page aligned ranges of the loadable segments moved up by `bias`, with their flags, in order.
+///
+/// Segments may not start or end at page boundaries, e.g. the end of .text and the start of .data
+/// often share a page. Segments may even overlap, in any order of program headers.
+/// A page has the flags of all segments in it, then adjacent ranges with the same flags are merged,
+/// so that ranges never overlap and cover every page of every segment.
+/// So a page shared by a writable segment and an executable one is both, which `Program::parse` refuses.
+fn segment_pages(elf: &ElfFile, bias: usize) -> Vec<(usize, usize, Flags)> {
+    use ucore_memory::PAGE_SIZE;
+    let segments: Vec<(usize, usize, Flags)> = elf.program_iter()
+        .filter(|ph| ph.get_type() == Ok(Type::Load) && ph.mem_size() != 0)
+        .map(|ph| {
+            let start = ph.virtual_addr() as usize + bias;
+            let end = start + ph.mem_size() as usize;
+            (start / PAGE_SIZE * PAGE_SIZE, (end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE, ph.flags())
+        })
+        .collect();
+    use core::iter::once;
+    let mut bounds: Vec<usize> = segments.iter().flat_map(|&(start, end, _)| once(start).chain(once(end))).collect();
+    bounds.sort();
+    bounds.dedup();
+    let mut ranges: Vec<(usize, usize, Flags)> = Vec::new();
+    for bound in bounds.windows(2) {
+        let (start, end) = (bound[0], bound[1]);
+        let flags = segments.iter()
+            .filter(|&&(seg_start, seg_end, _)| seg_start <= start && end <= seg_end)
+            .fold(None, |flags: Option<u32>, &(_, _, seg_flags)| Some(flags.unwrap_or(0) | seg_flags.0));
+        let flags = match flags {
+            Some(flags) => Flags(flags),
+            None => continue,   // A gap between segments
         };
-        set.push(MemoryArea::new(virt_addr, virt_addr + mem_size, memory_attr_from(flags), ""));
+        match ranges.last_mut() {
+            Some(last) if last.1 == start && (last.2).0 == flags.0 => last.1 = end,
+            _ => ranges.push((start, end, flags)),
+        }
+    }
+    ranges
+}
+
+/// Maps page ranges of segments into `set`, see `segment_pages`.
+///
+/// All segments are mapped in a single page table edit, or none if frames run out.
+/// They are writable for loading, see `memory_attr_from` for their actual attributes.
+fn push_segments(set: &mut MemorySet, segments: &[(usize, usize, Flags)]) -> Result<(), LoadError> {
+    set.try_push_all(segments.iter()
+        .map(|&(start, end, _)| MemoryArea::new(start, end, MemoryAttr::default().user(), "")))
+        .map_err(LoadError::from)
+}
+
+/// Type of the program header deciding if the stack is executable, not in `xmas_elf::program::Type`.
+const PT_GNU_STACK: u32 = 0x6474_e551;
+
+/// Attributes of the user stack, decided by the `PT_GNU_STACK` header of `elf`.
+fn stack_attr_from(elf: &ElfFile) -> MemoryAttr {
+    let stack = elf.program_iter().find(|ph| ph.get_type() == Ok(Type::OsSpecific(PT_GNU_STACK)));
+    stack_attr(stack.map(|ph| ph.flags()))
+}
+
+/// Attributes of the user stack, executable only if the `PT_GNU_STACK` flags request it.
+///
+/// The stack is then writable and executable, so such programs are refused by `Program::parse`
+/// and only run with feature `allow_wx`. Without the header the stack is not executable.
+fn stack_attr(gnu_stack: Option<Flags>) -> MemoryAttr {
+    match gnu_stack {
+        Some(flags) if flags.is_execute() => MemoryAttr::default().user().execute(),
+        _ => MemoryAttr::default().user(),
     }
-    set
 }
 
 /// Extract memory area attributes from elf prog header
 fn memory_attr_from(elf_flags: Flags) -> MemoryAttr {
     let mut flags = MemoryAttr::default().user();
-    // TODO: handle readonly
+    if !elf_flags.is_write() { flags = flags.readonly(); }
     if elf_flags.is_execute() { flags = flags.execute(); }
     flags
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gnu_stack() {
+        // Header absent
+        assert_eq!(stack_attr(None), MemoryAttr::default().user());
+        // RW
+        assert_eq!(stack_attr(Some(Flags(6))), MemoryAttr::default().user());
+        // RWX
+        let attr = stack_attr(Some(Flags(7)));
+        assert_eq!(attr, MemoryAttr::default().user().execute());
+        assert!(attr.is_wx());
+    }
+}
@@ -1,20 +1,28 @@
 //! Process management module.
 //! Scheduler/Thread models are implemented in ucore_process crate.
 
+use alloc::vec::Vec;
+use arch::cpu;
+use consts::MAX_CPU_NUM;
+use memory::MemorySet;
 use spin::Once;
 use sync::{SpinNoIrqLock, Mutex, MutexGuard, SpinNoIrq};
-pub use self::context::Context;
+pub use self::context::{Context, LoadError, MemoryLayout, seed_aslr};
 pub use ucore_process::processor::{*, Context as _whatever};
 pub use ucore_process::scheduler::*;
+pub use ucore_process::signal::*;
 pub use ucore_process::thread::*;
 
 mod context;
 
 type Processor = Processor_<Context, StrideScheduler>;
 
-/// Inits processor and adds an idle kernel thread.
+/// Inits the processor of the current CPU and adds an idle kernel thread.
+///
+/// Must be called once on every CPU before it schedules.
+/// Each CPU has its own processor and run queue.
 pub fn init() {
-    PROCESSOR.call_once(||
+    PROCESSORS[cpu::id()].call_once(||
         SpinNoIrqLock::new({
             let mut processor = Processor::new(
                 unsafe { Context::new_init() },
@@ -22,20 +30,75 @@ pub fn init() {
                 StrideScheduler::new(5),
             );
             extern fn idle(arg: usize) -> ! {
-                loop {}
+                loop { cpu::wait_for_interrupt(); }
             }
-            processor.add(Context::new_kernel(idle, 0));
+            processor.set_idle(Context::new_kernel(idle, 0));
             processor
         })
     );
     info!("process init end");
 }
 
-pub static PROCESSOR: Once<SpinNoIrqLock<Processor>> = Once::new();
+lazy_static! {
+    /// Processors of all CPUs, indexed by CPU id.
+    static ref PROCESSORS: Vec<Once<SpinNoIrqLock<Processor>>> = (0..MAX_CPU_NUM).map(|_| Once::new()).collect();
+}
+
+/// Returns the processor of the current CPU, or `None` if it is not inited.
+pub fn local_processor() -> Option<&'static SpinNoIrqLock<Processor>> {
+    PROCESSORS[cpu::id()].try()
+}
 
-/// The only way to get processor.
+/// The only way to get the processor of the current CPU.
 pub fn processor() -> MutexGuard<'static, Processor, SpinNoIrq> {
-    PROCESSOR.try().unwrap().lock()
+    local_processor().unwrap().lock()
+}
+
+/// Run `f` with the memory set of the current process, e.g. for syscalls like `mmap` and `brk`.
+///
+/// Its page table is the active one, so `f` may change the address space.
+/// The processor is unlocked meanwhile, only the memory set is locked.
+/// So a page fault in `f` is not handled by `MemorySet::handle_fault`, see `Context::memory_set`.
+pub fn with_current_memory_set<R>(f: impl FnOnce(&mut MemorySet) -> R) -> R {
+    let memory_set = processor().current_context().shared_memory_set();
+    let mut memory_set = memory_set.lock();
+    f(&mut memory_set)
+}
+
+/// Access to the memory set of the current process, with the processor locked.
+pub trait CurrentMemorySet {
+    /// Lock the memory set of the current process to change mappings, see `Context::memory_set_mut`.
+    ///
+    /// Prefer `with_current_memory_set`, which does not keep the processor locked.
+    fn current_memory_set_mut(&mut self) -> MutexGuard<MemorySet, SpinNoIrq>;
+}
+
+impl CurrentMemorySet for Processor {
+    fn current_memory_set_mut(&mut self) -> MutexGuard<MemorySet, SpinNoIrq> {
+        self.current_context_mut().memory_set_mut()
+    }
+}
+
+/// Steal a ready process from other CPUs into `processor` of the current CPU.
+///
+/// Other processors are only `try_lock`ed, so that two idle CPUs
+/// stealing from each other at the same time can never deadlock.
+pub fn steal(processor: &mut Processor) -> Option<Pid> {
+    let id = cpu::id();
+    for (i, other) in PROCESSORS.iter().enumerate() {
+        if i == id {
+            continue;
+        }
+        let mut other = match other.try().and_then(|other| other.try_lock()) {
+            Some(other) => other,
+            None => continue,
+        };
+        if let Some(pid) = processor.steal_from(&mut other) {
+            info!("CPU {} steal process {} from CPU {}", id, pid, i);
+            return Some(pid);
+        }
+    }
+    None
 }
 
 #[allow(non_camel_case_types)]
@@ -0,0 +1,107 @@
+//! Swap space for pages of user processes, see `MemorySet::swap_out_page`.
+
+use bit_allocator::{BitAlloc, BitAlloc4K};
+use simple_filesystem::BlockedDevice;
+use spin::Mutex;
+use ucore_memory::PAGE_SIZE;
+use ucore_memory::swap::Swapper;
+#[cfg(target_arch = "x86_64")]
+use arch::driver::ide;
+
+/// Number of page slots in the swap space, 16M in total.
+const SWAP_SLOTS: usize = 4096;
+
+/// Swap space on a block device, a page in each slot of consecutive blocks.
+///
+/// Slots are allocated in memory, so the device needs no format, and its content is not kept across boots.
+pub struct DiskSwapper<D: BlockedDevice> {
+    device: D,
+    slots: BitAlloc4K,
+}
+
+impl<D: BlockedDevice> DiskSwapper<D> {
+    pub fn new(device: D) -> Self {
+        let mut slots = BitAlloc4K::default();
+        slots.insert(0..SWAP_SLOTS);
+        DiskSwapper { device, slots }
+    }
+
+    /// Frees slot `token`, see `InactivePageTable::swap_free`.
+    pub fn free(&mut self, token: usize) {
+        assert!(!self.slots.test(token), "swap slot {} is not allocated", token);
+        self.slots.dealloc(token);
+    }
+
+    fn write(&mut self, token: usize, data: &[u8]) -> Result<(), ()> {
+        let block_size = 1 << D::BLOCK_SIZE_LOG2;
+        let first = token * (PAGE_SIZE / block_size);
+        for (i, block) in data.chunks(block_size).enumerate() {
+            if !self.device.write_at(first + i, block) {
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        let block_size = 1 << D::BLOCK_SIZE_LOG2;
+        let first = token * (PAGE_SIZE / block_size);
+        for (i, block) in data.chunks_mut(block_size).enumerate() {
+            if !self.device.read_at(first + i, block) {
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockedDevice> Swapper for DiskSwapper<D> {
+    fn swap_out(&mut self, data: &[u8]) -> Result<usize, ()> {
+        let token = self.slots.alloc().ok_or(())?;
+        if self.write(token, data).is_err() {
+            self.slots.dealloc(token);
+            return Err(());
+        }
+        Ok(token)
+    }
+
+    fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()> {
+        self.write(token, data)
+    }
+
+    fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        self.read(token, data)?;
+        self.free(token);
+        Ok(())
+    }
+
+    fn swap_read(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        self.read(token, data)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+lazy_static! {
+    /// On the third IDE disk, see `Makefile`.
+    static ref SWAPPER: Mutex<DiskSwapper<&'static ide::LockedIde>> = Mutex::new(DiskSwapper::new(&*ide::DISK2));
+}
+
+/// Calls `f` with the swapper of the kernel, or `None` if there is no swap space.
+///
+/// Lock order: after the memory set. `f` must not unmap anything, as it frees swap slots by `swap_free`.
+#[cfg(target_arch = "x86_64")]
+pub fn with_swapper<R>(f: impl FnOnce(Option<&mut Swapper>) -> R) -> R {
+    f(Some(&mut *SWAPPER.lock()))
+}
+
+/// There is no disk to swap to on RISC-V.
+#[cfg(target_arch = "riscv32")]
+pub fn with_swapper<R>(f: impl FnOnce(Option<&mut Swapper>) -> R) -> R {
+    f(None)
+}
+
+/// Frees a swap slot of a page being unmapped, see `InactivePageTable::swap_free`.
+#[cfg(target_arch = "x86_64")]
+pub fn swap_free(token: usize) {
+    SWAPPER.lock().free(token);
+}
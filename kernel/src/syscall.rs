@@ -2,6 +2,7 @@
 
 #![allow(unused)]
 
+use alloc::string::String;
 use arch::interrupt::TrapFrame;
 use process::*;
 use thread;
@@ -10,13 +11,16 @@ use util;
 /// 系统调用入口点
 ///
 /// 当发生系统调用中断时，中断服务例程将控制权转移到这里。
-pub fn syscall(id: usize, args: [usize; 6], tf: &TrapFrame) -> i32 {
+pub fn syscall(id: usize, args: [usize; 6], tf: &mut TrapFrame) -> i32 {
     match id {
         SYS_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
         SYS_OPEN => sys_open(args[0] as *const u8, args[1]),
         SYS_CLOSE => sys_close(args[0]),
         SYS_WAIT => sys_wait(args[0], args[1] as *mut i32),
         SYS_FORK => sys_fork(tf),
+        SYS_VFORK => sys_vfork(tf),
+        SYS_EXEC => sys_exec(args[0] as *const u8, tf),
+        SYS_CLONE => sys_clone(tf, args[0], args[1], args[2]),
         SYS_KILL => sys_kill(args[0]),
         SYS_EXIT => sys_exit(args[0]),
         SYS_YIELD => sys_yield(),
@@ -25,26 +29,38 @@ pub fn syscall(id: usize, args: [usize; 6], tf: &TrapFrame) -> i32 {
         SYS_GETTIME => sys_get_time(),
         SYS_LAB6_SET_PRIORITY => sys_lab6_set_priority(args[0]),
         SYS_PUTC => sys_putc(args[0] as u8 as char),
+        SYS_SIGALTSTACK => sys_sigaltstack(args[0], args[1]),
+        SYS_SIGACTION => sys_sigaction(args[0], args[1], args[2]),
+        SYS_SIGRETURN => sys_sigreturn(),
         _ => {
             error!("unknown syscall id: {:#x?}, args: {:x?}", id, args);
-            ::trap::error(tf);
+            ::trap::error(tf, ::process::SIGSYS);
         }
     }
 }
 
 fn sys_write(fd: usize, base: *const u8, len: usize) -> i32 {
     info!("write: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
-    use core::slice;
+    use alloc::vec::Vec;
     use core::str;
-    let slice = unsafe { slice::from_raw_parts(base, len) };
-    print!("{}", str::from_utf8(slice).unwrap());
-    0
+    let mut buf = Vec::new();
+    buf.resize(len, 0u8);
+    if copy_from_user(base as usize, &mut buf).is_err() {
+        return -1;
+    }
+    match str::from_utf8(&buf) {
+        Ok(s) => { print!("{}", s); 0 }
+        Err(_) => -1,
+    }
 }
 
 fn sys_open(path: *const u8, flags: usize) -> i32 {
-    let path = unsafe { util::from_cstr(path) };
+    let path = match read_user_cstr(path as usize) {
+        Some(path) => path,
+        None => return -1,
+    };
     info!("open: path: {:?}, flags: {:?}", path, flags);
-    match path {
+    match path.as_str() {
         "stdin:" => 0,
         "stdout:" => 1,
         _ => -1,
@@ -59,12 +75,79 @@ fn sys_close(fd: usize) -> i32 {
 /// Fork the current process. Return the child's PID.
 fn sys_fork(tf: &TrapFrame) -> i32 {
     let mut processor = processor();
-    let context = processor.current_context().fork(tf);
+    let context = match processor.current_context_mut().fork(tf) {
+        Ok(context) => context,
+        Err(()) => return -1,
+    };
     let pid = processor.add(context);
+    processor.inherit_sigactions(pid);
     info!("fork: {} -> {}", processor.current_pid(), pid);
     pid as i32
 }
 
+/// Fork the current process without copying its memory, like `vfork`. Return the child's PID.
+///
+/// The child borrows the memory of the parent, which is blocked until the child calls `exec` or exits,
+/// see `Context::fork_lazy`.
+fn sys_vfork(tf: &TrapFrame) -> i32 {
+    let mut processor = processor();
+    let context = match processor.current_context().fork_lazy(tf) {
+        Ok(context) => context,
+        Err(()) => return -1,
+    };
+    let pid = processor.add(context);
+    processor.inherit_sigactions(pid);
+    info!("vfork: {} -> {}", processor.current_pid(), pid);
+    processor.vfork_wait(pid);
+    pid as i32
+}
+
+/// Replace the program of the current process with user program `name`, see `Context::exec`.
+///
+/// Arguments are not passed yet, the program gets its name only.
+/// Refused if other threads share the memory. Returns 0 to the new program on success.
+fn sys_exec(name: *const u8, tf: &mut TrapFrame) -> i32 {
+    use alloc::vec::Vec;
+    const MAX_PROGRAM_SIZE: usize = 0x40000;
+    let name = match read_user_cstr(name as usize) {
+        Some(name) => name,
+        None => return -1,
+    };
+    let mut buf = Vec::new();
+    buf.resize(MAX_PROGRAM_SIZE, 0u8);
+    let len = match ::fs::read_program(&name, &mut buf) {
+        Some(len) => len,
+        None => return -1,
+    };
+    let mut processor = processor();
+    let pid = processor.current_pid();
+    match processor.current_context_mut().exec(&buf[..len], &[&name], tf) {
+        Ok(()) => {
+            info!("exec: {} {}", pid, name);
+            // A child of `vfork` no longer borrows the memory of its parent
+            processor.release_vfork(pid);
+            0
+        }
+        Err(err) => {
+            info!("exec: {} failed to load {}: {}", pid, name, err);
+            -1
+        }
+    }
+}
+
+/// Create a thread sharing memory with the current process,
+/// running `entry(arg)` on the user stack at `stack_top`. Return the thread's PID.
+fn sys_clone(tf: &TrapFrame, entry: usize, arg: usize, stack_top: usize) -> i32 {
+    let mut processor = processor();
+    let context = match processor.current_context().new_thread(tf, entry, arg, stack_top) {
+        Ok(context) => context,
+        Err(()) => return -1,
+    };
+    let pid = processor.add(context);
+    info!("clone: {} -> {}", processor.current_pid(), pid);
+    pid as i32
+}
+
 /// Wait the process exit.
 /// 
 /// Return the PID. Store exit code to `code` if it's not null.
@@ -73,7 +156,13 @@ fn sys_wait(pid: usize, code: *mut i32) -> i32 {
     match processor.current_wait_for(pid) {
         WaitResult::Ok(pid, error_code) => {
             if !code.is_null() {
-                unsafe { *code = error_code as i32 };
+                use core::mem::transmute;
+                let bytes: [u8; 4] = unsafe { transmute(error_code as i32) };
+                let mut memory_set = processor.current_context().memory_set();
+                let result = ::swap::with_swapper(|swapper| memory_set.copy_to_user(code as usize, &bytes, swapper));
+                if result.is_err() {
+                    return -1;
+                }
             }
             0
         }
@@ -105,6 +194,48 @@ fn sys_exit(error_code: usize) -> i32 {
     0
 }
 
+/// Set the alternate signal stack of the current process to `[base, base + size)`.
+///
+/// The stack must be mapped user writable. Unset it if `base` is 0.
+fn sys_sigaltstack(base: usize, size: usize) -> i32 {
+    with_current_memory_set(|memory_set| {
+        if base == 0 {
+            memory_set.clear_alt_stack();
+            return 0;
+        }
+        match memory_set.set_alt_stack(base, size) {
+            Ok(()) => 0,
+            Err(()) => -1,
+        }
+    })
+}
+
+/// Install `handler` for `signal`, or restore the default action if it is 0.
+///
+/// The handler is called as `handler(signal)` and returns to `restorer`,
+/// which should call `sigreturn` to resume the interrupted program.
+fn sys_sigaction(signal: usize, handler: usize, restorer: usize) -> i32 {
+    let mut processor = processor();
+    let action = match handler {
+        0 => None,
+        _ => Some(SigAction { handler, restorer }),
+    };
+    let pid = processor.current_pid();
+    match processor.set_sigaction(pid, signal, action) {
+        Ok(()) => 0,
+        Err(()) => -1,
+    }
+}
+
+/// Return from a signal handler, resuming where the signal interrupted.
+fn sys_sigreturn() -> i32 {
+    let mut processor = processor();
+    match processor.current_context_mut().sigreturn() {
+        Ok(()) => 0,
+        Err(()) => -1,
+    }
+}
+
 fn sys_sleep(time: usize) -> i32 {
     use core::time::Duration;
     thread::sleep(Duration::from_millis(time as u64 * 10));
@@ -132,6 +263,7 @@ const SYS_FORK: usize = 2;
 const SYS_WAIT: usize = 3;
 const SYS_EXEC: usize = 4;
 const SYS_CLONE: usize = 5;
+const SYS_VFORK: usize = 6;
 const SYS_YIELD: usize = 10;
 const SYS_SLEEP: usize = 11;
 const SYS_KILL: usize = 12;
@@ -152,4 +284,32 @@ const SYS_FSYNC: usize = 111;
 const SYS_GETCWD: usize = 121;
 const SYS_GETDIRENTRY: usize = 128;
 const SYS_DUP: usize = 130;
+const SYS_SIGALTSTACK: usize = 131;
+const SYS_SIGACTION: usize = 132;
+const SYS_SIGRETURN: usize = 133;
 const SYS_LAB6_SET_PRIORITY: usize = 255;
+
+/// Copies `buf.len()` bytes from user address `addr` of the current process.
+///
+/// Returns `Err` if the range is not readable by the user, see `MemorySet::check_access`.
+fn copy_from_user(addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+    with_current_memory_set(|memory_set| ::swap::with_swapper(|swapper| memory_set.copy_from_user(addr, buf, swapper)))
+}
+
+/// Max length of a string passed by the user, excluding the terminating NUL.
+const MAX_USER_STR_LEN: usize = 255;
+
+/// Reads a NUL terminated UTF-8 string from user address `addr`.
+///
+/// Returns `None` if it is unreadable, too long or not UTF-8.
+fn read_user_cstr(addr: usize) -> Option<String> {
+    use alloc::vec::Vec;
+    let bytes = with_current_memory_set(|memory_set| {
+        let len = ::swap::with_swapper(|swapper| memory_set.validate_str(addr, MAX_USER_STR_LEN + 1, swapper))?;
+        let mut bytes = Vec::new();
+        bytes.resize(len, 0u8);
+        ::swap::with_swapper(|swapper| memory_set.copy_from_user(addr, &mut bytes, swapper)).ok()?;
+        Some(bytes)
+    })?;
+    String::from_utf8(bytes).ok()
+}
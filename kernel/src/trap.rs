@@ -4,16 +4,77 @@ use process::*;
 use arch::interrupt::TrapFrame;
 
 /// Called in timer interrupt.
+///
+/// Ticks the processor of the current CPU, and samples page access every few ticks.
+/// Ignored if the processor of the CPU is not inited yet, e.g. on a CPU just booted.
 pub fn timer() {
-    let mut processor = processor();
+    const ACCESS_SAMPLE_INTERVAL: usize = 10;
+    let mut processor = match local_processor() {
+        Some(processor) => processor.lock(),
+        None => return,
+    };
     processor.tick();
+    if processor.get_time() % ACCESS_SAMPLE_INTERVAL == 0 {
+        ::memory::sample_access();
+    }
 }
 
 /// Called before return from interrupt handler.
-pub fn before_return() {
-    if let Some(processor) = PROCESSOR.try() {
-        processor.lock().schedule();
+///
+/// If returning to user mode, restores the trap frame saved for a signal handler
+/// after `sigreturn`, and delivers a pending signal to its handler if installed.
+/// A process whose handler can not be set up is killed by `SIGSEGV`.
+/// Then schedules on the processor of the current CPU if the current
+/// process used up its slice and preemption is enabled.
+/// If it has nothing to run, try to steal work from other CPUs first.
+pub fn before_return(tf: &mut TrapFrame) {
+    if let Some(processor) = local_processor() {
+        let mut processor = processor.lock();
+        if tf.is_user() {
+            processor.current_context_mut().restore_signal_frame(tf);
+            if let Some((signal, action)) = processor.handle_signals() {
+                if processor.current_context_mut().enter_signal_handler(tf, signal, action).is_err() {
+                    let pid = processor.current_pid();
+                    error!("Process {} failed to handle signal {}", pid, signal);
+                    processor.exit(pid, exit_code(SIGSEGV));
+                }
+            }
+        }
+        if processor.is_idle() && steal(&mut processor).is_some() {
+            processor.set_reschedule();
+        }
+        if processor.need_schedule() {
+            processor.schedule();
+        }
+    }
+}
+
+/// Called on a page fault at `addr`, caused by a write if `write`.
+///
+/// It is resolved by `memory::page_fault_handler` if possible, e.g. by copy-on-write.
+/// Otherwise it is a segmentation fault, which is reported to the process by `SIGSEGV`,
+/// so that a process with a handler for it can recover, see `fault`.
+pub fn page_fault(tf: &TrapFrame, addr: usize, write: bool) {
+    if ::memory::page_fault_handler(addr, write) {
+        return;
     }
+    fault(tf, SIGSEGV);
+}
+
+/// Called when a fault occured which can be reported to the process by `signal`,
+/// such as page fault or illegal instruction.
+///
+/// If it is from user mode, queues `signal` to the current process
+/// and lets `before_return` deliver it. Otherwise it is a kernel error.
+pub fn fault(tf: &TrapFrame, signal: Signal) {
+    if !tf.is_user() {
+        error(tf, signal);
+    }
+    let mut processor = processor();
+    let pid = processor.current_pid();
+    error!("Process {} ({}) fault, signal {}:\n{:#x?}",
+           pid, processor.current_context().name().unwrap_or("?"), signal, tf);
+    processor.send_signal(pid, signal);
 }
 
 /// Called when a error occured in interrupt handler.
@@ -21,15 +82,42 @@ pub fn before_return() {
 /// Argument: 
 /// 
 /// + `tf`: the TrapFrame in stack when the error occurs
-pub fn error(tf: &TrapFrame) -> ! {
-    if let Some(processor) = PROCESSOR.try() {
+/// + `signal`: the cause of the error, e.g. `SIGSEGV` for a page fault
+///
+/// The current process exits as if killed by `signal`, see `signal::exit_code`.
+pub fn error(tf: &TrapFrame, signal: Signal) -> ! {
+    if let Some(processor) = local_processor() {
         let mut processor = processor.lock();
         let pid = processor.current_pid();
-        error!("Process {} error:\n{:#x?}", pid, tf);
-        processor.exit(pid, 0x100); // TODO: Exit code for error
+        error!("Process {} ({}) error, signal {}:\n{:#x?}",
+               pid, processor.current_context().name().unwrap_or("?"), signal, tf);
+        #[cfg(feature = "coredump")]
+        dump_core(pid, processor.current_context());
+        processor.exit(pid, exit_code(signal));
         processor.schedule();
         unreachable!();
     } else {
         panic!("Exception when processor not inited\n{:#x?}", tf);
     }
-}
\ No newline at end of file
+}
+
+/// Print the core image of process `pid` to the console in hex.
+///
+/// It can be extracted from the log and recovered by `xxd -r -p`.
+#[cfg(feature = "coredump")]
+fn dump_core(pid: Pid, context: &Context) {
+    use alloc::vec::Vec;
+    let mut core = Vec::new();
+    if context.memory_set().write_coredump(&mut core).is_err() {
+        error!("failed to dump core of process {}", pid);
+        return;
+    }
+    println!("---- BEGIN COREDUMP process {} ({} bytes) ----", pid, core.len());
+    for line in core.chunks(32) {
+        for byte in line {
+            print!("{:02x}", byte);
+        }
+        println!();
+    }
+    println!("---- END COREDUMP ----");
+}
@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate ucore_ulib;
+use ucore_ulib::syscall::*;
+
+// Checks the return values of fork in both processes
+#[no_mangle]
+pub fn main() {
+    let pid = sys_fork();
+    if pid == 0 {
+        println!("fork returns 0 in child.");
+        sys_exit(sys_getpid() as usize);
+    }
+    assert!(pid > 0, "fork failed");
+    let mut code = 0;
+    assert_eq!(sys_wait(pid as usize, &mut code), 0, "wait failed");
+    assert_eq!(code, pid, "fork returns another pid");
+    println!("fork returns child pid in parent.");
+    println!("forkret pass.");
+}
@@ -112,6 +112,18 @@ pub fn sys_putc(c: char) -> i32 {
     sys_call(SYS_PUTC, c as usize, 0, 0, 0, 0, 0)
 }
 
+/// Install `handler` for `signal`, or restore the default action if `None`.
+pub fn sys_sigaction(signal: usize, handler: Option<extern fn(usize)>) -> i32 {
+    let handler = handler.map_or(0, |f| f as usize);
+    sys_call(SYS_SIGACTION, signal, handler, sigreturn as usize, 0, 0, 0)
+}
+
+/// Signal handlers return here, to resume where the signal interrupted.
+extern fn sigreturn() -> ! {
+    sys_call(SYS_SIGRETURN, 0, 0, 0, 0, 0, 0);
+    unreachable!()
+}
+
 const SYS_EXIT: usize = 1;
 const SYS_FORK: usize = 2;
 const SYS_WAIT: usize = 3;
@@ -137,4 +149,6 @@ const SYS_FSYNC: usize = 111;
 const SYS_GETCWD: usize = 121;
 const SYS_GETDIRENTRY: usize = 128;
 const SYS_DUP: usize = 130;
+const SYS_SIGACTION: usize = 132;
+const SYS_SIGRETURN: usize = 133;
 const SYS_LAB6_SET_PRIORITY: usize = 255;
\ No newline at end of file